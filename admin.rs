@@ -0,0 +1,140 @@
+/// Remote administration over private message
+///
+/// Users with the owner role can run the stdin commands by sending them to the
+/// bot in a private message, e.g. "/msg rustbot /join #channel". The sender is
+/// identified by hostmask or services account, never by nick alone; if the
+/// owner role could be granted by an account the bot doesn't yet know the
+/// sender to have, the command waits for a WHOIS to confirm it.
+///
+/// Every attempt is written to admin.log next to the config file, along with
+/// whether it was run or refused.
+
+use State;
+use stdin;
+use std::{io, mem, str};
+use irc::conn;
+use irc::conn::{Conn, Event};
+use plugins::{acl, command};
+use time;
+
+// a command waiting for the sender's account to be confirmed
+struct Pending {
+    nick: ~[u8], // lowercased
+    mask: ~[u8],
+    line: ~str
+}
+
+/// Remote administration state for a connection
+pub struct Admin {
+    priv log_path: Path,
+    priv pending: ~[Pending]
+}
+
+impl Admin {
+    pub fn new(log_path: Path) -> Admin {
+        Admin { log_path: log_path, pending: ~[] }
+    }
+}
+
+/// Handles an event for remote administration. This must run after the
+/// plugins have seen the event, so the roles are up to date.
+pub fn handle(conn: &mut Conn, event: &Event, state: &mut State) {
+    let line = match *event {
+        conn::Disconnected => {
+            state.admin.pending.clear();
+            return;
+        }
+        conn::Connected => return,
+        conn::LineReceived(ref line) => line
+    };
+    let conn::Line{ref command, ref args, ref prefix} = *line;
+    match *command {
+        conn::IRCCmd(ref cmd) if cmd.as_slice() == "PRIVMSG" && args.len() == 2 => {
+            let user = match *prefix {
+                None => return,
+                Some(ref user) => user
+            };
+            // only private messages that look like stdin commands
+            let to_me = command::lower(args[0]) == command::lower(conn.me().nick());
+            if !to_me || !args[1].starts_with(bytes!("/")) {
+                return;
+            }
+            let text = str::from_utf8_lossy(args[1]).into_owned();
+            match state.plugins().check_role(user.raw(), acl::Owner, None) {
+                acl::Granted => run(conn, state, user.nick(), user.raw(), text.as_slice()),
+                acl::Denied => refuse(conn, state, user.nick(), user.raw(), text.as_slice()),
+                acl::Unverified => {
+                    state.admin.pending.push(Pending {
+                        nick: command::lower(user.nick()),
+                        mask: user.raw().to_owned(),
+                        line: text
+                    });
+                    let mut line = bytes!("WHOIS ").to_owned();
+                    line.push_all(user.nick());
+                    conn.send_raw(line);
+                }
+            }
+        }
+        // RPL_ENDOFWHOIS: me nick :End of /WHOIS list
+        conn::IRCCode(318) if args.len() >= 2 => {
+            let nick = command::lower(args[1]);
+            let pending = mem::replace(&mut state.admin.pending, ~[]);
+            let (ready, waiting) = pending.partition(|p| p.nick == nick);
+            state.admin.pending = waiting;
+            for p in ready.move_iter() {
+                let (mask, line) = (p.mask.as_slice(), p.line.as_slice());
+                let nick = acl::nick_of(mask).to_owned();
+                // an account that's still unknown means the sender isn't logged in
+                match state.plugins().check_role(mask, acl::Owner, None) {
+                    acl::Granted => run(conn, state, nick.as_slice(), mask, line),
+                    _ => refuse(conn, state, nick.as_slice(), mask, line)
+                }
+            }
+        }
+        _ => ()
+    }
+}
+
+fn run(conn: &mut Conn, state: &mut State, nick: &[u8], mask: &[u8], line: &str) {
+    // help is answered here, as the console would print it
+    if line == "/help" || line.starts_with("/help ") {
+        audit(state, mask, "run", line);
+        match stdin::help(line.slice_from(5).trim()) {
+            Ok(lines) => for l in lines.iter() { conn.notice(nick, l.as_bytes()); },
+            Err(e) => conn.notice(nick, format!("Error: {}", e).as_bytes())
+        }
+        return;
+    }
+    match stdin::parse_line(line, None) {
+        Err(e) => {
+            audit(state, mask, "invalid", line);
+            conn.notice(nick, format!("Error: {}", e).as_bytes());
+        }
+        Ok(cmd) => {
+            audit(state, mask, "run", line);
+            conn.notice(nick, bytes!("OK"));
+            state.output = Some(nick.to_owned());
+            cmd(conn, state);
+            state.output = None;
+        }
+    }
+}
+
+fn refuse(conn: &mut Conn, state: &mut State, nick: &[u8], mask: &[u8], line: &str) {
+    audit(state, mask, "refused", line);
+    conn.notice(nick, bytes!("Permission denied"));
+}
+
+// writes an entry to the audit log, and to stdout
+fn audit(state: &mut State, mask: &[u8], outcome: &str, line: &str) {
+    let entry = format!("{} {} {}: {}", time::now().rfc3339(),
+                        str::from_utf8_lossy(mask), outcome, line);
+    println!("Admin: {}", entry);
+    let res = io::File::open_mode(&state.admin.log_path, io::Append, io::Write).and_then(|mut f| {
+        f.write_line(entry.as_slice())
+    });
+    match res {
+        Ok(()) => (),
+        Err(e) => println!("Error writing to {}: {}", state.admin.log_path.display(), e)
+    }
+}
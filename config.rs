@@ -1,14 +1,23 @@
 use std::{io, os};
+use std::ascii::StrAsciiExt;
 use std::io::{IoError, FileNotFound, PathAlreadyExists};
 use getopts::{getopts, optflag, optopt, usage, OptGroup};
 use toml;
+use VERSION;
 
 static CONFIG_EXAMPLE: &'static str = include_str!("config.example.toml");
 
 #[deriving(Clone)]
 pub struct Config {
+    config_path: Path, // path for the config file itself
     config_dir: Path, // path for the dir where the config file resides
     plugin_dir: Path, // path for the dir where plugins exist
+    memory_limit: Option<uint>, // max bytes for the whole plugin Lua state
+    plugin_memory_limit: Option<uint>, // max bytes for any single plugin
+    plugin_config: ~[(~str, toml::Value)], // the [plugins.<name>] tables
+    roles: ~[RoleGrant], // the [acl.<role>] tables
+    rate_limit: RateLimit,
+    ctcp: Ctcp,
     reconnect_time: Option<uint>,
     reconnect_backoff: bool,
     servers: ~[Server]
@@ -23,9 +32,43 @@ pub struct Server {
     nick: ~str,
     user: ~str,
     real: ~str,
-    autojoin: ~[Channel]
+    autojoin: ~[Channel],
+    command_prefix: ~str,
+    channel_prefixes: ~[(~str, ~str)], // (channel, command prefix)
+    bare_private_commands: bool // whether every private message is a command
 }
 
+/// The ways a user can be granted a role
+#[deriving(Clone)]
+pub struct RoleGrant {
+    role: ~str,
+    hostmasks: ~[~str], // nick!user@host masks, with * and ? wildcards
+    accounts: ~[~str], // services account names
+    channel_ops: bool // whether channel operators have the role in their channel
+}
+
+/// Limits on how often bot commands may be used
+#[deriving(Clone)]
+pub struct RateLimit {
+    user_commands: uint, // commands per user per user_window, 0 for no limit
+    user_window: uint, // seconds
+    channel_commands: uint, // commands per channel per channel_window, 0 for no limit
+    channel_window: uint, // seconds
+    ignore_time: uint, // seconds a user who exceeds the limit is ignored for
+    notice: bool // whether to tell a user when they're ignored
+}
+
+/// The built-in CTCP replies
+#[deriving(Clone)]
+pub struct Ctcp {
+    version: ~str, // the VERSION reply
+    source: ~str, // the SOURCE reply
+    disabled: ~[~str] // uppercased CTCP types that get no built-in reply
+}
+
+/// The roles that may be granted, from most to least privileged
+pub static ROLES: &'static [&'static str] = &["owner", "admin", "trusted"];
+
 #[deriving(Clone)]
 pub struct Channel {
     name: ~str,
@@ -101,10 +144,9 @@ pub fn parse_args() -> Result<Config,Error> {
         }
     };
 
-    let root = match toml::parse_from_path(&path) {
+    let root = match parse_file(&path) {
         Ok(v) => v,
-        Err(toml::ParseError) => return Err(ErrBadConfig),
-        Err(toml::IOError(e)) => return Err(ErrIO(e))
+        Err(e) => return Err(e)
     };
 
     let plugin_dir = match root.lookup("plugin.dir").and_then(|v| v.get_str()) {
@@ -115,6 +157,17 @@ pub fn parse_args() -> Result<Config,Error> {
         }
         Some(s) => s.clone()
     };
+    let memory_limit = match root.lookup("plugin.memory_limit").and_then(|v| v.get_int()) {
+        None => None,
+        Some(x) if x <= 0 => None,
+        Some(x) => Some(x.to_uint().unwrap())
+    };
+    let plugin_memory_limit = match root.lookup("plugin.plugin_memory_limit")
+                                        .and_then(|v| v.get_int()) {
+        None => None,
+        Some(x) if x <= 0 => None,
+        Some(x) => Some(x.to_uint().unwrap())
+    };
     let reconnect = match root.lookup("general.reconnect").and_then(|v| v.get_int()) {
         None => Some(5),
         Some(x) if x < 0 => None,
@@ -122,12 +175,53 @@ pub fn parse_args() -> Result<Config,Error> {
     };
     let backoff = root.lookup("general.reconnect_backoff").and_then(|v| v.get_bool())
                       .unwrap_or(true);
+
+    let plugin_config = match plugin_config_from(&root) {
+        Ok(v) => v,
+        Err(e) => return Err(e)
+    };
+    let roles = match roles_from(&root) {
+        Ok(v) => v,
+        Err(e) => return Err(e)
+    };
+    let rate_limit = rate_limit_from(&root);
+    let ctcp = match ctcp_from(&root) {
+        Ok(v) => v,
+        Err(e) => return Err(e)
+    };
+    let servers = match servers_from(&root) {
+        Ok(v) => v,
+        Err(e) => return Err(e)
+    };
+
+    let config_dir = path.dir_path();
+    let plugin_dir = config_dir.join(plugin_dir);
+    Ok(Config{
+        config_path: path,
+        config_dir: config_dir,
+        plugin_dir: plugin_dir,
+        memory_limit: memory_limit,
+        plugin_memory_limit: plugin_memory_limit,
+        plugin_config: plugin_config,
+        roles: roles,
+        rate_limit: rate_limit,
+        ctcp: ctcp,
+        reconnect_time: reconnect,
+        reconnect_backoff: backoff,
+        servers: servers
+    })
+}
+
+/// Reads the [[servers]] tables from the parsed config file
+pub fn servers_from(root: &toml::Value) -> Result<~[Server],Error> {
     let default_nick = root.lookup("general.defaults.nick").and_then(|v| v.get_str())
                            .map(|s| s.clone()).unwrap_or_else(|| ~"rustbot");
     let default_user = root.lookup("general.defaults.user").and_then(|v| v.get_str())
                            .map(|s| s.clone()).unwrap_or_else(|| ~"rustbot");
     let default_real = root.lookup("general.defaults.real").and_then(|v| v.get_str())
                            .map(|s| s.clone()).unwrap_or_else(|| ~"Rust IRC Bot");
+    let default_prefix = root.lookup("general.defaults.command_prefix").and_then(|v| v.get_str())
+                             .map(|s| s.clone()).unwrap_or_else(|| ~"!");
 
     let mut servers = ~[];
     let server_list = match root.lookup("servers").and_then(|v| v.get_table_array()) {
@@ -193,17 +287,184 @@ pub fn parse_args() -> Result<Config,Error> {
                 }
             }
         }
+        let command_prefix = elem.lookup("command_prefix").and_then(|v| v.get_str())
+                                 .map(|s| s.clone()).unwrap_or_else(|| default_prefix.clone());
+        let mut channel_prefixes = ~[];
+        match elem.lookup("channel_prefixes").and_then(|v| v.get_vec()) {
+            None => (),
+            Some(v) => {
+                for val in v.iter() {
+                    let s = match val.get_str() {
+                        None => continue,
+                        Some(s) => s
+                    };
+                    match s.find(',') {
+                        None => {
+                            let _ = writeln!(&mut io::stderr(),
+                                             "error: channel_prefixes entry {} has no prefix", s);
+                            return Err(ErrBadConfig);
+                        }
+                        Some(idx) => {
+                            channel_prefixes.push((s.slice_to(idx).to_owned(),
+                                                   s.slice_from(idx+1).to_owned()));
+                        }
+                    }
+                }
+            }
+        }
+        let bare_private_commands = elem.lookup("bare_private_commands")
+                                        .and_then(|v| v.get_bool()).unwrap_or(false);
         servers.push(Server{ name: name, host: server, port: port, use_ssl: use_ssl,
-                             nick: nick, user: user, real: real, autojoin: channels });
+                             nick: nick, user: user, real: real, autojoin: channels,
+                             command_prefix: command_prefix,
+                             channel_prefixes: channel_prefixes,
+                             bare_private_commands: bare_private_commands });
     }
+    Ok(servers)
+}
 
-    let config_dir = path.dir_path();
-    let plugin_dir = config_dir.join(plugin_dir);
-    Ok(Config{
-        config_dir: config_dir,
-        plugin_dir: plugin_dir,
-        reconnect_time: reconnect,
-        reconnect_backoff: backoff,
-        servers: servers
-    })
+/// Parses the config file, for re-reading its sections with the *_from
+/// functions
+pub fn parse_file(path: &Path) -> Result<toml::Value,Error> {
+    match toml::parse_from_path(path) {
+        Ok(v) => Ok(v),
+        Err(toml::ParseError) => Err(ErrBadConfig),
+        Err(toml::IOError(e)) => Err(ErrIO(e))
+    }
+}
+
+/// Re-reads the [plugins.<name>] tables from the config file
+pub fn parse_plugin_config(path: &Path) -> Result<~[(~str, toml::Value)],Error> {
+    match parse_file(path) {
+        Ok(root) => plugin_config_from(&root),
+        Err(e) => Err(e)
+    }
+}
+
+/// Reads the [plugins.<name>] tables from the parsed config file
+pub fn plugin_config_from(root: &toml::Value) -> Result<~[(~str, toml::Value)],Error> {
+    let table = match root.lookup("plugins") {
+        None => return Ok(~[]),
+        Some(&toml::Table(_, ref table)) => table,
+        Some(_) => {
+            let _ = writeln!(&mut io::stderr(), "error: plugins must be a table");
+            return Err(ErrBadConfig);
+        }
+    };
+    let mut config = ~[];
+    for (name, val) in table.iter() {
+        match *val {
+            toml::Table(..) => config.push((name.clone(), val.clone())),
+            _ => {
+                let _ = writeln!(&mut io::stderr(), "error: plugins.{} must be a table", name);
+                return Err(ErrBadConfig);
+            }
+        }
+    }
+    Ok(config)
+}
+
+/// Reads the [acl.<role>] tables from the parsed config file
+pub fn roles_from(root: &toml::Value) -> Result<~[RoleGrant],Error> {
+    let table = match root.lookup("acl") {
+        None => return Ok(~[]),
+        Some(&toml::Table(_, ref table)) => table,
+        Some(_) => {
+            let _ = writeln!(&mut io::stderr(), "error: acl must be a table");
+            return Err(ErrBadConfig);
+        }
+    };
+    let mut roles = ~[];
+    for (role, val) in table.iter() {
+        if !ROLES.contains(&role.as_slice()) {
+            let _ = writeln!(&mut io::stderr(), "error: acl.{} is not a known role", role);
+            return Err(ErrBadConfig);
+        }
+        match *val {
+            toml::Table(..) => (),
+            _ => {
+                let _ = writeln!(&mut io::stderr(), "error: acl.{} must be a table", role);
+                return Err(ErrBadConfig);
+            }
+        }
+        let hostmasks = match string_list(val, role.as_slice(), "hostmasks") {
+            Ok(v) => v,
+            Err(e) => return Err(e)
+        };
+        let accounts = match string_list(val, role.as_slice(), "accounts") {
+            Ok(v) => v,
+            Err(e) => return Err(e)
+        };
+        let channel_ops = val.lookup("channel_ops").and_then(|v| v.get_bool()).unwrap_or(false);
+        roles.push(RoleGrant{ role: role.clone(), hostmasks: hostmasks, accounts: accounts,
+                              channel_ops: channel_ops });
+    }
+    Ok(roles)
+}
+
+/// Reads the [rate_limit] section from the parsed config file
+pub fn rate_limit_from(root: &toml::Value) -> RateLimit {
+    let limit = |key: &str, default: uint| {
+        match root.lookup(format!("rate_limit.{}", key).as_slice()).and_then(|v| v.get_int()) {
+            None => default,
+            Some(x) if x <= 0 => 0,
+            Some(x) => x.to_uint().unwrap()
+        }
+    };
+    RateLimit {
+        user_commands: limit("user_commands", 5),
+        user_window: limit("user_window", 10),
+        channel_commands: limit("channel_commands", 10),
+        channel_window: limit("channel_window", 10),
+        ignore_time: limit("ignore_time", 60),
+        notice: root.lookup("rate_limit.notice").and_then(|v| v.get_bool()).unwrap_or(true)
+    }
+}
+
+/// Reads the [ctcp] section from the parsed config file
+pub fn ctcp_from(root: &toml::Value) -> Result<Ctcp,Error> {
+    let string = |key: &str, default: ~str| {
+        root.lookup(format!("ctcp.{}", key).as_slice()).and_then(|v| v.get_str())
+            .map(|s| s.clone()).unwrap_or(default)
+    };
+    let version = string("version", format!("rustirc {}", VERSION));
+    let source = string("source", ~"https://github.com/kballard/rust-ircbot");
+    let mut disabled = ~[];
+    match root.lookup("ctcp.disabled").and_then(|v| v.get_vec()) {
+        None => (),
+        Some(v) => {
+            for elem in v.iter() {
+                match elem.get_str() {
+                    None => {
+                        let _ = writeln!(&mut io::stderr(),
+                                         "error: ctcp.disabled must be an array of strings");
+                        return Err(ErrBadConfig);
+                    }
+                    Some(s) => disabled.push(s.to_ascii_upper())
+                }
+            }
+        }
+    }
+    Ok(Ctcp{ version: version, source: source, disabled: disabled })
+}
+
+// reads an optional array of strings from the acl.<role> table
+fn string_list(table: &toml::Value, role: &str, key: &str) -> Result<~[~str],Error> {
+    let mut list = ~[];
+    match table.lookup(key).and_then(|v| v.get_vec()) {
+        None => (),
+        Some(v) => {
+            for elem in v.iter() {
+                match elem.get_str() {
+                    None => {
+                        let _ = writeln!(&mut io::stderr(),
+                                         "error: acl.{}.{} must be an array of strings", role, key);
+                        return Err(ErrBadConfig);
+                    }
+                    Some(s) => list.push(s.clone())
+                }
+            }
+        }
+    }
+    Ok(list)
 }
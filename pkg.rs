@@ -12,6 +12,7 @@ extern crate toml = "github.com/mneumann/rust-toml#toml:0.1";
 extern crate log;
 extern crate getopts;
 extern crate sync;
+extern crate time;
 
 use std::os;
 use std::io;
@@ -20,11 +21,17 @@ use std::task;
 use irc::conn;
 use irc::conn::{Conn, Line, Event, IRCCode};
 
+pub mod admin;
 pub mod config;
+pub mod readline;
 pub mod stdin;
+pub mod timer;
 
 pub mod plugins;
 
+/// The version of the bot, taken from the crate_id by the Makefile
+pub static VERSION: &'static str = env!("RUSTIRC_VERSION");
+
 fn main() {
     let conf = match config::parse_args() {
         Ok(c) => c,
@@ -44,8 +51,18 @@ fn main() {
     // This way we can swap it out on reconnections and stdin will work
     let arc = sync::MutexArc::new(None);
 
+    // the connection keeps the console's tab completions up to date
+    let completions = sync::MutexArc::new(stdin::Completions::new());
+
     // spawn the stdin listener now to control the bot
-    stdin::spawn_stdin_listener(arc.clone());
+    stdin::spawn_stdin_listener(arc.clone(), completions.clone(),
+                                conf.config_dir.join("console_history"));
+
+    // the plugins live across reconnections, along with their timers
+    let timers = timer::spawn_timer_source(arc.clone());
+    let mut plugins = plugins::PluginManager::new(&conf, timers);
+    let names = plugins.plugin_names();
+    completions.access(|c| c.set_plugins(names.as_slice()));
 
     // create the reconnect timer, later used to sleep between connections
     let mut recon_timer = io::timer::Timer::new().ok()
@@ -56,7 +73,7 @@ fn main() {
     // connect in a loop, based on the reconnection config
     println!("Connecting...");
     loop {
-        match connect(&conf, &arc) {
+        match connect(&conf, &arc, &completions, &mut plugins) {
             Ok(()) => {
                 // bot quit gracefully
                 println!("Exiting...");
@@ -100,18 +117,48 @@ fn main() {
         println!("Reconnecting...");
     }
 
+    // the plugins hear about it even if the bot is giving up on the server
+    plugins.shutdown(None);
+
     // some task is keeping us alive, so kill it
+    // the stdin listener may be in the middle of reading a line
+    if readline::is_interactive() {
+        readline::restore_terminal();
+    }
     unsafe { ::std::libc::exit(0); }
 }
 
 /// Payload for the Conn
 pub struct State {
-    plugins: plugins::PluginManager
+    // the PluginManager is owned by main() and outlives every connection
+    priv plugins: *mut plugins::PluginManager,
+    priv admin: admin::Admin,
+    priv completions: sync::MutexArc<stdin::Completions>,
+    // the nick of the owner running a remote admin command, who gets its output
+    priv output: Option<~[u8]>
+}
+
+impl State {
+    /// Returns the PluginManager
+    pub fn plugins<'a>(&'a mut self) -> &'a mut plugins::PluginManager {
+        unsafe { &mut *self.plugins }
+    }
+
+    /// Prints a line of output from a console command, or sends it as a
+    /// NOTICE to the owner if the command came from remote administration
+    pub fn print(&self, conn: &mut Conn, line: &str) {
+        match self.output {
+            None => println!("{}", line),
+            Some(ref nick) => conn.notice(nick.as_slice(), line.as_bytes())
+        }
+    }
 }
 
 pub type Cmd = conn::Cmd<State>;
 
-fn connect(conf: &config::Config, arc: &sync::MutexArc<Option<Sender<Cmd>>>) -> conn::Result {
+fn connect(conf: &config::Config, arc: &sync::MutexArc<Option<Sender<Cmd>>>,
+           completions: &sync::MutexArc<stdin::Completions>,
+           plugins: &mut plugins::PluginManager) -> conn::Result {
     // TODO: eventually we should support multiple servers
     let server = &conf.servers[0];
     let mut opts = irc::conn::Options::new(server.host, server.port);
@@ -135,7 +182,8 @@ fn connect(conf: &config::Config, arc: &sync::MutexArc<Option<Sender<Cmd>>>) ->
             loop {
                 match listener.rx.recv() {
                     Interrupt => {
-                        cmd_tx.try_send(proc(conn: &mut Conn, _state: &mut State) {
+                        cmd_tx.try_send(proc(conn: &mut Conn, state: &mut State) {
+                            state.plugins().shutdown(Some(&mut *conn));
                             conn.quit([]);
                         });
                         listener.unregister(Interrupt);
@@ -149,7 +197,12 @@ fn connect(conf: &config::Config, arc: &sync::MutexArc<Option<Sender<Cmd>>>) ->
         warn!("Couldn't register ^C signal handler");
     }
 
-    let state = State { plugins: plugins::PluginManager::new(conf) };
+    let state = State {
+        plugins: plugins as *mut plugins::PluginManager,
+        admin: admin::Admin::new(conf.config_dir.join("admin.log")),
+        completions: completions.clone(),
+        output: None
+    };
 
     let autojoin = server.autojoin.as_slice();
 
@@ -160,8 +213,12 @@ fn connect(conf: &config::Config, arc: &sync::MutexArc<Option<Sender<Cmd>>>) ->
 fn handler(conn: &mut Conn, event: Event, state: &mut State, autojoin: &[config::Channel]) {
     match event {
         irc::conn::Connected => println!("Connected"),
-        irc::conn::Disconnected => println!("Disconnected"),
+        irc::conn::Disconnected => {
+            println!("Disconnected");
+            state.completions.access(|c| c.clear());
+        }
         irc::conn::LineReceived(ref line) => {
+            state.completions.access(|c| c.feed(line, conn.me().nick()));
             let Line{ref command, args: _, prefix: _} = *line;
             match *command {
                 IRCCode(1) => {
@@ -175,5 +232,10 @@ fn handler(conn: &mut Conn, event: Event, state: &mut State, autojoin: &[config:
             }
         }
     }
-    state.plugins.dispatch_irc_event(conn, &event);
+    // ignored users don't get to reach the plugins, or remote administration,
+    // and neither do commands over the rate limits
+    if !state.plugins().is_ignored(&event) && !state.plugins().rate_limit(conn, &event) {
+        state.plugins().dispatch_irc_event(conn, &event);
+        admin::handle(conn, &event, state);
+    }
 }
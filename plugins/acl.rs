@@ -0,0 +1,392 @@
+//! Roles and permissions
+//!
+//! Users are granted roles by the [acl.<role>] tables of the config file. The
+//! roles are owner, admin and trusted, and each role includes the ones after
+//! it, so an owner also has the admin and trusted roles. A role is granted to
+//! users matching one of its hostmasks, to users logged in to one of its
+//! services accounts, and, if channel_ops is set, to the operators of a
+//! channel within that channel.
+//!
+//! Services accounts and channel operator status are learned by watching the
+//! connection. Accounts come from WHOIS replies (numeric 330), extended JOIN
+//! and account-notify messages. A known account is tied to the full hostmask
+//! it was seen with, so another user taking the nick doesn't inherit it. That
+//! a user isn't logged in is remembered the same way, until their next
+//! ACCOUNT, NICK or QUIT, so they aren't sent a WHOIS for every command.
+//! Operator status comes from NAMES replies and channel MODE changes.
+//!
+//! The IRCv3 account-tag isn't read, as the connection library drops message
+//! tags when it parses a line. Account-notify and extended JOIN cover the
+//! same changes for users in the bot's channels.
+
+use config;
+use irc::conn;
+use super::command::lower;
+use super::irc::is_channel;
+
+/// A role that can be granted to users
+#[deriving(Eq, Ord, Clone)]
+pub enum Role {
+    Trusted,
+    Admin,
+    Owner
+}
+
+impl Role {
+    /// Returns the role with the given name
+    pub fn from_name(name: &str) -> Option<Role> {
+        match name {
+            "owner" => Some(Owner),
+            "admin" => Some(Admin),
+            "trusted" => Some(Trusted),
+            _ => None
+        }
+    }
+
+    /// Returns the name of the role
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Owner => "owner",
+            Admin => "admin",
+            Trusted => "trusted"
+        }
+    }
+}
+
+/// The result of checking whether a user has a role
+#[deriving(Eq)]
+pub enum Check {
+    Granted,
+    Denied,
+    /// Not granted yet, but the role might be granted by the user's services
+    /// account, which isn't known. A WHOIS will tell.
+    Unverified
+}
+
+struct Grant {
+    role: Role,
+    hostmasks: ~[~[u8]], // lowercased
+    accounts: ~[~[u8]], // lowercased
+    channel_ops: bool
+}
+
+// the services account a user was seen with
+struct Identity {
+    mask: ~[u8], // the user's lowercased nick!user@host
+    account: Option<~[u8]> // None if they're known not to be logged in
+}
+
+/// Grants roles to users, and tracks what it needs to know about them
+pub struct Acl {
+    priv grants: ~[Grant],
+    priv identities: ~[(~[u8], Identity)], // by lowercased nick
+    priv whois: ~[(~[u8], ~[u8])], // hostmasks from WHOIS replies in progress, by nick
+    priv ops: ~[(~[u8], ~[~[u8]])] // lowercased channel, lowercased operator nicks
+}
+
+impl Acl {
+    pub fn new(grants: &[config::RoleGrant]) -> Acl {
+        let mut acl = Acl { grants: ~[], identities: ~[], whois: ~[], ops: ~[] };
+        acl.set_grants(grants);
+        acl
+    }
+
+    /// Replaces the configured role grants
+    pub fn set_grants(&mut self, grants: &[config::RoleGrant]) {
+        self.grants = grants.iter().filter_map(|g| {
+            Role::from_name(g.role.as_slice()).map(|role| Grant {
+                role: role,
+                hostmasks: g.hostmasks.iter().map(|m| lower(m.as_bytes())).collect(),
+                accounts: g.accounts.iter().map(|a| lower(a.as_bytes())).collect(),
+                channel_ops: g.channel_ops
+            })
+        }).collect();
+    }
+
+    /// Forgets everything learned from the connection
+    pub fn clear(&mut self) {
+        self.identities.clear();
+        self.whois.clear();
+        self.ops.clear();
+    }
+
+    /// Checks whether the user with the given nick!user@host has a role.
+    /// `chan` is the channel the role is needed in, if any.
+    pub fn check(&self, mask: &[u8], role: Role, chan: Option<&[u8]>) -> Check {
+        let mask = lower(mask);
+        let nick = nick_of(mask.as_slice());
+        let identity = self.identity(mask.as_slice());
+        let account = identity.and_then(|id| id.account.as_ref()).map(|a| a.as_slice());
+
+        let mut unverified = false;
+        for grant in self.grants.iter().filter(|g| g.role >= role) {
+            if grant.hostmasks.iter().any(|m| glob_match(*m, mask.as_slice())) {
+                return Granted;
+            }
+            if grant.channel_ops && chan.map_or(false, |c| self.is_op(c, nick)) {
+                return Granted;
+            }
+            match account {
+                Some(a) if grant.accounts.iter().any(|x| x.as_slice() == a) => return Granted,
+                None if identity.is_none() && !grant.accounts.is_empty() => unverified = true,
+                _ => ()
+            }
+        }
+        if unverified { Unverified } else { Denied }
+    }
+
+    /// Returns the services account of the user with the given nick!user@host,
+    /// if it's known
+    pub fn account_of<'a>(&'a self, mask: &[u8]) -> Option<&'a [u8]> {
+        let mask = lower(mask);
+        self.identity(mask.as_slice()).and_then(|id| id.account.as_ref()).map(|a| a.as_slice())
+    }
+
+    // returns what's known of the account of the user with the given
+    // lowercased hostmask
+    fn identity<'a>(&'a self, mask: &[u8]) -> Option<&'a Identity> {
+        let nick = nick_of(mask);
+        self.identities.iter().find(|&&(ref n, ref id)| {
+            n.as_slice() == nick && id.mask.as_slice() == mask
+        }).map(|&(_, ref id)| id)
+    }
+
+    /// Returns true if the nick is an operator of the channel
+    pub fn is_op(&self, chan: &[u8], nick: &[u8]) -> bool {
+        let (chan, nick) = (lower(chan), lower(nick));
+        self.ops.iter().any(|&(ref c, ref nicks)| *c == chan && nicks.contains(&nick))
+    }
+
+    /// Learns about accounts and channel operators from a line received from
+    /// the server. `me` is the bot's nick.
+    pub fn feed(&mut self, line: &conn::Line, me: &[u8]) {
+        let conn::Line{ref command, ref args, ref prefix} = *line;
+        let source = prefix.as_ref().map(|u| lower(u.raw()));
+        match *command {
+            // RPL_WHOISUSER: me nick user host * :real
+            conn::IRCCode(311) if args.len() >= 4 => {
+                let nick = lower(args[1]);
+                let mut mask = nick.clone();
+                mask.push('!' as u8);
+                mask.push_all(lower(args[2]));
+                mask.push('@' as u8);
+                mask.push_all(lower(args[3]));
+                self.whois.retain(|&(ref n, _)| *n != nick);
+                self.whois.push((nick, mask));
+            }
+            // RPL_WHOISACCOUNT: me nick account :is logged in as
+            conn::IRCCode(330) if args.len() >= 3 => {
+                let nick = lower(args[1]);
+                let mask = match self.whois.iter().find(|&&(ref n, _)| *n == nick) {
+                    None => return,
+                    Some(&(_, ref mask)) => mask.clone()
+                };
+                self.set_account(mask.as_slice(), Some(args[2].as_slice()));
+            }
+            // RPL_ENDOFWHOIS: me nick :End of /WHOIS list
+            conn::IRCCode(318) if args.len() >= 2 => {
+                let nick = lower(args[1]);
+                let mask = self.whois.iter().find(|&&(ref n, _)| *n == nick)
+                                     .map(|&(_, ref mask)| mask.clone());
+                self.whois.retain(|&(ref n, _)| *n != nick);
+                // without an RPL_WHOISACCOUNT, they aren't logged in
+                match mask {
+                    Some(mask) => if self.identity(mask.as_slice()).is_none() {
+                        self.set_account(mask.as_slice(), None);
+                    },
+                    None => ()
+                }
+            }
+            // RPL_NAMREPLY: me symbol chan :names
+            conn::IRCCode(353) if args.len() >= 4 => {
+                let chan = lower(args[2]);
+                for name in args[3].split(|&b| b == ' ' as u8).filter(|n| !n.is_empty()) {
+                    let start = name.iter().position(|&b| !is_status(b)).unwrap_or(name.len());
+                    let op = name.slice_to(start).iter().any(|&b| {
+                        b == '@' as u8 || b == '&' as u8 || b == '~' as u8
+                    });
+                    // with userhost-in-names, the name is a full hostmask
+                    let nick = nick_of(name.slice_from(start));
+                    self.set_op(chan.clone(), nick, op);
+                }
+            }
+            conn::IRCCmd(ref cmd) => {
+                let source = match source {
+                    None => return,
+                    Some(s) => s
+                };
+                let nick = nick_of(source.as_slice()).to_owned();
+                match cmd.as_slice() {
+                    "ACCOUNT" if args.len() >= 1 => {
+                        let account = if args[0].as_slice() == bytes!("*") {
+                            None
+                        } else {
+                            Some(args[0].as_slice())
+                        };
+                        self.set_account(source.as_slice(), account);
+                    }
+                    "JOIN" if args.len() >= 1 => {
+                        if nick == lower(me) {
+                            // a NAMES reply follows
+                            self.forget_chan(args[0]);
+                        } else {
+                            self.set_op(lower(args[0]), nick.as_slice(), false);
+                        }
+                        // extended-join: chan account :real
+                        if args.len() >= 3 {
+                            let account = if args[1].as_slice() == bytes!("*") {
+                                None
+                            } else {
+                                Some(args[1].as_slice())
+                            };
+                            self.set_account(source.as_slice(), account);
+                        }
+                    }
+                    "PART" if args.len() >= 1 => {
+                        self.left(args[0], nick, me);
+                    }
+                    "KICK" if args.len() >= 2 => {
+                        self.left(args[0], lower(args[1]), me);
+                    }
+                    "QUIT" => {
+                        self.identities.retain(|&(ref n, _)| *n != nick);
+                        for entry in self.ops.mut_iter() {
+                            entry.mut1().retain(|n| *n != nick);
+                        }
+                    }
+                    "NICK" if args.len() >= 1 => {
+                        let new = lower(args[0]);
+                        // a logged out user may have changed nicks to log in
+                        self.identities.retain(|&(ref n, ref id)| {
+                            *n != nick || id.account.is_some()
+                        });
+                        for entry in self.identities.mut_iter() {
+                            let (ref mut n, ref mut id) = *entry;
+                            if *n == nick {
+                                // the rest of the hostmask stays the same
+                                let mut mask = new.clone();
+                                mask.push_all(id.mask.slice_from(nick.len()));
+                                *n = new.clone();
+                                id.mask = mask;
+                            }
+                        }
+                        for entry in self.ops.mut_iter() {
+                            for n in entry.mut1().mut_iter() {
+                                if *n == nick {
+                                    *n = new.clone();
+                                }
+                            }
+                        }
+                    }
+                    "MODE" if args.len() >= 2 && is_channel(args[0]) => {
+                        let chan = lower(args[0]);
+                        let mut params = args.slice_from(2).iter();
+                        let mut adding = true;
+                        for &c in args[1].iter() {
+                            match c as char {
+                                '+' => adding = true,
+                                '-' => adding = false,
+                                'o' => match params.next() {
+                                    None => (),
+                                    Some(n) => {
+                                        self.set_op(chan.clone(), lower(*n).as_slice(), adding);
+                                    }
+                                },
+                                // the other modes that take a parameter
+                                'v' | 'h' | 'a' | 'q' | 'b' | 'e' | 'I' | 'k' => {
+                                    params.next();
+                                }
+                                'l' if adding => {
+                                    params.next();
+                                }
+                                _ => ()
+                            }
+                        }
+                    }
+                    _ => ()
+                }
+            }
+            _ => ()
+        }
+    }
+
+    // records the account of the user with the given lowercased hostmask, or
+    // that they're not logged in
+    fn set_account(&mut self, mask: &[u8], account: Option<&[u8]>) {
+        let nick = nick_of(mask).to_owned();
+        self.identities.retain(|&(ref n, _)| *n != nick);
+        let id = Identity { mask: mask.to_owned(), account: account.map(|a| lower(a)) };
+        self.identities.push((nick, id));
+    }
+
+    fn set_op(&mut self, chan: ~[u8], nick: &[u8], op: bool) {
+        let idx = match self.ops.iter().position(|&(ref c, _)| *c == chan) {
+            Some(idx) => idx,
+            None if op => {
+                self.ops.push((chan, ~[]));
+                self.ops.len() - 1
+            }
+            None => return
+        };
+        let nicks = self.ops[idx].mut1();
+        nicks.retain(|n| n.as_slice() != nick);
+        if op {
+            nicks.push(nick.to_owned());
+        }
+    }
+
+    fn forget_chan(&mut self, chan: &[u8]) {
+        let chan = lower(chan);
+        self.ops.retain(|&(ref c, _)| *c != chan);
+    }
+
+    // handles the lowercased nick leaving the channel
+    fn left(&mut self, chan: &[u8], nick: ~[u8], me: &[u8]) {
+        if nick == lower(me) {
+            self.forget_chan(chan);
+        } else {
+            self.set_op(lower(chan), nick.as_slice(), false);
+        }
+    }
+}
+
+/// Returns the nick part of a nick!user@host
+pub fn nick_of<'a>(mask: &'a [u8]) -> &'a [u8] {
+    match mask.iter().position(|&b| b == '!' as u8) {
+        None => mask,
+        Some(idx) => mask.slice_to(idx)
+    }
+}
+
+// status prefixes that may precede a nick in a NAMES reply
+fn is_status(b: u8) -> bool {
+    b == '~' as u8 || b == '&' as u8 || b == '@' as u8 || b == '%' as u8 || b == '+' as u8
+}
+
+/// Matches s against a pattern where * matches any run of bytes and ?
+/// matches a single byte
+pub fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    // the position after the last * and the byte of s it's trying to match
+    let mut backtrack = None;
+    let (mut p, mut i) = (0u, 0u);
+    while i < s.len() {
+        if p < pattern.len() && pattern[p] == '*' as u8 {
+            p += 1;
+            backtrack = Some((p, i));
+        } else if p < pattern.len() && (pattern[p] == '?' as u8 || pattern[p] == s[i]) {
+            p += 1;
+            i += 1;
+        } else {
+            match backtrack {
+                None => return false,
+                Some((bp, bi)) => {
+                    // let the last * swallow one more byte
+                    p = bp;
+                    i = bi + 1;
+                    backtrack = Some((bp, bi + 1));
+                }
+            }
+        }
+    }
+    pattern.slice_from(p).iter().all(|&b| b == '*' as u8)
+}
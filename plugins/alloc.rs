@@ -0,0 +1,235 @@
+//! Memory-tracking allocator for the plugin Lua state
+//!
+//! Every block handed to Lua is prefixed with a small header that records the
+//! owner that allocated it and its size. Allocations are charged to whichever
+//! owner is current at the time (the plugin being loaded, or the plugin whose
+//! handler is running), and frees are credited back to the original owner.
+//! Owner 0 is the plugin manager itself.
+//!
+//! When an allocation would push an owner past the per-plugin limit, or the
+//! whole state past the global limit, the allocator returns NULL and Lua
+//! raises a memory error in the running plugin.
+
+#[allow(uppercase_variables)];
+
+use lua;
+use std::{cmp, libc, mem, ptr};
+use std::libc::{c_void, size_t};
+
+static ALLOCATOR: &'static str = "allocator";
+
+/// The owner id for allocations made by the plugin manager itself
+pub static OWNER_CORE: uint = 0;
+
+struct Header {
+    owner: uint,
+    size: uint
+}
+
+// round the header up so the block we hand back stays suitably aligned
+static HEADER_SIZE: uint = 16;
+
+struct Owner {
+    name: ~str,
+    usage: uint
+}
+
+/// Tracks memory usage for a Lua state
+pub struct Allocator {
+    priv owners: ~[Owner],
+    priv current: uint,
+    priv total: uint,
+    priv limit: Option<uint>,
+    priv plugin_limit: Option<uint>,
+    priv denied: Option<(uint, Limit)>
+}
+
+enum Limit {
+    TotalLimit,
+    PluginLimit
+}
+
+impl Allocator {
+    /// Creates a new Allocator. `limit` caps the whole state, `plugin_limit`
+    /// caps each individual plugin.
+    pub fn new(limit: Option<uint>, plugin_limit: Option<uint>) -> Allocator {
+        Allocator {
+            owners: ~[Owner { name: ~"core", usage: 0 }],
+            current: OWNER_CORE,
+            total: 0,
+            limit: limit,
+            plugin_limit: plugin_limit,
+            denied: None
+        }
+    }
+
+    /// Returns the owner id for the plugin with the given name, creating it
+    /// if necessary. Ids are stable across reloads so that blocks freed by an
+    /// old state are credited to the right plugin.
+    pub fn owner_id(&mut self, name: &str) -> uint {
+        match self.owners.iter().position(|o| o.name.as_slice() == name) {
+            Some(id) => id,
+            None => {
+                self.owners.push(Owner { name: name.to_owned(), usage: 0 });
+                self.owners.len() - 1
+            }
+        }
+    }
+
+    /// Returns the name of the given owner
+    pub fn name<'a>(&'a self, owner: uint) -> &'a str {
+        self.owners[owner].name.as_slice()
+    }
+
+    /// Returns the number of bytes currently held by the given owner
+    pub fn usage(&self, owner: uint) -> uint {
+        self.owners[owner].usage
+    }
+
+    /// Returns the number of bytes currently held by the whole state
+    pub fn total(&self) -> uint {
+        self.total
+    }
+
+    /// Returns the limit for the whole state, if any
+    pub fn limit(&self) -> Option<uint> {
+        self.limit
+    }
+
+    /// Returns the limit for each plugin, if any
+    pub fn plugin_limit(&self) -> Option<uint> {
+        self.plugin_limit
+    }
+
+    /// Returns the owner that new allocations are charged to
+    pub fn current(&self) -> uint {
+        self.current
+    }
+
+    /// Sets the owner that new allocations are charged to, returning the
+    /// previous owner
+    pub fn set_current(&mut self, owner: uint) -> uint {
+        mem::replace(&mut self.current, owner)
+    }
+
+    /// Describes the most recently refused allocation, if any, and clears it.
+    /// Returns the offending owner and a description of the limit it hit.
+    pub fn take_denied(&mut self) -> Option<(uint, ~str)> {
+        self.denied.take().map(|(owner, which)| {
+            let desc = match which {
+                TotalLimit => format!("total memory limit of {} bytes", self.limit.unwrap()),
+                PluginLimit => format!("plugin memory limit of {} bytes",
+                                       self.plugin_limit.unwrap())
+            };
+            (owner, desc)
+        })
+    }
+
+    fn reserve(&mut self, owner: uint, n: uint) -> bool {
+        match self.limit {
+            Some(limit) if self.total + n > limit => {
+                self.denied = Some((owner, TotalLimit));
+                return false;
+            }
+            _ => ()
+        }
+        if owner != OWNER_CORE {
+            match self.plugin_limit {
+                Some(limit) if self.owners[owner].usage + n > limit => {
+                    self.denied = Some((owner, PluginLimit));
+                    return false;
+                }
+                _ => ()
+            }
+        }
+        self.owners[owner].usage += n;
+        self.total += n;
+        true
+    }
+
+    fn release(&mut self, owner: uint, n: uint) {
+        let o = &mut self.owners[owner];
+        o.usage -= cmp::min(o.usage, n);
+        self.total -= cmp::min(self.total, n);
+    }
+}
+
+/// Creates a new Lua state that allocates through the given Allocator.
+/// The Allocator must outlive the returned state.
+pub fn new_state(alloc: &mut Allocator) -> lua::State {
+    let ud = alloc as *mut Allocator as *mut c_void;
+    let mut L = unsafe {
+        let L = lua::raw::lua_newstate(lua_alloc, ud);
+        if L.is_null() {
+            fail!("lua_newstate returned NULL");
+        }
+        lua::State::from_lua(L)
+    };
+    L.pushlightuserdata(ud);
+    L.setfield(lua::REGISTRYINDEX, ALLOCATOR);
+    L
+}
+
+/// Retrieves the Allocator from inside a Lua callback
+pub unsafe fn get(L: &mut lua::ExternState) -> &'static mut Allocator {
+    L.getfield(lua::REGISTRYINDEX, ALLOCATOR);
+    let ptr = L.touserdata(-1) as *mut Allocator;
+    L.pop(1);
+    if ptr.is_null() {
+        L.errorstr("could not retrieve allocator");
+    }
+    &mut *ptr
+}
+
+extern "C" fn lua_alloc(ud: *mut c_void, p: *mut c_void, _osize: size_t,
+                        nsize: size_t) -> *mut c_void {
+    unsafe {
+        let alloc = &mut *(ud as *mut Allocator);
+        let nsize = nsize as uint;
+
+        if nsize == 0 {
+            if p.is_not_null() {
+                let hdr = (p as *mut u8).offset(-(HEADER_SIZE as int)) as *mut Header;
+                alloc.release((*hdr).owner, (*hdr).size);
+                libc::free(hdr as *mut c_void);
+            }
+            return ptr::mut_null();
+        }
+
+        if p.is_null() {
+            let owner = alloc.current;
+            if !alloc.reserve(owner, nsize) {
+                return ptr::mut_null();
+            }
+            let hdr = libc::malloc((nsize + HEADER_SIZE) as size_t) as *mut Header;
+            if hdr.is_null() {
+                alloc.release(owner, nsize);
+                return ptr::mut_null();
+            }
+            (*hdr).owner = owner;
+            (*hdr).size = nsize;
+            return (hdr as *mut u8).offset(HEADER_SIZE as int) as *mut c_void;
+        }
+
+        // reallocation stays charged to the block's original owner
+        let hdr = (p as *mut u8).offset(-(HEADER_SIZE as int)) as *mut Header;
+        let owner = (*hdr).owner;
+        let osize = (*hdr).size;
+        if nsize > osize && !alloc.reserve(owner, nsize - osize) {
+            return ptr::mut_null();
+        }
+        let newhdr = libc::realloc(hdr as *mut c_void, (nsize + HEADER_SIZE) as size_t)
+                     as *mut Header;
+        if newhdr.is_null() {
+            if nsize > osize {
+                alloc.release(owner, nsize - osize);
+            }
+            return ptr::mut_null();
+        }
+        if nsize < osize {
+            alloc.release(owner, osize - nsize);
+        }
+        (*newhdr).size = nsize;
+        (newhdr as *mut u8).offset(HEADER_SIZE as int) as *mut c_void
+    }
+}
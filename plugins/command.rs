@@ -0,0 +1,159 @@
+//! Prefix-triggered bot commands
+//!
+//! A PRIVMSG invokes a command if it starts with the command prefix for its
+//! channel or if it's addressed to the bot by nick ("rustbot: foo" or
+//! "rustbot, foo"). Private messages follow the same rules, unless the server
+//! allows bare commands in private, where the prefix is optional. The first
+//! word names the command, and the rest of the message is split into
+//! arguments the way a shell would.
+
+/// The command prefixes for the current server
+pub struct Prefixes {
+    priv default: ~str,
+    priv channels: ~[(~[u8], ~str)], // channel names are lowercased
+    priv bare_private: bool
+}
+
+impl Prefixes {
+    pub fn new(default: &str, channels: &[(~str, ~str)], bare_private: bool) -> Prefixes {
+        Prefixes {
+            default: default.to_owned(),
+            channels: channels.iter().map(|&(ref c, ref p)| (lower(c.as_bytes()), p.clone()))
+                              .collect(),
+            bare_private: bare_private
+        }
+    }
+
+    /// Returns true if every private message is a command, even without the
+    /// prefix or the bot's nick
+    pub fn bare_private(&self) -> bool {
+        self.bare_private
+    }
+
+    /// Returns the prefix used outside of any configured channel
+    pub fn default_prefix<'a>(&'a self) -> &'a str {
+        self.default.as_slice()
+    }
+
+    /// Returns the prefix for commands sent to the given channel
+    pub fn prefix_for<'a>(&'a self, chan: &[u8]) -> &'a str {
+        let chan = lower(chan);
+        match self.channels.iter().find(|&&(ref c, _)| *c == chan) {
+            None => self.default.as_slice(),
+            Some(&(_, ref p)) => p.as_slice()
+        }
+    }
+}
+
+/// A command invocation found in a message
+pub struct Invocation {
+    /// The lowercased command name
+    name: ~[u8],
+    /// The text after the command name, not yet split into arguments
+    text: ~[u8]
+}
+
+/// Parses the text of a message, returning the invocation if it's a command.
+/// `me` is the bot's nick, and `bare` says whether the message is a command
+/// even without the prefix or the bot's nick.
+pub fn parse(text: &[u8], prefix: &[u8], me: &[u8], bare: bool) -> Option<Invocation> {
+    let rest = if !prefix.is_empty() && text.starts_with(prefix) {
+        text.slice_from(prefix.len())
+    } else if text.len() > me.len() && lower(text.slice_to(me.len())) == lower(me) &&
+              (text[me.len()] == ':' as u8 || text[me.len()] == ',' as u8) {
+        trim_left(text.slice_from(me.len() + 1))
+    } else if bare {
+        text
+    } else {
+        return None;
+    };
+
+    let (name, text) = match rest.iter().position(|&b| is_space(b)) {
+        None => (rest, &[]),
+        Some(idx) => (rest.slice_to(idx), trim_left(rest.slice_from(idx)))
+    };
+    if name.is_empty() {
+        return None;
+    }
+    Some(Invocation { name: lower(name), text: text.to_owned() })
+}
+
+/// Splits text into arguments. Arguments are separated by whitespace, which
+/// can be included in an argument by quoting it. Text in 'single quotes' is
+/// taken literally, and in "double quotes" a backslash escapes the next
+/// character. Outside of quotes, a backslash also escapes the next character.
+pub fn split_args(text: &[u8]) -> Result<~[~[u8]], ~str> {
+    let mut args = ~[];
+    let mut cur = ~[];
+    let mut in_arg = false;
+    let mut iter = text.iter();
+    loop {
+        let b = match iter.next() {
+            None => break,
+            Some(&b) => b
+        };
+        match b as char {
+            '\'' => {
+                in_arg = true;
+                loop {
+                    match iter.next() {
+                        None => return Err(~"unterminated ' quote"),
+                        Some(&b) if b == '\'' as u8 => break,
+                        Some(&b) => cur.push(b)
+                    }
+                }
+            }
+            '"' => {
+                in_arg = true;
+                loop {
+                    match iter.next() {
+                        None => return Err(~"unterminated \" quote"),
+                        Some(&b) if b == '"' as u8 => break,
+                        Some(&b) if b == '\\' as u8 => {
+                            match iter.next() {
+                                None => return Err(~"unterminated \" quote"),
+                                Some(&b) => cur.push(b)
+                            }
+                        }
+                        Some(&b) => cur.push(b)
+                    }
+                }
+            }
+            '\\' => {
+                in_arg = true;
+                // a trailing backslash is kept as is
+                cur.push(iter.next().map_or(b, |&b| b));
+            }
+            _ if is_space(b) => {
+                if in_arg {
+                    args.push(::std::mem::replace(&mut cur, ~[]));
+                    in_arg = false;
+                }
+            }
+            _ => {
+                in_arg = true;
+                cur.push(b);
+            }
+        }
+    }
+    if in_arg {
+        args.push(cur);
+    }
+    Ok(args)
+}
+
+fn is_space(b: u8) -> bool {
+    b == ' ' as u8 || b == '\t' as u8
+}
+
+fn trim_left<'a>(s: &'a [u8]) -> &'a [u8] {
+    match s.iter().position(|&b| !is_space(b)) {
+        None => &[],
+        Some(idx) => s.slice_from(idx)
+    }
+}
+
+/// Lowercases the ASCII letters of s
+pub fn lower(s: &[u8]) -> ~[u8] {
+    s.iter().map(|&b| if b >= 'A' as u8 && b <= 'Z' as u8 { b + 32 } else { b }).collect()
+}
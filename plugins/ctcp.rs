@@ -0,0 +1,53 @@
+//! Built-in replies to CTCP queries
+//!
+//! The bot answers VERSION, PING, TIME, CLIENTINFO and SOURCE queries itself,
+//! so it isn't mistaken for a dead client by bots that check. The VERSION and
+//! SOURCE replies come from the [ctcp] section of the config file, which can
+//! also disable any of the built-in replies. Plugins may override or add
+//! replies, or disable a CTCP type altogether, with irc.ctcp_reply.
+
+use config;
+use time;
+
+/// The CTCP types answered by default
+pub static BUILTINS: &'static [&'static str] = &["CLIENTINFO", "PING", "SOURCE", "TIME",
+                                                 "VERSION"];
+
+/// Returns true if the built-in reply to the uppercased CTCP type is enabled
+pub fn is_enabled(conf: &config::Ctcp, cmd: &str) -> bool {
+    BUILTINS.contains(&cmd) && !conf.disabled.iter().any(|d| d.as_slice() == cmd)
+}
+
+/// Returns the built-in reply text for the uppercased CTCP type, if it's
+/// enabled. `supported` lists the types named in a CLIENTINFO reply.
+pub fn builtin_reply(conf: &config::Ctcp, cmd: &str, text: Option<&[u8]>,
+                     supported: &[~str]) -> Option<~[u8]> {
+    if !is_enabled(conf, cmd) {
+        return None;
+    }
+    match cmd {
+        "VERSION" => Some(conf.version.as_bytes().to_owned()),
+        "SOURCE" => Some(conf.source.as_bytes().to_owned()),
+        "PING" => Some(text.unwrap_or(bytes!("")).to_owned()),
+        "TIME" => Some(time::now().rfc822().into_bytes()),
+        "CLIENTINFO" => Some(supported.connect(" ").into_bytes()),
+        _ => None
+    }
+}
+
+/// Builds the text of a CTCP message, for a query PRIVMSG or a reply NOTICE.
+/// Characters that would end the message or the line are dropped from the text.
+pub fn frame(cmd: &str, text: &[u8]) -> ~[u8] {
+    let mut msg = ~[1u8];
+    msg.push_all(cmd.as_bytes());
+    if !text.is_empty() {
+        msg.push(' ' as u8);
+        for &b in text.iter() {
+            if b != 1 && b != '\r' as u8 && b != '\n' as u8 {
+                msg.push(b);
+            }
+        }
+    }
+    msg.push(1);
+    msg
+}
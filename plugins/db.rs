@@ -0,0 +1,431 @@
+//! SQLite databases for plugins
+//!
+//! Vends a package named 'irc.db'. db.open(name) opens (creating if needed)
+//! the database of that name for the calling plugin, stored under the plugin
+//! data dir. The name is optional and defaults to "plugin".
+//!
+//! Database objects have the following methods:
+//!
+//! db:exec(sql, ...): Runs a statement, returning the number of rows changed
+//! db:query(sql, ...): Returns an iterator over the result rows, for use with
+//!     a generic for. Each row is a table keyed by column name.
+//! db:first(sql, ...): Returns the first result row, or nil
+//! db:transaction(f): Calls f(db) inside a transaction. The transaction is
+//!     committed if f returns normally and rolled back if it raises an error,
+//!     in which case the error is re-raised.
+//! db:last_insert_id(): Returns the rowid of the most recent insert
+//! db:close(): Closes the database. This also happens when it's collected.
+//!
+//! The sql given to exec, query and first must be a single statement. Any
+//! extra arguments to them are bound in order to the ? parameters of the
+//! statement. Parameters may be nil, booleans, numbers or strings.
+
+#[allow(uppercase_variables)];
+
+use lua;
+use super::{raise, store};
+use std::{libc, mem, ptr, str, vec};
+use std::libc::c_int;
+
+static DB_MT: &'static str = "irc.db.Database";
+static STMT_MT: &'static str = "irc.db.Statement";
+
+struct Database {
+    db: *mut ffi::sqlite3
+}
+
+struct Statement {
+    stmt: *mut ffi::sqlite3_stmt
+}
+
+lua_extern_pub! {
+    unsafe fn lua_require(L: &mut lua::ExternState) -> i32 {
+        // 1 argument is passed: modname
+
+        L.newmetatable(DB_MT);
+        L.newtable();
+        L.registerlib(None, [
+            ("exec", lua_db_exec),
+            ("query", lua_db_query),
+            ("first", lua_db_first),
+            ("transaction", lua_db_transaction),
+            ("last_insert_id", lua_db_last_insert_id),
+            ("close", lua_db_close)
+        ]);
+        L.setfield(-2, "__index");
+        L.pushcfunction(lua_db_close);
+        L.setfield(-2, "__gc");
+        L.pop(1);
+
+        L.newmetatable(STMT_MT);
+        L.pushcfunction(lua_stmt_gc);
+        L.setfield(-2, "__gc");
+        L.pop(1);
+
+        L.newtable();
+        L.registerlib(None, [
+            ("open", lua_db_open)
+        ]);
+        1
+    }
+}
+
+lua_extern! {
+    unsafe fn lua_db_open(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: name (optional)
+
+        let name = L.optstring(1, "plugin").unwrap_or("");
+        L.argcheck(store::valid_name(name), 1,
+                   "name may only contain letters, digits, '-' and '_'");
+
+        let ud = L.newuserdata(mem::size_of::<Database>()) as *mut Database;
+        (*ud).db = ptr::mut_null();
+        L.getfield(lua::REGISTRYINDEX, DB_MT);
+        L.setmetatable(-2);
+
+        // the paths are freed before any error is raised
+        let err = {
+            let dir = store::plugin_dir(L);
+            let path = dir.join(format!("{}.sqlite3", name));
+            match ::std::io::fs::mkdir_recursive(&dir, ::std::io::UserDir) {
+                Err(e) => Some(format!("could not create {}: {}", dir.display(), e)),
+                Ok(()) => {
+                    let mut db = ptr::mut_null();
+                    let rc = path.with_c_str(|p| ffi::sqlite3_open(p, &mut db));
+                    if rc != ffi::SQLITE_OK {
+                        let msg = if db.is_null() { ~"out of memory" } else { errmsg(db) };
+                        ffi::sqlite3_close(db);
+                        Some(format!("could not open {}: {}", path.display(), msg))
+                    } else {
+                        (*ud).db = db;
+                        None
+                    }
+                }
+            }
+        };
+        match err {
+            None => 1,
+            Some(msg) => raise(L, msg)
+        }
+    }
+
+    unsafe fn lua_db_exec(L: &mut lua::ExternState) -> i32 {
+        // 2+ args: db, sql, params...
+
+        let db = check_db(L, 1);
+        let ud = prepare(L, db);
+        loop {
+            match ffi::sqlite3_step((*ud).stmt) {
+                ffi::SQLITE_ROW => (),
+                ffi::SQLITE_DONE => break,
+                _ => raise_errmsg(L, db, Some(ud))
+            }
+        }
+        finalize(ud);
+        L.pushinteger(ffi::sqlite3_changes(db) as int);
+        1
+    }
+
+    unsafe fn lua_db_query(L: &mut lua::ExternState) -> i32 {
+        // 2+ args: db, sql, params...
+
+        let db = check_db(L, 1);
+        prepare(L, db);
+
+        // the iterator holds on to the statement and the database
+        L.pushvalue(1);
+        L.pushcclosure(lua_rows_next, 2);
+        1
+    }
+
+    unsafe fn lua_db_first(L: &mut lua::ExternState) -> i32 {
+        // 2+ args: db, sql, params...
+
+        let db = check_db(L, 1);
+        let ud = prepare(L, db);
+        match ffi::sqlite3_step((*ud).stmt) {
+            ffi::SQLITE_ROW => push_row(L, (*ud).stmt),
+            ffi::SQLITE_DONE => L.pushnil(),
+            _ => raise_errmsg(L, db, Some(ud))
+        }
+        finalize(ud);
+        1
+    }
+
+    unsafe fn lua_db_transaction(L: &mut lua::ExternState) -> i32 {
+        // 2 args: db, func
+
+        let db = check_db(L, 1);
+        L.checktype(2, lua::Type::Function);
+        L.settop(2);
+
+        exec_simple(L, db, "BEGIN");
+        L.pushvalue(1);
+        match L.pcall(1, 0, 0) {
+            Ok(()) => {
+                let rc = "COMMIT".with_c_str(|sql| {
+                    ffi::sqlite3_exec(db, sql, ptr::null(), ptr::mut_null(), ptr::mut_null())
+                });
+                if rc != ffi::SQLITE_OK {
+                    // a failed commit leaves the transaction open
+                    {
+                        let msg = errmsg(db);
+                        L.pushstring(msg.as_slice());
+                    }
+                    rollback(db);
+                    L.error();
+                }
+                0
+            }
+            Err(_) => {
+                // the error is on top of the stack, raise it again once we've rolled back
+                rollback(db);
+                L.error()
+            }
+        }
+    }
+
+    unsafe fn lua_db_last_insert_id(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: db
+
+        let db = check_db(L, 1);
+        L.pushinteger(ffi::sqlite3_last_insert_rowid(db) as int);
+        1
+    }
+
+    unsafe fn lua_db_close(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: db
+
+        let ud = L.checkudata(1, DB_MT) as *mut Database;
+        if (*ud).db.is_not_null() {
+            // outstanding statements keep the connection open until they're finalized
+            ffi::sqlite3_close_v2((*ud).db);
+            (*ud).db = ptr::mut_null();
+        }
+        0
+    }
+
+    unsafe fn lua_stmt_gc(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: statement
+
+        let ud = L.checkudata(1, STMT_MT) as *mut Statement;
+        finalize(ud);
+        0
+    }
+
+    unsafe fn lua_rows_next(L: &mut lua::ExternState) -> i32 {
+        // upvalues: statement, db
+
+        let ud = L.touserdata(lua::upvalueindex(1)) as *mut Statement;
+        let stmt = (*ud).stmt;
+        if stmt.is_null() {
+            return 0; // already exhausted
+        }
+        match ffi::sqlite3_step(stmt) {
+            ffi::SQLITE_ROW => {
+                push_row(L, stmt);
+                1
+            }
+            ffi::SQLITE_DONE => {
+                finalize(ud);
+                0
+            }
+            _ => raise_errmsg(L, ffi::sqlite3_db_handle(stmt), Some(ud))
+        }
+    }
+}
+
+// returns the open connection of the database at the given index
+unsafe fn check_db(L: &mut lua::ExternState, idx: i32) -> *mut ffi::sqlite3 {
+    let ud = L.checkudata(idx, DB_MT) as *mut Database;
+    if (*ud).db.is_null() {
+        L.errorstr("database is closed");
+    }
+    (*ud).db
+}
+
+// prepares the statement at index 2, binding the values after it as
+// parameters. The Statement is pushed as a userdata, which finalizes it when
+// it's collected if an error is raised before it's finished with.
+unsafe fn prepare(L: &mut lua::ExternState, db: *mut ffi::sqlite3) -> *mut Statement {
+    L.checkbytes(2);
+    let top = L.gettop();
+    let ud = L.newuserdata(mem::size_of::<Statement>()) as *mut Statement;
+    (*ud).stmt = ptr::mut_null();
+    L.getfield(lua::REGISTRYINDEX, STMT_MT);
+    L.setmetatable(-2);
+
+    let sql = L.tobytes(2).unwrap();
+    let mut tail = ptr::null();
+    let rc = ffi::sqlite3_prepare_v2(db, sql.as_ptr() as *libc::c_char, sql.len() as c_int,
+                                     &mut (*ud).stmt, &mut tail);
+    if rc != ffi::SQLITE_OK {
+        raise_errmsg(L, db, None);
+    }
+    let stmt = (*ud).stmt;
+    if stmt.is_null() {
+        L.errorstr("no SQL statement given");
+    }
+    // only the first statement would be run, so don't silently drop the rest
+    let rest = sql.slice_from(tail as uint - sql.as_ptr() as uint);
+    if rest.iter().any(|&b| !(b as char).is_whitespace()) {
+        finalize(ud);
+        L.errorstr("only one SQL statement may be given");
+    }
+
+    let nparams = ffi::sqlite3_bind_parameter_count(stmt) as i32;
+    if top - 2 != nparams {
+        finalize(ud);
+        raise(L, format!("statement expects {} parameters, but {} were given",
+                         nparams, top - 2));
+    }
+    for i in range(3, top + 1) {
+        let n = (i - 2) as c_int;
+        let rc = match L.type_(i) {
+            None | Some(lua::Type::Nil) => ffi::sqlite3_bind_null(stmt, n),
+            Some(lua::Type::Boolean) => {
+                ffi::sqlite3_bind_int64(stmt, n, if L.toboolean(i) { 1 } else { 0 })
+            }
+            Some(lua::Type::Number) => {
+                let v = L.tonumber(i);
+                if v == v.floor() && v.abs() < 9007199254740992.0 {
+                    ffi::sqlite3_bind_int64(stmt, n, v as i64)
+                } else {
+                    ffi::sqlite3_bind_double(stmt, n, v)
+                }
+            }
+            Some(lua::Type::String) => {
+                let v = L.tobytes(i).unwrap();
+                ffi::sqlite3_bind_text(stmt, n, v.as_ptr() as *libc::c_char, v.len() as c_int,
+                                       ffi::SQLITE_TRANSIENT)
+            }
+            Some(_) => {
+                finalize(ud);
+                L.argerror(i, "parameters must be nil, booleans, numbers or strings");
+            }
+        };
+        if rc != ffi::SQLITE_OK {
+            raise_errmsg(L, db, Some(ud));
+        }
+    }
+    ud
+}
+
+// finalizes the statement, if it hasn't been already
+unsafe fn finalize(ud: *mut Statement) {
+    if (*ud).stmt.is_not_null() {
+        ffi::sqlite3_finalize((*ud).stmt);
+        (*ud).stmt = ptr::mut_null();
+    }
+}
+
+// raises the database's most recent error, finalizing the statement if given
+unsafe fn raise_errmsg(L: &mut lua::ExternState, db: *mut ffi::sqlite3,
+                       ud: Option<*mut Statement>) -> ! {
+    {
+        let msg = errmsg(db);
+        L.pushstring(msg.as_slice());
+    }
+    match ud {
+        Some(ud) => finalize(ud),
+        None => ()
+    }
+    L.error()
+}
+
+// rolls back the open transaction, ignoring any error
+unsafe fn rollback(db: *mut ffi::sqlite3) {
+    "ROLLBACK".with_c_str(|sql| {
+        ffi::sqlite3_exec(db, sql, ptr::null(), ptr::mut_null(), ptr::mut_null())
+    });
+}
+
+// pushes the current row of the statement as a table keyed by column name
+unsafe fn push_row(L: &mut lua::ExternState, stmt: *mut ffi::sqlite3_stmt) {
+    let ncols = ffi::sqlite3_column_count(stmt);
+    L.createtable(0, ncols as i32);
+    for i in range(0, ncols) {
+        match ffi::sqlite3_column_type(stmt, i) {
+            ffi::SQLITE_INTEGER => L.pushinteger(ffi::sqlite3_column_int64(stmt, i) as int),
+            ffi::SQLITE_FLOAT => L.pushnumber(ffi::sqlite3_column_double(stmt, i)),
+            ffi::SQLITE_NULL => L.pushnil(),
+            _ => {
+                let p = ffi::sqlite3_column_blob(stmt, i) as *u8;
+                let n = ffi::sqlite3_column_bytes(stmt, i) as uint;
+                if p.is_null() {
+                    L.pushstring("");
+                } else {
+                    vec::raw::buf_as_slice(p, n, |v| L.pushbytes(v));
+                }
+            }
+        }
+        let name = str::raw::from_c_str(ffi::sqlite3_column_name(stmt, i));
+        L.setfield(-2, name.as_slice());
+    }
+}
+
+// runs a statement that takes no parameters and returns no rows
+unsafe fn exec_simple(L: &mut lua::ExternState, db: *mut ffi::sqlite3, sql: &str) {
+    let rc = sql.with_c_str(|sql| {
+        ffi::sqlite3_exec(db, sql, ptr::null(), ptr::mut_null(), ptr::mut_null())
+    });
+    if rc != ffi::SQLITE_OK {
+        raise_errmsg(L, db, None);
+    }
+}
+
+unsafe fn errmsg(db: *mut ffi::sqlite3) -> ~str {
+    str::raw::from_c_str(ffi::sqlite3_errmsg(db))
+}
+
+#[allow(non_camel_case_types)]
+mod ffi {
+    use std::libc::{c_char, c_double, c_int, c_void};
+
+    pub enum sqlite3 {}
+    pub enum sqlite3_stmt {}
+
+    pub static SQLITE_OK: c_int = 0;
+    pub static SQLITE_ROW: c_int = 100;
+    pub static SQLITE_DONE: c_int = 101;
+
+    pub static SQLITE_INTEGER: c_int = 1;
+    pub static SQLITE_FLOAT: c_int = 2;
+    pub static SQLITE_NULL: c_int = 5;
+
+    // tells sqlite to make its own copy of bound values
+    pub static SQLITE_TRANSIENT: int = -1;
+
+    #[link(name = "sqlite3")]
+    extern "C" {
+        pub fn sqlite3_open(filename: *c_char, db: *mut *mut sqlite3) -> c_int;
+        pub fn sqlite3_close(db: *mut sqlite3) -> c_int;
+        pub fn sqlite3_close_v2(db: *mut sqlite3) -> c_int;
+        pub fn sqlite3_errmsg(db: *mut sqlite3) -> *c_char;
+        pub fn sqlite3_changes(db: *mut sqlite3) -> c_int;
+        pub fn sqlite3_last_insert_rowid(db: *mut sqlite3) -> i64;
+        pub fn sqlite3_exec(db: *mut sqlite3, sql: *c_char, callback: *c_void,
+                            arg: *mut c_void, errmsg: *mut *mut c_char) -> c_int;
+
+        pub fn sqlite3_prepare_v2(db: *mut sqlite3, sql: *c_char, nbyte: c_int,
+                                  stmt: *mut *mut sqlite3_stmt, tail: *mut *c_char) -> c_int;
+        pub fn sqlite3_db_handle(stmt: *mut sqlite3_stmt) -> *mut sqlite3;
+        pub fn sqlite3_step(stmt: *mut sqlite3_stmt) -> c_int;
+        pub fn sqlite3_finalize(stmt: *mut sqlite3_stmt) -> c_int;
+
+        pub fn sqlite3_bind_parameter_count(stmt: *mut sqlite3_stmt) -> c_int;
+        pub fn sqlite3_bind_null(stmt: *mut sqlite3_stmt, i: c_int) -> c_int;
+        pub fn sqlite3_bind_int64(stmt: *mut sqlite3_stmt, i: c_int, v: i64) -> c_int;
+        pub fn sqlite3_bind_double(stmt: *mut sqlite3_stmt, i: c_int, v: c_double) -> c_int;
+        pub fn sqlite3_bind_text(stmt: *mut sqlite3_stmt, i: c_int, v: *c_char, n: c_int,
+                                 destructor: int) -> c_int;
+
+        pub fn sqlite3_column_count(stmt: *mut sqlite3_stmt) -> c_int;
+        pub fn sqlite3_column_name(stmt: *mut sqlite3_stmt, i: c_int) -> *c_char;
+        pub fn sqlite3_column_type(stmt: *mut sqlite3_stmt, i: c_int) -> c_int;
+        pub fn sqlite3_column_int64(stmt: *mut sqlite3_stmt, i: c_int) -> i64;
+        pub fn sqlite3_column_double(stmt: *mut sqlite3_stmt, i: c_int) -> c_double;
+        pub fn sqlite3_column_blob(stmt: *mut sqlite3_stmt, i: c_int) -> *c_void;
+        pub fn sqlite3_column_bytes(stmt: *mut sqlite3_stmt, i: c_int) -> c_int;
+    }
+}
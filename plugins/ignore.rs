@@ -0,0 +1,281 @@
+//! The ignore list
+//!
+//! Messages from ignored users (PRIVMSG, NOTICE, ACTION, CTCP and INVITE) are
+//! dropped before the plugins see them. A user is ignored by hostmask, e.g.
+//! "*!*@spam.example", or by services account, written "$a:account". A bare
+//! nick is taken as the hostmask "nick!*@*". Each entry may expire, and may be
+//! limited to one server or one channel.
+//!
+//! Account entries only match users whose account the bot already knows, from
+//! extended JOIN, account-notify or an earlier WHOIS. Messages aren't held
+//! back to look the sender up, so a user the bot has seen no account for isn't
+//! matched by them; pair an account entry with a hostmask to cover that.
+//!
+//! The list is kept in the ignore.list file next to the config file, with one
+//! entry per line: the target, the expiry time in seconds since the epoch,
+//! the server name and the channel, separated by spaces, with "-" for a value
+//! that isn't set. Server names come from the config file and may contain
+//! anything, so spaces, "%" and a lone "-" in them are %-encoded.
+
+use super::acl::glob_match;
+use super::command::lower;
+use std::{io, num, str};
+use std::io::IoError;
+use std::num::{CheckedAdd, CheckedMul};
+use time;
+
+/// An entry of the ignore list
+#[deriving(Clone)]
+pub struct Entry {
+    /// The lowercased hostmask, or "$a:" followed by the lowercased account
+    target: ~str,
+    /// When the entry expires, in seconds since the epoch
+    expires: Option<i64>,
+    /// The server the entry applies to, or None for every server
+    server: Option<~str>,
+    /// The lowercased channel the entry applies to, or None for every channel
+    channel: Option<~str>
+}
+
+/// The ignore list shared by the stdin commands and the plugins
+pub struct IgnoreList {
+    priv path: Path,
+    priv server: ~str, // the name of the current server
+    priv entries: ~[Entry]
+}
+
+impl IgnoreList {
+    /// Loads the ignore list from the given file, if it exists
+    pub fn load(path: Path, server: &str) -> IgnoreList {
+        let mut list = IgnoreList { path: path, server: server.to_owned(), entries: ~[] };
+        if !list.path.exists() {
+            return list;
+        }
+        let contents = match io::File::open(&list.path).and_then(|mut f| f.read_to_end()) {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error reading ignore list {}: {}", list.path.display(), e);
+                return list;
+            }
+        };
+        for line in str::from_utf8_lossy(contents).as_slice().lines() {
+            let words = line.words().collect::<~[&str]>();
+            if words.len() != 4 {
+                continue;
+            }
+            let opt = |s: &str| if s == "-" { None } else { Some(s.to_owned()) };
+            let server = if words[2] == "-" {
+                None
+            } else {
+                match decode_server(words[2]) {
+                    None => continue,
+                    Some(s) => Some(s)
+                }
+            };
+            let expires = if words[1] == "-" {
+                None
+            } else {
+                match from_str::<i64>(words[1]) {
+                    None => continue,
+                    Some(t) => Some(t)
+                }
+            };
+            list.entries.push(Entry {
+                target: words[0].to_owned(),
+                expires: expires,
+                server: server,
+                channel: opt(words[3])
+            });
+        }
+        list
+    }
+
+    /// Returns the name of the current server, for scoping entries to it
+    pub fn server<'a>(&'a self) -> &'a str {
+        self.server.as_slice()
+    }
+
+    /// Adds an entry, replacing any entry with the same target and scope
+    pub fn add(&mut self, entry: Entry) -> Result<(), IoError> {
+        self.entries.retain(|e| {
+            !(e.target == entry.target && e.server == entry.server && e.channel == entry.channel)
+        });
+        self.entries.push(entry);
+        self.save()
+    }
+
+    /// Removes every entry for the target, returning the number removed
+    pub fn remove(&mut self, target: &str) -> Result<uint, IoError> {
+        let target = normalize_target(target);
+        let len = self.entries.len();
+        self.entries.retain(|e| e.target != target);
+        let removed = len - self.entries.len();
+        if removed > 0 {
+            match self.save() {
+                Ok(()) => (),
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Returns the entries that haven't expired
+    pub fn entries<'a>(&'a mut self) -> &'a [Entry] {
+        self.expire();
+        self.entries.as_slice()
+    }
+
+    /// Returns true if a message from the user with the given nick!user@host
+    /// and account, sent to the given channel if any, is ignored
+    pub fn is_ignored(&mut self, mask: &[u8], account: Option<&[u8]>,
+                      chan: Option<&[u8]>) -> bool {
+        if self.entries.is_empty() {
+            return false;
+        }
+        self.expire();
+        let mask = lower(mask);
+        let chan = chan.map(|c| lower(c));
+        let server = self.server.as_slice();
+        self.entries.iter().any(|e| {
+            let in_scope = e.server.as_ref().map_or(true, |s| s.as_slice() == server) &&
+                           e.channel.as_ref().map_or(true, |c| {
+                               chan.as_ref().map_or(false, |ch| c.as_bytes() == ch.as_slice())
+                           });
+            in_scope && if e.target.starts_with("$a:") {
+                let ignored = e.target.as_bytes().slice_from(3);
+                account.map_or(false, |a| lower(a).as_slice() == ignored)
+            } else {
+                glob_match(e.target.as_bytes(), mask.as_slice())
+            }
+        })
+    }
+
+    // drops the expired entries, saving the list if any were dropped
+    fn expire(&mut self) {
+        let now = time::get_time().sec;
+        let len = self.entries.len();
+        self.entries.retain(|e| e.expires.map_or(true, |t| t > now));
+        if self.entries.len() != len {
+            match self.save() {
+                Ok(()) => (),
+                Err(e) => println!("Error saving ignore list {}: {}", self.path.display(), e)
+            }
+        }
+    }
+
+    fn save(&self) -> Result<(), IoError> {
+        let mut out = ~"";
+        for e in self.entries.iter() {
+            out.push_str(format!("{} {} {} {}\n", e.target,
+                                 e.expires.map_or(~"-", |t| t.to_str()),
+                                 e.server.as_ref().map_or(~"-", |s| encode_server(s.as_slice())),
+                                 e.channel.as_ref().map_or(~"-", |c| c.clone())));
+        }
+        let tmp = self.path.with_extension("tmp");
+        match io::File::create(&tmp).and_then(|mut f| f.write(out.as_bytes())) {
+            Ok(()) => (),
+            Err(e) => return Err(e)
+        }
+        io::fs::rename(&tmp, &self.path)
+    }
+}
+
+impl Entry {
+    /// Creates an entry for the given target, which may be a hostmask, a
+    /// "$a:account" or a nick. `duration` is in seconds.
+    pub fn new(target: &str, duration: Option<i64>, server: Option<&str>,
+               channel: Option<&str>) -> Result<Entry, ~str> {
+        let spaced = |s: &str| s.chars().any(|c| c.is_whitespace());
+        if target.is_empty() || target == "$a:" || spaced(target) {
+            return Err(format!("invalid ignore target `{}'", target));
+        }
+        if channel.map_or(false, |c| c.is_empty() || spaced(c)) {
+            return Err(~"invalid channel");
+        }
+        let expires = match duration {
+            None => None,
+            Some(d) => match time::get_time().sec.checked_add(&d) {
+                None => return Err(~"duration is too long"),
+                Some(t) => Some(t)
+            }
+        };
+        Ok(Entry {
+            target: normalize_target(target),
+            expires: expires,
+            server: server.map(|s| s.to_owned()),
+            channel: channel.map(|c| str::from_utf8_lossy(lower(c.as_bytes())).into_owned())
+        })
+    }
+}
+
+// lowercases the target, turning a bare nick into a hostmask
+fn normalize_target(target: &str) -> ~str {
+    let target = str::from_utf8_lossy(lower(target.as_bytes())).into_owned();
+    if target.starts_with("$a:") || target.contains_char('!') || target.contains_char('@') {
+        target
+    } else {
+        format!("{}!*@*", target)
+    }
+}
+
+/// Parses a duration such as "90", "30m", "2h" or "1d" into seconds
+pub fn parse_duration(s: &str) -> Option<i64> {
+    if s.is_empty() {
+        return None;
+    }
+    let (num, unit) = match s.char_range_at_reverse(s.len()).ch {
+        's' => (s.slice_to(s.len() - 1), 1),
+        'm' => (s.slice_to(s.len() - 1), 60),
+        'h' => (s.slice_to(s.len() - 1), 60 * 60),
+        'd' => (s.slice_to(s.len() - 1), 24 * 60 * 60),
+        _ => (s, 1)
+    };
+    from_str::<i64>(num).and_then(|n| if n > 0 { n.checked_mul(&unit) } else { None })
+}
+
+// %-encodes the bytes of a server name that the ignore.list format can't hold
+fn encode_server(server: &str) -> ~str {
+    if server == "-" {
+        return ~"%2D";
+    }
+    let mut out = ~"";
+    for c in server.chars() {
+        if c.is_whitespace() || c == '%' {
+            let mut buf = [0u8, ..4];
+            let n = c.encode_utf8(buf);
+            for &b in buf.slice_to(n).iter() {
+                out.push_str(format!("%{:02X}", b));
+            }
+        } else {
+            out.push_char(c);
+        }
+    }
+    out
+}
+
+// reverses encode_server, returning None if the name is badly encoded
+fn decode_server(server: &str) -> Option<~str> {
+    let bytes = server.as_bytes();
+    let mut out = ~[];
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == '%' as u8 {
+            if i + 3 > bytes.len() {
+                return None;
+            }
+            let hex = match str::from_utf8(bytes.slice(i + 1, i + 3)) {
+                None => return None,
+                Some(hex) => hex
+            };
+            match num::from_str_radix::<u8>(hex, 16) {
+                None => return None,
+                Some(b) => out.push(b)
+            }
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    str::from_utf8_owned(out)
+}
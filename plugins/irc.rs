@@ -10,6 +10,17 @@
 //! arguments: dst and text. CTCP commands and replies provide 2 or 3
 //! arguments: CTCP command name, destionation, and text if provided.
 //!
+//! irc.addhandler returns an opaque handle that can later be passed to
+//! irc.removehandler(handle) to unregister the function. irc.once(event, f)
+//! works like irc.addhandler but removes the handler after its first call.
+//!
+//! Both accept an optional third argument, an integer priority (default 0).
+//! Handlers with a higher priority are called first, and handlers with equal
+//! priority are called in the order they were added. A handler that returns
+//! irc.HANDLED consumes the event, and no lower-priority handler sees it.
+//! Commands, triggers and the built-in CTCP replies only run once the handlers
+//! are done, so a consumed message doesn't reach them either.
+//!
 //! Note that the arguments of any arbitrary IRC command should not be assumed.
 //! e.g. PRIVMSG should have 2 arguments: dst, and text. But the actual arguments
 //! are provided by the IRC server and are not validated by the bot before being
@@ -18,15 +29,188 @@
 //! Note: if the prefix was not provided for a given command, it will be given
 //! to Lua as nil. Otherwise, it will be a table representation of the User.
 //!
-//! There are 6 special events that can be registered:
+//! There are 9 special events that can be registered:
 //!
 //! irc.CONNECTED: No args
 //! irc.DISCONNECTED: No args
 //! irc.RELOADED: No args, sent when plugins are reloaded instead of CONNECTED
+//! irc.LOADED: The state handed over by the plugin's previous instance, or nil
+//! irc.UNLOADING: No args, sent before plugins are reloaded
+//! irc.SHUTDOWN: No args, sent before the bot quits, or once it's disconnected
+//!     for good if the connection is lost and won't be retried
 //! irc.ACTION: Sender, destination, text
 //! irc.CTCP: Sender, CTCP command, destination, optionally text
 //! irc.CTCPREPLY: Sender, CTCP command, destination, optionally text
 //!
+//! Handlers may also be registered for a wildcard pattern instead of a single
+//! event. irc.ALL ("*") receives every event. A pattern ending in "*" matches
+//! every event with that prefix, e.g. "-CTCP*". A pattern of the form "N-M"
+//! matches numeric replies in that inclusive range, e.g. "400-599" for all
+//! error numerics. Since every handler receives the event name as its first
+//! argument, wildcard handlers can tell which event they were called for.
+//! Wildcard handlers are ordered by priority along with the exact handlers.
+//!
+//! Plugins can publish their own events with irc.emit(name, ...). Custom
+//! events are namespaced by the plugin that emits them, so irc.emit("item", x)
+//! from the plugin "feeds" dispatches the event "feeds:item", which other
+//! plugins subscribe to with irc.addhandler("feeds:item", f) or a wildcard
+//! such as "feeds:*". The name may not contain ':' or spaces, which also keeps
+//! custom events apart from IRC commands and the special events. Handlers
+//! receive the event name, a nil sender, and the arguments given to
+//! irc.emit. Tables are copied for each handler. Handlers run before
+//! irc.emit returns.
+//!
+//! Handlers registered with irc.on(event, f) instead receive a single event
+//! object, a table with the following values:
+//!
+//! command: The event name
+//! sender: The sender User, or nil
+//! target: The destination of a PRIVMSG, NOTICE, ACTION or CTCP, if any
+//! text: The message text of a PRIVMSG, NOTICE, ACTION or CTCP, if any
+//! ctcp: The CTCP command name, for irc.CTCP and irc.CTCPREPLY
+//! params: An array of the event's arguments
+//! is_private: true if the target is not a channel
+//! tags: A table of the line's message tags. The connection doesn't
+//!     negotiate or parse IRCv3 message tags yet, so for now it's always empty
+//! raw: The line received from the server, rebuilt from its parsed form, or
+//!     nil for special events
+//! numeric: The symbolic name of a numeric reply, if known
+//!
+//! Event objects also have two methods. ev:reply(text) sends a PRIVMSG to the
+//! channel, or to the sender if the event was private. ev:notice_reply(text)
+//! does the same with a NOTICE.
+//!
+//! Numeric replies are dispatched under their zero-padded code, e.g. "001".
+//! The standard numerics are also exported by name, so irc.RPL_WELCOME is
+//! "001" and irc.ERR_NICKNAMEINUSE is "433", and irc.addhandler accepts the
+//! symbolic names directly. irc.numeric_name(code) maps a code back to its
+//! name, or nil if it isn't known. Event objects for numerics carry the name
+//! in their numeric field.
+//!
+//! Plugins can also schedule work with timers. irc.after(seconds, f) calls f
+//! once after the given delay, and irc.every(seconds, f) calls it repeatedly
+//! at that interval. Both return a timer that can be passed to
+//! irc.cancel(timer). Timers run with the connection active, so the irc
+//! functions may be used from them. If the bot is disconnected when a timer
+//! comes due, it runs once the connection is re-established. Reloading the
+//! plugins cancels all timers.
+//!
+//! Handlers and timers run as coroutines, which lets them suspend themselves
+//! with irc.wait(event, filter, timeout). The coroutine resumes when the next
+//! matching event arrives, and irc.wait returns that event's arguments, in
+//! the same form a handler would receive them. The event may be a wildcard
+//! pattern. If filter is given, it's called with the event's arguments and
+//! must return true for the event to match. If timeout (in seconds) is given
+//! and expires first, irc.wait returns nothing. Note that irc.wait can't be
+//! used from within a pcall, and that a suspended handler doesn't get to
+//! return irc.HANDLED. A handler that yields any other way is reported as an
+//! error and abandoned. Waits that are still pending when the connection
+//! drops are abandoned too.
+//!
+//! The bot can also run queries whose replies span several lines, gathering
+//! them into a single result:
+//!
+//! irc.whois(nick, cb): A table with nick, user, host, real, server,
+//!     server_info, operator, idle, signon, channels, account and away
+//! irc.who(mask, cb): An array of tables with channel, user, host, server,
+//!     nick, flags, hops and real
+//! irc.names(chan, cb): An array of nicks, including any status prefixes
+//! irc.banlist(chan, cb): An array of tables with mask, setter and time
+//!
+//! The callback is called with the result, or with nil and an error message
+//! if the query failed. If the callback is omitted from within a handler or
+//! timer, the query instead suspends the coroutine and returns the result (or
+//! nil and the error) directly.
+//!
+//! irc.command(spec) registers a bot command. spec is a table with the
+//! following values:
+//!
+//! name: The command name
+//! handler: The function to call
+//! aliases: An array of other names for the command (optional)
+//! usage: A description of the arguments, e.g. "<nick> [reason]" (optional)
+//! help: A short description of what the command does (optional)
+//! min_args: The minimum number of arguments (optional, default 0)
+//! role: The role needed to use the command, "owner", "admin" or "trusted"
+//!     (optional)
+//!
+//! A command is invoked by a message that starts with the command prefix
+//! (configured per server and per channel) or by addressing the bot by nick, as
+//! in "rustbot: name args". Private messages need one or the other too, unless
+//! the server is configured with bare_private_commands. The arguments are split
+//! like a shell would, so quotes and backslashes can be used to include spaces.
+//! The handler is called with an event object followed by the arguments. The
+//! event object has command, sender, target, is_private, prefix, text (the
+//! unsplit arguments) and args values, and the reply methods of other event
+//! objects. If too few arguments are given, the usage is sent back instead.
+//! Unless a plugin registers its own, a help command lists the commands and
+//! shows their usage.
+//!
+//! Roles are granted to users by the [acl.<role>] tables of the config file;
+//! see the acl module for details. irc.has_role(user, role, chan) returns true
+//! if the user, a User table or a "nick!user@host" string, has the role. If
+//! chan is given, channel operator grants for that channel count too. A
+//! command with a role is refused to users without it, with the channel it's
+//! used in counting for operator grants. If the role could be granted by a
+//! services account the bot doesn't know the sender to have, the bot checks
+//! with a WHOIS before running or refusing the command.
+//!
+//! irc.on_match(regex, f) registers a trigger, a handler that's called for
+//! every match of the regex in the text of a PRIVMSG or ACTION. The regex may
+//! be a pattern string or a compiled Regex from irc.regex, the regex module.
+//! Like irc.addhandler, it accepts an optional priority and returns a handle
+//! for irc.removehandler. Triggers run after the other handlers and the bot
+//! commands, and not at all if a handler consumed the message. The trigger is
+//! called with an event object followed by the captures, as regex.match would
+//! return them. The event object has command (PRIVMSG or irc.ACTION), sender,
+//! target, is_private, text, match (the whole match) and captures (an array of
+//! the groups, with false for a group that didn't participate) values, and the
+//! reply methods of other event objects. Each regex is matched once per
+//! message, however many triggers use it.
+//!
+//! irc.ignore(target, opts) adds an entry to the ignore list, which drops the
+//! messages of spambots and abusive users before any plugin sees them. See
+//! the ignore module for the forms target can take. opts is an optional table
+//! with the following values:
+//!
+//! duration: How long to ignore the target, in seconds (default forever)
+//! channel: Only ignore messages sent to this channel
+//! server: If true, only ignore the target on the current server
+//!
+//! irc.unignore(target) removes every entry for the target, returning true if
+//! there were any. irc.ignores() returns an array of the entries, tables with
+//! target, expires (seconds since the epoch), server and channel values.
+//!
+//! The bot replies to CTCP VERSION, PING, TIME, CLIENTINFO and SOURCE queries
+//! itself, as configured by the [ctcp] section of the config file; see the
+//! ctcp module. irc.ctcp_reply(type, reply) overrides the reply to a CTCP
+//! type, or adds a reply to a new one. reply is the text to reply with, or a
+//! function that's called with the sender, the destination and the text (or
+//! nil) of each query and returns the text, or nil to send no reply. A reply
+//! of false disables the type, and nil restores the built-in reply. Replies
+//! are sent before the irc.CTCP handlers run, and CLIENTINFO lists the types
+//! the bot replies to. If several plugins register a reply for the same type,
+//! the last one wins.
+//!
+//! irc.store(namespace) opens persistent storage for the calling plugin. See
+//! the store module for details.
+//!
+//! irc.config() returns a copy of the calling plugin's table from the
+//! [plugins.<name>] section of the config file, or an empty table if there is
+//! none. The table is also passed to the plugin chunk as its second argument.
+//! The config file is watched for changes, which irc.config() picks up as soon
+//! as the bot next hears from the server. Everything else in the file is only
+//! re-read when plugins are reloaded.
+//!
+//! LOADED and UNLOADING are only sent to the handlers of the plugin concerned.
+//! LOADED is sent once all plugins have loaded, after a reload, and at
+//! startup once the bot has connected (just before CONNECTED), so the
+//! connection can be used from it. UNLOADING handlers may return a table, which is handed to the
+//! LOADED handlers of the reloaded plugin so it can pick up where it left off.
+//! The table is subject to the same restrictions as store values, and the
+//! first one returned wins. Event objects for LOADED carry the table in their
+//! state field.
+//!
 //! A User (the sender value) is a table with the following values:
 //!
 //! raw: The raw text comprising the user
@@ -38,18 +222,42 @@
 
 use lua;
 use irc;
+use super::{acl, alloc, command, ctcp, ignore, numerics, pattern, query, store};
+use super::{get_acl, get_ctcp_config, get_ignores, get_timers, get_queries, get_prefixes};
+use super::get_regexes;
+use super::push_current_config;
+use super::raise;
 use irc::conn;
 use irc::conn::{Conn, Event};
-use std::{libc, mem, ptr};
+use std::{cmp, libc, mem, ptr, str};
+use std::ascii::StrAsciiExt;
 use std::io::BufWriter;
 use std::iter::range_inclusive;
 
 static EVT_CONNECTED: &'static str = "-CONNECTED";
 static EVT_DISCONNECTED: &'static str = "-DISCONNECTED";
 static EVT_RELOADED: &'static str = "-RELOADED";
+static EVT_LOADED: &'static str = "-LOADED";
+static EVT_UNLOADING: &'static str = "-UNLOADING";
+static EVT_SHUTDOWN: &'static str = "-SHUTDOWN";
 static EVT_ACTION: &'static str = "-ACTION";
 static EVT_CTCP: &'static str = "-CTCP";
 static EVT_CTCPREPLY: &'static str = "-CTCPREPLY";
+static EVT_ALL: &'static str = "*";
+// the handlers of irc.on_match, never dispatched as an event
+static EVT_MATCH: &'static str = "-MATCH";
+
+static EVENT_MT: &'static str = "irc.Event";
+
+static COMMANDS: &'static str = "commands";
+static TIMERS: &'static str = "timers";
+static WAITERS: &'static str = "waiters";
+// the coroutine that last suspended itself through irc.wait or a query
+static SUSPENDING: &'static str = "suspending";
+static CTCP_REPLIES: &'static str = "ctcp_replies";
+
+static HANDLER_INDEX: &'static str = "handler_index";
+static HANDLER_NEXT_ID: &'static str = "handler_next_id";
 
 lua_extern_pub! {
     unsafe fn lua_require(L: &mut lua::ExternState) -> i32 {
@@ -66,6 +274,28 @@ lua_extern_pub! {
         L.newtable();
         L.registerlib(None, [
             ("addhandler", lua_addhandler),
+            ("removehandler", lua_removehandler),
+            ("once", lua_once),
+            ("on", lua_on),
+            ("on_match", lua_on_match),
+            ("numeric_name", lua_numeric_name),
+            ("emit", lua_emit),
+            ("command", lua_command),
+            ("has_role", lua_has_role),
+            ("ignore", lua_ignore),
+            ("unignore", lua_unignore),
+            ("ignores", lua_ignores),
+            ("ctcp_reply", lua_ctcp_reply),
+            ("after", lua_after),
+            ("every", lua_every),
+            ("cancel", lua_cancel),
+            ("wait", lua_wait),
+            ("whois", lua_whois),
+            ("who", lua_who),
+            ("names", lua_names),
+            ("banlist", lua_banlist),
+            ("store", store::lua_store),
+            ("config", lua_config),
             ("host", lua_host),
             ("me", lua_me),
             //("send_raw", lua_send_raw),
@@ -84,12 +314,41 @@ lua_extern_pub! {
         L.setfield(-2, "DISCONNECTED");
         L.pushstring(EVT_RELOADED);
         L.setfield(-2, "RELOADED");
+        L.pushstring(EVT_LOADED);
+        L.setfield(-2, "LOADED");
+        L.pushstring(EVT_UNLOADING);
+        L.setfield(-2, "UNLOADING");
+        L.pushstring(EVT_SHUTDOWN);
+        L.setfield(-2, "SHUTDOWN");
         L.pushstring(EVT_ACTION);
         L.setfield(-2, "ACTION");
         L.pushstring(EVT_CTCP);
         L.setfield(-2, "CTCP");
         L.pushstring(EVT_CTCPREPLY);
         L.setfield(-2, "CTCPREPLY");
+        L.pushstring(EVT_ALL);
+        L.setfield(-2, "ALL");
+        L.pushlightuserdata(handled_sentinel());
+        L.setfield(-2, "HANDLED");
+
+        pattern::push_module(L);
+        L.setfield(-2, "regex");
+
+        // and the numeric reply names, e.g. irc.RPL_WELCOME = "001"
+        for &(code, name) in numerics::NUMERICS.iter() {
+            push_code(L, code);
+            L.setfield(-2, name);
+        }
+
+        // create the metatable for event objects
+        L.newmetatable(EVENT_MT);
+        L.newtable();
+        L.registerlib(None, [
+            ("reply", lua_event_reply),
+            ("notice_reply", lua_event_notice_reply)
+        ]);
+        L.setfield(-2, "__index");
+        L.pop(1);
 
         1
     }
@@ -100,9 +359,28 @@ lua_extern_pub! {
         let evtptr = L.touserdata(1) as *mut Event;
         L.argcheck(evtptr.is_not_null(), 1, "expected Event");
         let event = &*evtptr;
+        let mut raw = None;
+        let mut listening = true;
 
         L.settop(0); // clear the stack
 
+        // keep track of who's who for the roles
+        match *event {
+            conn::Connected => (),
+            conn::Disconnected => get_acl(L).clear(),
+            conn::LineReceived(ref line) => get_acl(L).feed(line, getconn(L).me().nick())
+        }
+
+        // hand any finished queries back to the plugins that made them
+        let done = match *event {
+            conn::Connected => ~[],
+            conn::Disconnected => get_queries(L).abort_all("disconnected"),
+            conn::LineReceived(ref line) => get_queries(L).feed(line, getconn(L).me().nick())
+        };
+        for c in done.iter() {
+            deliver_query(L, c);
+        }
+
         // get the event name
         match *event {
             conn::Connected => {
@@ -116,19 +394,7 @@ lua_extern_pub! {
 
                 match *command {
                     conn::IRCCode(code) => {
-                        // construct our string on the stack
-                        let mut buf = [0u8, ..64];
-                        let n = {
-                            let mut w = BufWriter::new(buf);
-                            match write!(&mut w, "{:03u}", code).and_then(|_| w.tell()) {
-                                Ok(n) => n,
-                                Err(e) => {
-                                    drop(e);
-                                    L.errorstr("could not format IRCCode");
-                                }
-                            }
-                        };
-                        L.pushbytes(buf.slice_to(n as uint));
+                        push_code(L, code);
                     }
                     conn::IRCCmd(ref cmd) => {
                         L.pushstring(cmd.as_slice());
@@ -149,39 +415,114 @@ lua_extern_pub! {
                     }
                 }
 
-                // ensure we actually have a handler for this event before proceeding
-                L.pushlightuserdata(lua_addhandler as *mut libc::c_void);
-                L.gettable(lua::REGISTRYINDEX);
-                if !L.istable(-1) {
-                    return 0;
-                }
-                L.pushvalue(1); // event name
-                L.gettable(-2);
-                if !L.istable(-1) || L.objlen(-1) == 0 {
-                    return 0;
-                }
-                // we have at least one handler; continue on
-                L.pop(2);
+                // don't bother with the rest if nothing is listening for this event
+                listening = has_listeners(L);
+                if listening {
+                    raw = Some(raw_line(line));
 
-                // construct the sender
-                match *prefix {
-                    None => {
-                        L.pushnil();
+                    // construct the sender
+                    match *prefix {
+                        None => {
+                            L.pushnil();
+                        }
+                        Some(ref user) => {
+                            push_user(L, user);
+                        }
                     }
-                    Some(ref user) => {
-                        push_user(L, user);
+                    // move sender just after the event name
+                    L.insert(2);
+                    // add any arguments
+                    for arg in args.iter() {
+                        L.pushbytes(*arg);
                     }
                 }
-                // move sender just after the event name
-                L.insert(2);
-                // add any arguments
-                for arg in args.iter() {
-                    L.pushbytes(*arg);
-                }
             }
         }
 
-        dispatch_event_inner(L);
+        let handled = listening &&
+                      dispatch_event_inner(L, raw.as_ref().map(|r| r.as_slice()), None);
+        L.settop(0);
+
+        // bot commands, triggers and CTCP replies run after the handlers, unless
+        // one of them consumed the message
+        match *event {
+            _ if handled => (),
+            conn::LineReceived(conn::Line{command: conn::IRCCmd(ref cmd), ref args,
+                                          prefix: Some(ref user)})
+                    if cmd.as_slice() == "PRIVMSG" && args.len() == 2 => {
+                dispatch_command(L, user, args[0], args[1]);
+                dispatch_triggers(L, "PRIVMSG", user, args[0], args[1]);
+            }
+            conn::LineReceived(conn::Line{command: conn::IRCAction(ref dst), ref args,
+                                          prefix: Some(ref user)}) if args.len() == 1 => {
+                dispatch_triggers(L, EVT_ACTION, user, dst.as_slice(), args[0]);
+            }
+            conn::LineReceived(conn::Line{command: conn::IRCCTCP(ref cmd, ref dst), ref args,
+                                          prefix: Some(ref user)}) => {
+                reply_ctcp(L, user, cmd.as_slice(), dst.as_slice(),
+                           args.head().map(|a| a.as_slice()));
+            }
+            _ => ()
+        }
+
+        // nothing is left to resume the remaining waits
+        match *event {
+            conn::Disconnected => clear_waiters(L, None),
+            _ => ()
+        }
+        0
+    }
+
+    unsafe fn lua_dispatch_timer(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: timer id
+
+        let id = L.checkinteger(1);
+        L.settop(0); // clear the stack
+
+        push_timers(L);
+        L.rawgeti(1, id as i32);
+        if !L.istable(2) {
+            return 0; // the timer was cancelled
+        }
+        L.getfield(2, "waiter");
+        if L.isthread(-1) {
+            // an irc.wait timed out; resume it with no event
+            L.pushnil();
+            L.rawseti(1, id as i32);
+            push_waiters(L);
+            L.pushvalue(-2);
+            L.rawget(-2);
+            if !L.istable(-1) {
+                return 0; // no longer waiting
+            }
+            L.getfield(-1, "owner");
+            let owner = L.tointeger(-1) as uint;
+            L.pop(2);
+            L.pushvalue(-2);
+            L.pushnil();
+            L.rawset(-3);
+            let mut co = L.tothread(3).unwrap();
+            match resume_as(&mut co, owner, 0, "resuming handler") {
+                Some(true) => co.settop(0),
+                _ => ()
+            }
+            return 0;
+        }
+        L.pop(1);
+        L.getfield(2, "interval");
+        let interval = L.tointeger(-1);
+        L.pop(1);
+        if interval > 0 {
+            get_timers(L).reschedule(id as uint, interval as u64);
+        } else {
+            L.pushnil();
+            L.rawseti(1, id as i32);
+        }
+        L.getfield(2, "owner");
+        let owner = L.tointeger(-1) as uint;
+        L.pop(1);
+        L.getfield(2, "fn");
+        call_as(L, owner, 0, 0, "running timer");
         0
     }
 
@@ -192,180 +533,1566 @@ lua_extern_pub! {
 
         L.pushstring(EVT_RELOADED);
 
-        dispatch_event_inner(L);
+        dispatch_event_inner(L, None, None);
+        0
+    }
+
+    unsafe fn lua_dispatch_shutdown(L: &mut lua::ExternState) -> i32 {
+        // 0 args
+
+        L.settop(0); // clear the stack
+
+        L.pushstring(EVT_SHUTDOWN);
+
+        dispatch_event_inner(L, None, None);
+        0
+    }
+
+    unsafe fn lua_dispatch_loaded(L: &mut lua::ExternState) -> i32 {
+        // 2 args: owner, serialized state (optional)
+
+        let owner = L.checkinteger(1) as uint;
+        let state = L.tobytes(2).map(|s| s.to_owned());
+        L.settop(0); // clear the stack
+
+        L.pushstring(EVT_LOADED);
+        match state {
+            None => L.pushnil(),
+            Some(state) => {
+                match store::deserialize_value(L, state) {
+                    Ok(()) => (),
+                    Err(e) => {
+                        let alloc = alloc::get(L);
+                        println!("Error in plugin {} restoring state: {}", alloc.name(owner), e);
+                        L.pushnil();
+                    }
+                }
+            }
+        }
+
+        dispatch_event_inner(L, None, Some(owner));
+        0
+    }
+
+    unsafe fn lua_dispatch_unloading(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: owner
+        // returns the serialized state to hand over, if any
+
+        let owner = L.checkinteger(1) as uint;
+        L.settop(0); // clear the stack
+
+        // the plugin's pending waits won't be resumed by its successor
+        clear_waiters(L, Some(owner));
+
+        L.pushstring(EVT_UNLOADING);
+        let len = push_matching_handlers(L);
+        let list = L.gettop();
+        for i in range_inclusive(1, len) {
+            L.rawgeti(list, i); // handler record
+            L.getfield(-1, "owner");
+            let matches = L.tointeger(-1) as uint == owner;
+            L.pop(1);
+            if !matches {
+                L.pop(1);
+                continue;
+            }
+            L.getfield(-1, "rich");
+            let rich = L.toboolean(-1);
+            L.pop(1);
+            L.getfield(-1, "fn");
+            L.remove(-2); // pop the record, leaving the function
+            if rich {
+                push_event_object(L, 1, None);
+            } else {
+                L.pushvalue(1);
+            }
+            if !call_as(L, owner, 1, 1, "unloading") {
+                continue;
+            }
+            if L.istable(-1) {
+                match store::serialize_value(L, -1) {
+                    Ok(state) => {
+                        L.pushbytes(state);
+                        return 1;
+                    }
+                    Err(e) => {
+                        let alloc = alloc::get(L);
+                        println!("Error in plugin {} saving state: {}", alloc.name(owner), e);
+                    }
+                }
+            }
+            L.pop(1);
+        }
         0
     }
 }
 
-unsafe fn dispatch_event_inner(L: &mut lua::ExternState) {
+// raw is the raw line for the event, if it came from the server
+// if only is given, the event is only delivered to that allocator owner
+// returns true if a handler consumed the event by returning irc.HANDLED
+unsafe fn dispatch_event_inner(L: &mut lua::ExternState, raw: Option<&[u8]>,
+                               only: Option<uint>) -> bool {
     // our event arguments are all on the stack
     let nargs = L.gettop();
+    // coroutines suspended in irc.wait get the first look
+    resume_waiters(L, nargs, only);
+
     // get the handler list and call each one with a copy of the arguments
-    L.pushlightuserdata(lua_addhandler as *mut libc::c_void);
-    L.gettable(lua::REGISTRYINDEX);
-    if !L.istable(-1) {
-        return; // no handlers
-    }
-    L.pushvalue(1); // event name
-    L.gettable(-2);
-    if !L.istable(-1) {
-        return; // no handlers
+    // the list is a snapshot, as handlers may add or remove handlers while we dispatch
+    let len = push_matching_handlers(L);
+    let list = L.gettop();
+    for i in range_inclusive(1, len) {
+        L.rawgeti(list, i); // handler record
+        L.getfield(-1, "id");
+        let id = L.tointeger(-1);
+        L.pop(1);
+        if !handler_registered(L, id) {
+            // an earlier handler removed this one
+            L.pop(1);
+            continue;
+        }
+        // run the handler on behalf of the plugin that registered it
+        L.getfield(-1, "owner");
+        let owner = L.tointeger(-1) as uint;
+        L.pop(1);
+        if only.map_or(false, |o| o != owner) {
+            L.pop(1);
+            continue;
+        }
+        L.getfield(-1, "once");
+        if L.toboolean(-1) {
+            remove_handler(L, id);
+        }
+        L.pop(1);
+        L.getfield(-1, "rich");
+        let rich = L.toboolean(-1);
+        L.pop(1);
+        L.getfield(-1, "fn");
+        L.remove(-2); // pop the record, leaving the function
+        let nfnargs = if rich {
+            // a single event object
+            push_event_object(L, nargs, raw);
+            1
+        } else {
+            push_event_args(L, nargs);
+            nargs
+        };
+        let mut handled = false;
+        if call_as(L, owner, nfnargs, 1, "dispatching IRC event") {
+            handled = L.touserdata(-1) == handled_sentinel();
+            L.pop(1);
+        }
+        if handled {
+            // lower-priority handlers don't get to see this event
+            return true;
+        }
     }
-    L.pushnil(); // first key
-    while L.next(-2) {
-        // key is -2, value is -1
-        // copy all the arguments; deep-copy the sender table
-        for i in range_inclusive(1, nargs) {
-            if L.istable(i) {
-                // copy it
-                L.newtable();
+    false
+}
+
+// calls the function below the top nargs values on behalf of the given
+// allocator owner, reporting any error along with the owner's name
+// the function runs as a new coroutine so it may suspend itself with irc.wait
+// on success, leaves nresults values on the stack and returns true; if the
+// function suspended itself, those values are all nil
+unsafe fn call_as(L: &mut lua::ExternState, owner: uint, nargs: i32, nresults: i32,
+                  what: &str) -> bool {
+    let mut co = L.newthread();
+    L.insert(-(nargs + 2)); // move the thread below the function
+    L.xmove(&mut co, nargs + 1);
+    let thread = L.gettop();
+    let ok = match resume_as(&mut co, owner, nargs, what) {
+        None => false,
+        Some(true) => {
+            co.settop(nresults);
+            co.xmove(L, nresults);
+            true
+        }
+        Some(false) => {
+            // the waiter record keeps the coroutine alive
+            co.settop(0);
+            for _ in range(0, nresults) {
                 L.pushnil();
-                while L.next(i) {
-                    L.pushvalue(-2); // copy the key
-                    L.insert(-2); // move it behind the value
-                    L.settable(-4); // set key=value in the new table
-                    // leave behind the key for next
-                }
+            }
+            true
+        }
+    };
+    L.remove(thread);
+    ok
+}
+
+// resumes the coroutine with the top nargs values of its stack on behalf of
+// the given allocator owner, reporting any error along with the owner's name
+// returns Some(true) if it finished, Some(false) if it suspended itself
+// through irc.wait or a query, and None if it raised an error or yielded
+// any other way
+unsafe fn resume_as(co: &mut lua::ExternState, owner: uint, nargs: i32,
+                    what: &str) -> Option<bool> {
+    let alloc = alloc::get(co);
+    let prev = alloc.set_current(owner);
+    let res = match co.resume(nargs) {
+        Ok(false) => Some(true),
+        Ok(true) => {
+            if take_suspending(co) {
+                Some(false)
             } else {
-                L.pushvalue(i);
+                // nothing would ever resume it
+                println!("Error in plugin {} {}: attempt to yield outside of irc.wait",
+                         alloc.name(owner), what);
+                None
             }
         }
-        match L.pcall(nargs, 0, 0) {
-            Ok(()) => (),
-            Err(e) => {
-                println!("Error dispatching IRC event: {}: {}", e, L.describe(-1));
-                L.pop(1);
+        Err(e) => {
+            match alloc.take_denied() {
+                Some((denied, limit)) => {
+                    println!("Error in plugin {} {}: {} exceeded {}", alloc.name(owner), what,
+                             alloc.name(denied), limit);
+                }
+                None => {
+                    println!("Error in plugin {} {}: {}: {}", alloc.name(owner), what, e,
+                             co.describe(-1));
+                }
             }
+            None
         }
-    }
+    };
+    alloc.set_current(prev);
+    res
 }
 
-unsafe fn push_user(L: &mut lua::ExternState, user: &irc::User) {
-    L.createtable(0, 4);
-    L.pushbytes(user.raw());
-    L.setfield(-2, "raw");
-    L.pushbytes(user.nick());
-    L.setfield(-2, "nick");
-    match user.user() {
-        None => L.pushnil(),
-        Some(v) => L.pushbytes(v)
+// runs the bot command invoked by a PRIVMSG, if any
+unsafe fn dispatch_command(L: &mut lua::ExternState, user: &irc::User, dst: &[u8], text: &[u8]) {
+    let conn = getconn(L);
+    let private = !is_channel(dst);
+    let prefix = {
+        let prefixes = get_prefixes(L);
+        if private { prefixes.default_prefix() } else { prefixes.prefix_for(dst) }
+    };
+    let bare = private && get_prefixes(L).bare_private();
+    let inv = match command::parse(text, prefix.as_bytes(), conn.me().nick(), bare) {
+        None => return,
+        Some(inv) => inv
+    };
+    // replies go to the channel, or to the sender of a private message
+    let reply_to = if private { user.nick().to_owned() } else { dst.to_owned() };
+
+    let top = L.gettop();
+    push_commands(L);
+    L.pushbytes(inv.name);
+    L.rawget(-2);
+    if !L.istable(-1) {
+        if inv.name.as_slice() == bytes!("help") {
+            let msg = command_help(L, top + 1, prefix, inv.text);
+            conn.privmsg(reply_to.as_slice(), msg.as_bytes());
+        }
+        L.settop(top);
+        return;
     }
-    L.setfield(-2, "user");
-    match user.user() {
-        None => L.pushnil(),
-        Some(v) => L.pushbytes(v)
+    let rec = L.gettop();
+
+    // commands restricted to a role may need to look up the sender's account first
+    let chan = if private { None } else { Some(dst) };
+    let verify = match check_role(L, rec, user.raw(), chan) {
+        acl::Granted => false,
+        acl::Unverified => true,
+        acl::Denied => {
+            let msg = denied(L, rec);
+            conn.privmsg(reply_to.as_slice(), msg.as_bytes());
+            L.settop(top);
+            return;
+        }
+    };
+
+    let args = match command::split_args(inv.text) {
+        Ok(args) => args,
+        Err(e) => {
+            conn.privmsg(reply_to.as_slice(), format!("Error: {}", e).as_bytes());
+            L.settop(top);
+            return;
+        }
+    };
+    L.getfield(rec, "min_args");
+    let min_args = L.tointeger(-1) as uint;
+    L.pop(1);
+    if args.len() < min_args {
+        let msg = usage(L, rec, prefix);
+        conn.privmsg(reply_to.as_slice(), msg.as_bytes());
+        L.settop(top);
+        return;
     }
-    L.setfield(-2, "host");
-}
 
-// unsafe because the Conn isn't really 'static
-unsafe fn getconn(L: &mut lua::ExternState) -> &'static mut Conn<'static> {
-    L.pushlightuserdata(lua_require as *mut libc::c_void);
-    L.gettable(lua::REGISTRYINDEX);
-    let ptr = L.touserdata(-1) as *mut *mut Conn<'static>;
-    if ptr.is_null() {
-        L.errorstr("could not retrieve connection information");
+    L.getfield(rec, "owner");
+    let owner = L.tointeger(-1) as uint;
+    L.pop(1);
+    L.getfield(rec, "fn");
+
+    // the event object, with the same reply methods as other events
+    L.createtable(0, 8);
+    L.getfield(rec, "name");
+    L.setfield(-2, "command");
+    push_user(L, user);
+    L.setfield(-2, "sender");
+    L.pushbytes(dst);
+    L.setfield(-2, "target");
+    L.pushboolean(private);
+    L.setfield(-2, "is_private");
+    L.pushstring(prefix);
+    L.setfield(-2, "prefix");
+    L.pushbytes(inv.text);
+    L.setfield(-2, "text");
+    L.createtable(args.len() as i32, 0);
+    for (i, arg) in args.iter().enumerate() {
+        L.pushbytes(*arg);
+        L.rawseti(-2, i as i32 + 1);
     }
-    let ptr = *ptr;
-    if ptr.is_null() {
-        L.errorstr("no active connection");
+    L.setfield(-2, "args");
+    L.getfield(lua::REGISTRYINDEX, EVENT_MT);
+    L.setmetatable(-2);
+
+    if verify {
+        // run the command once WHOIS has told us the sender's account
+        L.remove(-2); // the function, it's in the record
+        L.pushvalue(rec);
+        L.insert(-2);
+        L.getfield(-1, "args");
+        L.pushcclosure(lua_verified_command, 3);
+        let callback = L.ref_(lua::REGISTRYINDEX);
+        get_queries(L).start(query::Whois, user.nick(), callback, owner);
+        let mut line = bytes!("WHOIS ").to_owned();
+        line.push_all(user.nick());
+        conn.send_raw(line);
+        L.settop(top);
+        return;
     }
-    &mut *ptr
-}
 
-pub fn activate_conn(L: &mut lua::State, conn: &mut Conn) {
-    L.pushlightuserdata(lua_require as *mut libc::c_void);
-    L.gettable(lua::REGISTRYINDEX);
-    let ptr = L.touserdata(-1) as *mut *mut Conn;
-    if ptr.is_null() {
-        L.errorstr("could not retrieve connection information");
+    for arg in args.iter() {
+        L.pushbytes(*arg);
     }
-    unsafe { *ptr = conn as *mut Conn };
+    call_as(L, owner, args.len() as i32 + 1, 0, "running command");
+    L.settop(top);
 }
 
-pub fn deactivate_conn(L: &mut lua::State) {
-    L.pushlightuserdata(lua_require as *mut libc::c_void);
-    L.gettable(lua::REGISTRYINDEX);
-    let ptr = L.touserdata(-1) as *mut *mut Conn;
-    if ptr.is_null() {
-        L.errorstr("could not retrieve connection information");
+// checks whether the sender has the role required by the command record at
+// the given index, if any
+unsafe fn check_role(L: &mut lua::ExternState, rec: i32, mask: &[u8],
+                     chan: Option<&[u8]>) -> acl::Check {
+    L.getfield(rec, "role");
+    let role = L.tostring(-1).and_then(acl::Role::from_name);
+    L.pop(1);
+    match role {
+        None => acl::Granted,
+        Some(role) => get_acl(L).check(mask, role, chan)
     }
-    unsafe { *ptr = ptr::mut_null() };
 }
 
-lua_extern! {
-    unsafe fn lua_addhandler(L: &mut lua::ExternState) -> i32 {
-        // 2 args: event, func
-
-        L.checkbytes(1);
-        L.checktype(2, lua::Type::Function);
-
-        L.settop(2); // throw away any extra values
+// returns the reply to a sender who lacks the role for the command record at
+// the given index
+unsafe fn denied(L: &mut lua::ExternState, rec: i32) -> ~str {
+    L.getfield(rec, "name");
+    L.getfield(rec, "role");
+    let msg = format!("Permission denied: {} requires the {} role",
+                      L.tostring(-2).unwrap_or(""), L.tostring(-1).unwrap_or(""));
+    L.pop(2);
+    msg
+}
 
-        // get or create handler table; key is lua_addhandler
-        L.pushlightuserdata(lua_addhandler as *mut libc::c_void);
-        L.gettable(lua::REGISTRYINDEX);
-        if !L.istable(3) {
+// answers a CTCP query with the reply registered by a plugin, if any, or else
+// the built-in reply
+unsafe fn reply_ctcp(L: &mut lua::ExternState, user: &irc::User, cmd: &[u8], dst: &[u8],
+                     text: Option<&[u8]>) {
+    let cmd = str::from_utf8_lossy(cmd).into_owned().to_ascii_upper();
+    let top = L.gettop();
+    push_ctcp_replies(L);
+    let replies = L.gettop();
+    L.getfield(replies, cmd.as_slice());
+    let reply = if L.istable(-1) {
+        L.getfield(-1, "reply");
+        if L.isfunction(-1) {
+            // the function decides, on behalf of the plugin that registered it
+            L.getfield(-2, "owner");
+            let owner = L.tointeger(-1) as uint;
             L.pop(1);
-            L.newtable();
-            L.pushlightuserdata(lua_addhandler as *mut libc::c_void);
-            L.pushvalue(3);
-            L.settable(lua::REGISTRYINDEX);
+            push_user(L, user);
+            L.pushbytes(dst);
+            match text {
+                None => L.pushnil(),
+                Some(t) => L.pushbytes(t)
+            }
+            if call_as(L, owner, 3, 1, "replying to CTCP") && L.isstring(-1) {
+                Some(L.tobytes(-1).unwrap().to_owned())
+            } else {
+                None
+            }
+        } else if L.isstring(-1) {
+            Some(L.tobytes(-1).unwrap().to_owned())
+        } else {
+            None // disabled
         }
-        // table is stack entry 3
-
-        // get or create the array
-        L.pushvalue(1); // copy the event to the top
-        L.gettable(3);
-        if !L.istable(4) {
-            L.pop(1);
-            L.newtable();
-            L.pushvalue(1); // copy event to top
-            L.pushvalue(4);
-            L.settable(3);
+    } else {
+        let supported = supported_ctcp(L, replies);
+        ctcp::builtin_reply(get_ctcp_config(L), cmd.as_slice(), text, supported.as_slice())
+    };
+    L.settop(top);
+    match reply {
+        None => (),
+        Some(reply) => {
+            let msg = ctcp::frame(cmd.as_slice(), reply.as_slice());
+            getconn(L).notice(user.nick(), msg.as_slice());
         }
-        // array is stack entry 4
+    }
+}
 
-        let len = L.objlen(4); // get table length
-        L.pushinteger(len as int + 1);
-        L.pushvalue(2); // copy function to top
-        L.settable(4); // set ary[len+1]=func
-        // and return
-        0
+// returns the CTCP types the bot replies to, for CLIENTINFO, given the index
+// of the table of replies registered by plugins
+unsafe fn supported_ctcp(L: &mut lua::ExternState, replies: i32) -> ~[~str] {
+    let conf = get_ctcp_config(L);
+    let mut types = ~[~"ACTION"];
+    for &cmd in ctcp::BUILTINS.iter() {
+        if ctcp::is_enabled(conf, cmd) {
+            types.push(cmd.to_owned());
+        }
+    }
+    L.pushnil(); // first key
+    while L.next(replies) {
+        // key is -2, value is -1
+        let cmd = L.tostring(-2).unwrap_or("").to_owned();
+        L.getfield(-1, "reply");
+        if L.toboolean(-1) {
+            if !types.contains(&cmd) {
+                types.push(cmd);
+            }
+        } else {
+            types.retain(|t| *t != cmd);
+        }
+        L.pop(2); // pop the reply and value, leave the key for next
     }
+    types.sort();
+    types
+}
 
-// *** IRC package functions ***
+// runs the triggers whose regex matches the text of a PRIVMSG or ACTION
+// each regex is matched once, however many triggers use it
+unsafe fn dispatch_triggers(L: &mut lua::ExternState, cmd: &str, user: &irc::User, dst: &[u8],
+                            text: &[u8]) {
+    let top = L.gettop();
+    L.pushlightuserdata(lua_addhandler as *mut libc::c_void);
+    L.gettable(lua::REGISTRYINDEX);
+    if !L.istable(-1) {
+        L.settop(top);
+        return;
+    }
+    L.getfield(-1, EVT_MATCH);
+    if !L.istable(-1) {
+        L.settop(top);
+        return;
+    }
+    // copy the array, triggers may be added or removed as we go
+    let len = L.objlen(-1) as i32;
+    L.createtable(len, 0);
+    for i in range_inclusive(1, len) {
+        L.rawgeti(-2, i);
+        L.rawseti(-2, i);
+    }
+    let triggers = L.gettop();
 
-    unsafe fn lua_host(L: &mut lua::ExternState) -> i32 {
-        // 0 args
+    let text = str::from_utf8_lossy(text).into_owned();
+    let private = !is_channel(dst);
+    // the matches of each regex, by cache id, as the positions of their groups
+    let mut matches: ~[(uint, ~[~[Option<(uint, uint)>]])] = ~[];
+    for i in range_inclusive(1, len) {
+        L.rawgeti(triggers, i);
+        let rec = L.gettop();
+        L.getfield(rec, "id");
+        let id = L.tointeger(-1);
+        L.getfield(rec, "regex");
+        if !handler_registered(L, id) || L.isnil(-1) {
+            L.settop(triggers);
+            continue;
+        }
+        let re = pattern::regex_id(L, -1);
+        if !matches.iter().any(|&(r, _)| r == re) {
+            let regex = get_regexes(L).get(re);
+            let found = regex.captures_iter(text.as_slice()).map(|caps| {
+                range(0, caps.len()).map(|g| caps.pos(g)).collect()
+            }).collect();
+            matches.push((re, found));
+        }
+        let &(_, ref found) = matches.iter().find(|&&(r, _)| r == re).unwrap();
+        L.getfield(rec, "owner");
+        let owner = L.tointeger(-1) as uint;
+        L.settop(rec);
 
-        let conn = getconn(L);
+        for groups in found.iter() {
+            let group = |g: uint| groups[g].map(|(s, e)| text.slice(s, e));
+            L.getfield(rec, "fn");
 
-        // construct our response on the stack
-        let mut buf = [0u8, ..64];
-        let n = {
-            let mut w = BufWriter::new(buf);
-            match write!(&mut w, "{}", conn.host()).and_then(|_| w.tell()) {
-                Ok(n) => n,
-                Err(e) => {
-                    drop(e);
-                    L.errorstr("could not format host");
+            // the event object, with the same reply methods as other events
+            L.createtable(0, 7);
+            L.pushstring(cmd);
+            L.setfield(-2, "command");
+            push_user(L, user);
+            L.setfield(-2, "sender");
+            L.pushbytes(dst);
+            L.setfield(-2, "target");
+            L.pushboolean(private);
+            L.setfield(-2, "is_private");
+            L.pushstring(text.as_slice());
+            L.setfield(-2, "text");
+            L.pushstring(group(0).unwrap());
+            L.setfield(-2, "match");
+            L.createtable(groups.len() as i32 - 1, 0);
+            for g in range(1, groups.len()) {
+                match group(g) {
+                    None => L.pushboolean(false),
+                    Some(s) => L.pushstring(s)
                 }
+                L.rawseti(-2, g as i32);
             }
-        };
-        L.pushbytes(buf.slice_to(n as uint));
-        1
-    }
+            L.setfield(-2, "captures");
+            L.getfield(lua::REGISTRYINDEX, EVENT_MT);
+            L.setmetatable(-2);
 
-    unsafe fn lua_me(L: &mut lua::ExternState) -> i32 {
-        // 0 args
+            // followed by the captures, as regex.match would return them
+            let nargs = if groups.len() <= 1 {
+                L.pushstring(group(0).unwrap());
+                1
+            } else {
+                for g in range(1, groups.len()) {
+                    match group(g) {
+                        None => L.pushboolean(false),
+                        Some(s) => L.pushstring(s)
+                    }
+                }
+                groups.len() as i32 - 1
+            };
+            call_as(L, owner, nargs + 1, 0, "running trigger");
+            L.settop(rec);
+        }
+        L.settop(triggers);
+    }
+    L.settop(top);
+}
 
-        let conn = getconn(L);
+// returns the usage message for the command record at the given index
+unsafe fn usage(L: &mut lua::ExternState, rec: i32, prefix: &str) -> ~str {
+    L.getfield(rec, "name");
+    L.getfield(rec, "usage");
+    let msg = match L.tostring(-1) {
+        None => format!("Usage: {}{}", prefix, L.tostring(-2).unwrap_or("")),
+        Some(usage) => format!("Usage: {}{} {}", prefix, L.tostring(-2).unwrap_or(""), usage)
+    };
+    L.pop(2);
+    msg
+}
 
-        // return a user table
-        push_user(L, conn.me());
-        1
-    }
+// returns the reply to the built-in help command, given the command table at
+// index cmds and the text following the command
+unsafe fn command_help(L: &mut lua::ExternState, cmds: i32, prefix: &str, text: &[u8]) -> ~str {
+    let word = match text.iter().position(|&b| b == ' ' as u8) {
+        None => text,
+        Some(idx) => text.slice_to(idx)
+    };
+    let word = if word.starts_with(prefix.as_bytes()) {
+        word.slice_from(prefix.len())
+    } else {
+        word
+    };
+
+    if word.is_empty() {
+        // list each command once, under its own name rather than its aliases
+        let mut names = ~[];
+        L.pushnil(); // first key
+        while L.next(cmds) {
+            // key is -2, value is -1
+            L.getfield(-1, "name");
+            if L.rawequal(-1, -3) {
+                names.push(L.tostring(-1).unwrap_or("").to_owned());
+            }
+            L.pop(2); // pop the name and value, leave the key for next
+        }
+        if names.is_empty() {
+            return ~"No commands are available";
+        }
+        names.sort();
+        return format!("Commands: {}. Use {}help <command> for details.",
+                       names.connect(", "), prefix);
+    }
+
+    L.pushbytes(command::lower(word));
+    L.rawget(cmds);
+    if !L.istable(-1) {
+        L.pop(1);
+        return format!("No such command: {}", str::from_utf8_lossy(word));
+    }
+    let rec = L.gettop();
+    let mut msg = usage(L, rec, prefix);
+    L.getfield(rec, "help");
+    match L.tostring(-1) {
+        None => (),
+        Some(help) => {
+            msg.push_str(" - ");
+            msg.push_str(help);
+        }
+    }
+    L.pop(1);
+    L.getfield(rec, "aliases");
+    if L.objlen(-1) > 0 {
+        let mut aliases = ~[];
+        for i in range_inclusive(1, L.objlen(-1) as i32) {
+            L.rawgeti(-1, i);
+            aliases.push(L.tostring(-1).unwrap_or("").to_owned());
+            L.pop(1);
+        }
+        msg.push_str(format!(" (aliases: {})", aliases.connect(", ")).as_slice());
+    }
+    L.pop(2);
+    msg
+}
+
+// pushes the table that maps command names and aliases to their records
+unsafe fn push_commands(L: &mut lua::ExternState) {
+    L.getfield(lua::REGISTRYINDEX, COMMANDS);
+    if !L.istable(-1) {
+        L.pop(1);
+        L.newtable();
+        L.pushvalue(-1);
+        L.setfield(lua::REGISTRYINDEX, COMMANDS);
+    }
+}
+
+// pushes the table that maps uppercased CTCP types to the replies registered
+// by plugins
+unsafe fn push_ctcp_replies(L: &mut lua::ExternState) {
+    L.getfield(lua::REGISTRYINDEX, CTCP_REPLIES);
+    if !L.istable(-1) {
+        L.pop(1);
+        L.newtable();
+        L.pushvalue(-1);
+        L.setfield(lua::REGISTRYINDEX, CTCP_REPLIES);
+    }
+}
+
+// pushes the table that maps suspended coroutines to their waiter records
+unsafe fn push_waiters(L: &mut lua::ExternState) {
+    L.getfield(lua::REGISTRYINDEX, WAITERS);
+    if !L.istable(-1) {
+        L.pop(1);
+        L.newtable();
+        L.pushvalue(-1);
+        L.setfield(lua::REGISTRYINDEX, WAITERS);
+    }
+}
+
+// records that the running coroutine is about to suspend itself through
+// irc.wait or a query
+unsafe fn mark_suspending(L: &mut lua::ExternState) {
+    L.pushthread();
+    L.setfield(lua::REGISTRYINDEX, SUSPENDING);
+}
+
+// returns true if the suspended coroutine was marked by mark_suspending,
+// clearing the mark
+unsafe fn take_suspending(co: &mut lua::ExternState) -> bool {
+    co.getfield(lua::REGISTRYINDEX, SUSPENDING);
+    co.pushthread();
+    let marked = co.rawequal(-1, -2);
+    co.pop(2);
+    co.pushnil();
+    co.setfield(lua::REGISTRYINDEX, SUSPENDING);
+    marked
+}
+
+// abandons the suspended coroutines waiting in irc.wait, only considering
+// those of the given allocator owner if any, and cancels their timeouts
+unsafe fn clear_waiters(L: &mut lua::ExternState, only: Option<uint>) {
+    push_waiters(L);
+    let waiters = L.gettop();
+    L.pushnil(); // first key
+    while L.next(waiters) {
+        // key is -2, value is -1
+        L.getfield(-1, "owner");
+        let owner = L.tointeger(-1) as uint;
+        L.pop(1);
+        if only.map_or(false, |o| o != owner) {
+            L.pop(1);
+            continue;
+        }
+        L.getfield(-1, "timer");
+        if L.isnumber(-1) {
+            let id = L.tointeger(-1);
+            push_timers(L);
+            L.pushnil();
+            L.rawseti(-2, id as i32);
+            L.pop(1);
+            get_timers(L).cancel(id as uint);
+        }
+        L.pop(2); // pop the timer and the record
+        // clearing an existing field doesn't disturb the traversal
+        L.pushvalue(-1);
+        L.pushnil();
+        L.rawset(waiters);
+    }
+    L.pop(1);
+}
+
+// resumes the coroutines waiting on the event whose arguments are at indices
+// 1 through nargs, only considering those of the given allocator owner if any
+unsafe fn resume_waiters(L: &mut lua::ExternState, nargs: i32, only: Option<uint>) {
+    let event = L.tobytes(1).unwrap_or(&[]).to_owned();
+    push_waiters(L);
+    let waiters = L.gettop();
+
+    // snapshot the matching records first, as resuming may add new waiters
+    L.newtable();
+    let matched = L.gettop();
+    let mut n = 0i32;
+    L.pushnil(); // first key
+    while L.next(waiters) {
+        // key is -2, value is -1
+        L.getfield(-1, "event");
+        let matches = match L.tobytes(-1) {
+            Some(pat) => pat == event.as_slice() || pattern_matches(pat, event),
+            None => false
+        };
+        L.pop(1);
+        if matches {
+            n += 1;
+            L.rawseti(matched, n);
+        } else {
+            L.pop(1);
+        }
+    }
+
+    for i in range_inclusive(1, n) {
+        L.rawgeti(matched, i);
+        let rec = L.gettop();
+        L.getfield(rec, "thread");
+        let thread = L.gettop();
+        // skip it if it was resumed since the snapshot
+        L.pushvalue(thread);
+        L.rawget(waiters);
+        let current = L.rawequal(-1, rec);
+        L.pop(1);
+        if !current {
+            L.settop(rec - 1);
+            continue;
+        }
+        L.getfield(rec, "owner");
+        let owner = L.tointeger(-1) as uint;
+        L.pop(1);
+        if only.map_or(false, |o| o != owner) {
+            L.settop(rec - 1);
+            continue;
+        }
+
+        L.getfield(rec, "filter");
+        if L.isfunction(-1) {
+            push_event_args(L, nargs);
+            let pass = call_filter(L, owner, nargs);
+            if !pass {
+                L.settop(rec - 1);
+                continue;
+            }
+        } else {
+            L.pop(1);
+        }
+
+        // it's a match; stop waiting and hand the event to the coroutine
+        L.pushvalue(thread);
+        L.pushnil();
+        L.rawset(waiters);
+        L.getfield(rec, "timer");
+        if L.isnumber(-1) {
+            let id = L.tointeger(-1);
+            push_timers(L);
+            L.pushnil();
+            L.rawseti(-2, id as i32);
+            L.pop(1);
+            get_timers(L).cancel(id as uint);
+        }
+        L.pop(1);
+
+        let mut co = L.tothread(thread).unwrap();
+        push_event_args(L, nargs);
+        L.xmove(&mut co, nargs);
+        match resume_as(&mut co, owner, nargs, "resuming handler") {
+            Some(true) => co.settop(0),
+            _ => ()
+        }
+        L.settop(rec - 1);
+    }
+    L.settop(waiters - 1);
+}
+
+// calls the waiter filter below the top nargs values with the allocator
+// owner of the waiting plugin, returning whether it accepted the event
+// the filter and arguments are popped
+unsafe fn call_filter(L: &mut lua::ExternState, owner: uint, nargs: i32) -> bool {
+    let alloc = alloc::get(L);
+    let prev = alloc.set_current(owner);
+    let pass = match L.pcall(nargs, 1, 0) {
+        Ok(()) => L.toboolean(-1),
+        Err(e) => {
+            println!("Error in plugin {} filtering event for irc.wait: {}: {}",
+                     alloc.name(owner), e, L.describe(-1));
+            false
+        }
+    };
+    L.pop(1);
+    alloc.set_current(prev);
+    pass
+}
+
+// pushes copies of the event arguments at indices 1 through nargs,
+// deep-copying the sender table
+unsafe fn push_event_args(L: &mut lua::ExternState, nargs: i32) {
+    for i in range_inclusive(1, nargs) {
+        if L.istable(i) {
+            copy_table(L, i);
+        } else {
+            L.pushvalue(i);
+        }
+    }
+}
+
+// delivers a finished query to its callback or suspended coroutine
+unsafe fn deliver_query(L: &mut lua::ExternState, c: &query::Completed) {
+    L.rawgeti(lua::REGISTRYINDEX, c.callback);
+    L.unref(lua::REGISTRYINDEX, c.callback);
+    // the result on success, or nil and the error message
+    let nargs = match c.error {
+        None => {
+            query::push_reply(L, &c.reply);
+            1
+        }
+        Some(ref err) => {
+            L.pushnil();
+            L.pushbytes(*err);
+            2
+        }
+    };
+    if L.isthread(-(nargs + 1)) {
+        let mut co = L.tothread(-(nargs + 1)).unwrap();
+        L.xmove(&mut co, nargs);
+        match resume_as(&mut co, c.owner, nargs, "resuming query") {
+            Some(true) => co.settop(0),
+            _ => ()
+        }
+        L.pop(1);
+    } else {
+        call_as(L, c.owner, nargs, 0, "handling query reply");
+    }
+}
+
+// sends a query for the target at index 1 and registers the callback at
+// index 2 to receive the reply; without a callback, the calling coroutine is
+// suspended until the reply arrives
+unsafe fn start_query(L: &mut lua::ExternState, kind: query::Kind, cmd: &[u8],
+                      suffix: &[u8]) -> i32 {
+    let target = L.checkbytes(1);
+    let valid = !target.is_empty() &&
+                !target.iter().any(|&b| b == ' ' as u8 || b == '\r' as u8 || b == '\n' as u8);
+    L.argcheck(valid, 1, "invalid query target");
+    let conn = getconn(L);
+    let owner = alloc::get(L).current();
+
+    let suspend = L.isnoneornil(2);
+    if suspend {
+        if L.pushthread() {
+            L.errorstr("a callback is required outside of a handler or timer");
+        }
+    } else {
+        L.checktype(2, lua::Type::Function);
+        L.pushvalue(2);
+    }
+    let callback = L.ref_(lua::REGISTRYINDEX);
+    get_queries(L).start(kind, target, callback, owner);
+
+    let mut line = cmd.to_owned();
+    line.push(' ' as u8);
+    line.push_all(target);
+    line.push_all(suffix);
+    conn.send_raw(line);
+
+    if suspend {
+        L.settop(0);
+        mark_suspending(L);
+        L.yield_(0)
+    } else {
+        0
+    }
+}
+
+// pushes the table that maps timer ids to their records
+unsafe fn push_timers(L: &mut lua::ExternState) {
+    L.getfield(lua::REGISTRYINDEX, TIMERS);
+    if !L.istable(-1) {
+        L.pop(1);
+        L.newtable();
+        L.pushvalue(-1);
+        L.setfield(lua::REGISTRYINDEX, TIMERS);
+    }
+}
+
+// schedules the function at index 2 to run after the number of seconds at
+// index 1, repeating if requested
+unsafe fn add_timer(L: &mut lua::ExternState, repeat: bool) -> i32 {
+    let secs = L.checknumber(1);
+    L.checktype(2, lua::Type::Function);
+    L.argcheck(secs >= 0.0, 1, "delay must not be negative");
+    let delay = (secs * 1000.0) as u64;
+    L.argcheck(!repeat || delay > 0, 1, "interval must be positive");
+
+    let timers = get_timers(L);
+    let id = timers.schedule(delay);
+
+    push_timers(L);
+    L.createtable(0, 3);
+    L.pushvalue(2);
+    L.setfield(-2, "fn");
+    L.pushinteger(if repeat { delay as int } else { 0 });
+    L.setfield(-2, "interval");
+    // timers run on behalf of the plugin that created them
+    L.pushinteger(alloc::get(L).current() as int);
+    L.setfield(-2, "owner");
+    L.rawseti(-2, id as i32);
+    L.pop(1);
+
+    L.pushinteger(id as int);
+    1
+}
+
+// pushes the zero-padded string form of a numeric reply code, e.g. "001"
+unsafe fn push_code(L: &mut lua::ExternState, code: uint) {
+    // construct our string on the stack
+    let mut buf = [0u8, ..16];
+    let n = {
+        let mut w = BufWriter::new(buf);
+        match write!(&mut w, "{:03u}", code).and_then(|_| w.tell()) {
+            Ok(n) => n,
+            Err(e) => {
+                drop(e);
+                L.errorstr("could not format IRCCode");
+            }
+        }
+    };
+    L.pushbytes(buf.slice_to(n as uint));
+}
+
+// pushes a shallow copy of the table at the given index
+unsafe fn copy_table(L: &mut lua::ExternState, idx: i32) {
+    L.newtable();
+    L.pushnil();
+    while L.next(idx) {
+        L.pushvalue(-2); // copy the key
+        L.insert(-2); // move it behind the value
+        L.settable(-4); // set key=value in the new table
+        // leave behind the key for next
+    }
+}
+
+// pushes an event object built from the positional event arguments at
+// indices 1 through nargs
+unsafe fn push_event_object(L: &mut lua::ExternState, nargs: i32, raw: Option<&[u8]>) {
+    let evt = L.tobytes(1).unwrap_or(&[]).to_owned();
+    // LOADED is the only event whose arguments don't lead with a sender
+    let loaded = evt.as_slice() == EVT_LOADED.as_bytes();
+
+    L.createtable(0, 10);
+    L.pushvalue(1);
+    L.setfield(-2, "command");
+    if !loaded && nargs >= 2 && L.istable(2) {
+        copy_table(L, 2);
+    } else {
+        L.pushnil();
+    }
+    L.setfield(-2, "sender");
+    if loaded && nargs >= 2 {
+        L.pushvalue(2);
+        L.setfield(-2, "state");
+    }
+
+    // the positional arguments after the sender are the params, except for
+    // the CTCP events, which lead with the CTCP command name
+    let mut first = if loaded { 2 } else { 3 };
+    let ctcp = evt.as_slice() == EVT_CTCP.as_bytes() || evt.as_slice() == EVT_CTCPREPLY.as_bytes();
+    if ctcp {
+        if nargs >= first {
+            L.pushvalue(first);
+            L.setfield(-2, "ctcp");
+        }
+        first += 1;
+    }
+    L.createtable(cmp::max(nargs - first + 1, 0), 0);
+    for i in range_inclusive(first, nargs) {
+        L.pushvalue(i);
+        L.rawseti(-2, i - first + 1);
+    }
+    L.setfield(-2, "params");
+
+    // target and text only make sense for message-like events
+    let msglike = evt.as_slice() == bytes!("PRIVMSG") || evt.as_slice() == bytes!("NOTICE") ||
+                  evt.as_slice() == EVT_ACTION.as_bytes() || ctcp;
+    let mut private = false;
+    if msglike && nargs >= first {
+        L.pushvalue(first);
+        L.setfield(-2, "target");
+        private = match L.tobytes(first) {
+            Some(dst) => !is_channel(dst),
+            None => false
+        };
+        if nargs > first {
+            L.pushvalue(nargs);
+            L.setfield(-2, "text");
+        }
+    }
+    L.pushboolean(private);
+    L.setfield(-2, "is_private");
+
+    match parse_code(evt.as_slice()).and_then(numerics::name) {
+        None => L.pushnil(),
+        Some(name) => L.pushstring(name)
+    }
+    L.setfield(-2, "numeric");
+
+    // the connection doesn't parse message tags yet, so this is always empty
+    L.newtable();
+    L.setfield(-2, "tags");
+
+    match raw {
+        None => L.pushnil(),
+        Some(raw) => L.pushbytes(raw)
+    }
+    L.setfield(-2, "raw");
+
+    L.getfield(lua::REGISTRYINDEX, EVENT_MT);
+    L.setmetatable(-2);
+}
+
+// returns true if the given command name is usable
+fn valid_command(name: &[u8]) -> bool {
+    !name.is_empty() && name.iter().all(|&b| b > ' ' as u8)
+}
+
+/// Returns true if the given message target names a channel
+pub fn is_channel(dst: &[u8]) -> bool {
+    match dst.head() {
+        Some(&c) => c == '#' as u8 || c == '&' as u8 || c == '+' as u8 || c == '!' as u8,
+        None => false
+    }
+}
+
+// reconstructs the raw text of a line received from the server
+fn raw_line(line: &conn::Line) -> ~[u8] {
+    let conn::Line{ref command, ref args, ref prefix} = *line;
+    let mut raw = ~[];
+    match *prefix {
+        None => (),
+        Some(ref user) => {
+            raw.push(':' as u8);
+            raw.push_all(user.raw());
+            raw.push(' ' as u8);
+        }
+    }
+    let ctcp = match *command {
+        conn::IRCCode(code) => {
+            raw.push_all(format!("{:03u}", code).as_bytes());
+            None
+        }
+        conn::IRCCmd(ref cmd) => {
+            raw.push_all(cmd.as_bytes());
+            None
+        }
+        conn::IRCAction(ref dst) => {
+            raw.push_all(bytes!("PRIVMSG "));
+            raw.push_all(dst.as_slice());
+            Some(bytes!("ACTION").to_owned())
+        }
+        conn::IRCCTCP(ref cmd, ref dst) => {
+            raw.push_all(bytes!("PRIVMSG "));
+            raw.push_all(dst.as_slice());
+            Some(cmd.clone())
+        }
+        conn::IRCCTCPReply(ref cmd, ref dst) => {
+            raw.push_all(bytes!("NOTICE "));
+            raw.push_all(dst.as_slice());
+            Some(cmd.clone())
+        }
+    };
+    match ctcp {
+        Some(cmd) => {
+            raw.push_all(bytes!(" :\x01"));
+            raw.push_all(cmd);
+            for arg in args.iter() {
+                raw.push(' ' as u8);
+                raw.push_all(*arg);
+            }
+            raw.push(1u8);
+        }
+        None => {
+            for (i, arg) in args.iter().enumerate() {
+                raw.push(' ' as u8);
+                if i == args.len() - 1 && (arg.is_empty() || arg.contains(&(' ' as u8)) ||
+                                           arg[0] == ':' as u8) {
+                    raw.push(':' as u8);
+                }
+                raw.push_all(*arg);
+            }
+        }
+    }
+    raw
+}
+
+// sends a reply to the event object at index 1 using the given function
+unsafe fn event_reply(L: &mut lua::ExternState, f: |&mut Conn, &[u8], &[u8]|) {
+    L.checktype(1, lua::Type::Table);
+    let msg = L.checkbytes(2);
+
+    // answer in private to the sender, otherwise to the channel
+    L.getfield(1, "is_private");
+    if L.toboolean(-1) {
+        L.getfield(1, "sender");
+        if !L.istable(-1) {
+            L.errorstr("event has no sender to reply to");
+        }
+        L.getfield(-1, "nick");
+    } else {
+        L.getfield(1, "target");
+    }
+    let dst = match L.tobytes(-1) {
+        None => L.errorstr("event has no target to reply to"),
+        Some(dst) => dst
+    };
+
+    let conn = getconn(L);
+    f(conn, dst, msg);
+}
+
+// returns true if a handler or a coroutine suspended in irc.wait may want the
+// event named at index 1
+unsafe fn has_listeners(L: &mut lua::ExternState) -> bool {
+    let n = push_matching_handlers(L);
+    L.pop(1);
+    if n > 0 {
+        return true;
+    }
+    // waits are matched against the event's arguments, so any will do
+    push_waiters(L);
+    L.pushnil(); // first key
+    let waiting = L.next(-2);
+    L.pop(if waiting { 3 } else { 1 });
+    waiting
+}
+
+// pushes a new array of the handler records that apply to the event named at
+// index 1, including wildcard handlers, ordered by priority
+// returns the number of records
+unsafe fn push_matching_handlers(L: &mut lua::ExternState) -> i32 {
+    let event = L.tobytes(1).unwrap_or(&[]).to_owned();
+    L.newtable();
+    let all = L.gettop();
+    let mut n = 0i32;
+    L.pushlightuserdata(lua_addhandler as *mut libc::c_void);
+    L.gettable(lua::REGISTRYINDEX);
+    if L.istable(-1) {
+        let handlers = L.gettop();
+        L.pushnil(); // first key
+        while L.next(handlers) {
+            // key is -2, value is -1
+            let matches = match L.type_(-2) {
+                Some(lua::Type::String) => {
+                    let key = L.tobytes(-2).unwrap();
+                    key == event.as_slice() || pattern_matches(key, event)
+                }
+                _ => false
+            };
+            if matches && L.istable(-1) {
+                let len = L.objlen(-1) as i32;
+                for i in range_inclusive(1, len) {
+                    L.rawgeti(-1, i);
+                    n += 1;
+                    L.rawseti(all, n);
+                }
+            }
+            L.pop(1); // pop the value, leave the key for next
+        }
+    }
+    L.pop(1); // pop the handler table
+
+    // order by descending priority, then by registration order
+    let mut order = ~[];
+    for i in range_inclusive(1, n) {
+        L.rawgeti(all, i);
+        L.getfield(-1, "priority");
+        L.getfield(-2, "id");
+        order.push((-L.tointeger(-2), L.tointeger(-1), i));
+        L.pop(3);
+    }
+    order.sort();
+    L.createtable(n, 0);
+    for (j, &(_, _, i)) in order.iter().enumerate() {
+        L.rawgeti(all, i);
+        L.rawseti(-2, j as i32 + 1);
+    }
+    L.remove(all);
+    n
+}
+
+// returns true if the wildcard handler pattern `pat` applies to `event`
+// "*" matches every event, a trailing "*" matches by prefix, and "N-M"
+// matches numeric replies in the inclusive range
+fn pattern_matches(pat: &[u8], event: &[u8]) -> bool {
+    if pat == EVT_ALL.as_bytes() {
+        return true;
+    }
+    if pat.len() > 1 && pat[pat.len()-1] == '*' as u8 {
+        return event.starts_with(pat.slice_to(pat.len()-1));
+    }
+    match pat.iter().position(|&b| b == '-' as u8) {
+        Some(idx) if idx > 0 => {
+            match (parse_code(pat.slice_to(idx)), parse_code(pat.slice_from(idx+1)),
+                   parse_code(event)) {
+                (Some(lo), Some(hi), Some(code)) => lo <= code && code <= hi,
+                _ => false
+            }
+        }
+        _ => false
+    }
+}
+
+fn parse_code(s: &[u8]) -> Option<uint> {
+    if s.is_empty() || !s.iter().all(|&b| b >= '0' as u8 && b <= '9' as u8) {
+        return None;
+    }
+    str::from_utf8(s).and_then(|s| from_str::<uint>(s))
+}
+
+// the unique lightuserdata value that handlers return to stop propagation
+fn handled_sentinel() -> *mut libc::c_void {
+    handled_sentinel as *mut libc::c_void
+}
+
+// replaces a symbolic numeric name at the given index, e.g. "RPL_WELCOME",
+// with its code
+unsafe fn normalize_event(L: &mut lua::ExternState, idx: i32) {
+    match L.tostring(idx).and_then(numerics::code) {
+        None => (),
+        Some(code) => {
+            push_code(L, code);
+            L.replace(idx);
+        }
+    }
+}
+
+// registers the function at index 2 as a handler for the event at index 1,
+// with an optional priority at index 3
+// rich handlers receive a single event object instead of positional arguments
+unsafe fn add_handler(L: &mut lua::ExternState, once: bool, rich: bool) -> i32 {
+    L.checkbytes(1);
+    L.checktype(2, lua::Type::Function);
+    let priority = L.optinteger(3, 0);
+
+    normalize_event(L, 1);
+
+    L.settop(2); // throw away any extra values
+    insert_handler(L, priority, once, rich, None)
+}
+
+// inserts a record for the handler at index 2 into the handlers for the event
+// at index 1, returning its handle; the stack must hold nothing else
+// regex is a registry reference to a trigger's Regex, which is released
+unsafe fn insert_handler(L: &mut lua::ExternState, priority: int, once: bool, rich: bool,
+                         regex: Option<i32>) -> i32 {
+    // get or create handler table; key is lua_addhandler
+    L.pushlightuserdata(lua_addhandler as *mut libc::c_void);
+    L.gettable(lua::REGISTRYINDEX);
+    if !L.istable(3) {
+        L.pop(1);
+        L.newtable();
+        L.pushlightuserdata(lua_addhandler as *mut libc::c_void);
+        L.pushvalue(3);
+        L.settable(lua::REGISTRYINDEX);
+    }
+    // table is stack entry 3
+
+    // get or create the array
+    L.pushvalue(1); // copy the event to the top
+    L.gettable(3);
+    if !L.istable(4) {
+        L.pop(1);
+        L.newtable();
+        L.pushvalue(1); // copy event to top
+        L.pushvalue(4);
+        L.settable(3);
+    }
+    // array is stack entry 4
+
+    // allocate a handle
+    L.getfield(lua::REGISTRYINDEX, HANDLER_NEXT_ID);
+    let id = L.tointeger(-1) + 1;
+    L.pop(1);
+    L.pushinteger(id);
+    L.setfield(lua::REGISTRYINDEX, HANDLER_NEXT_ID);
+
+    // build the handler record
+    L.createtable(0, 7);
+    L.pushvalue(2);
+    L.setfield(-2, "fn");
+    L.pushinteger(id);
+    L.setfield(-2, "id");
+    L.pushboolean(once);
+    L.setfield(-2, "once");
+    L.pushinteger(priority);
+    L.setfield(-2, "priority");
+    L.pushboolean(rich);
+    L.setfield(-2, "rich");
+    // remember which plugin registered the handler so its allocations are charged to it
+    L.pushinteger(alloc::get(L).current() as int);
+    L.setfield(-2, "owner");
+    match regex {
+        None => (),
+        Some(r) => {
+            L.rawgeti(lua::REGISTRYINDEX, r);
+            L.setfield(-2, "regex");
+            L.unref(lua::REGISTRYINDEX, r);
+        }
+    }
+
+    // keep the array sorted by descending priority, after any existing
+    // handlers of the same priority
+    let len = L.objlen(4) as i32; // get table length
+    let mut pos = len + 1;
+    for i in range_inclusive(1, len) {
+        L.rawgeti(4, i);
+        L.getfield(-1, "priority");
+        let p = L.tointeger(-1);
+        L.pop(2);
+        if p < priority {
+            pos = i;
+            break;
+        }
+    }
+    let mut i = len;
+    while i >= pos {
+        L.rawgeti(4, i);
+        L.rawseti(4, i + 1);
+        i -= 1;
+    }
+    L.rawseti(4, pos); // set ary[pos]=record
+
+    // map the handle back to its event so it can be removed
+    push_handler_index(L);
+    L.pushinteger(id);
+    L.pushvalue(1);
+    L.rawset(-3);
+    L.pop(1);
+
+    L.pushinteger(id);
+    1
+}
+
+// removes the handler with the given handle, returning true if it existed
+unsafe fn remove_handler(L: &mut lua::ExternState, id: int) -> bool {
+    let top = L.gettop();
+    push_handler_index(L);
+    L.pushinteger(id);
+    L.rawget(-2);
+    if L.isnil(-1) {
+        L.settop(top);
+        return false;
+    }
+    // event name is on top, forget the handle
+    L.pushinteger(id);
+    L.pushnil();
+    L.rawset(-4);
+
+    L.pushlightuserdata(lua_addhandler as *mut libc::c_void);
+    L.gettable(lua::REGISTRYINDEX);
+    L.insert(-2); // move the handler table behind the event name
+    L.gettable(-2);
+    let ary = L.gettop();
+    let len = L.objlen(ary) as i32;
+    for i in range_inclusive(1, len) {
+        L.rawgeti(ary, i);
+        L.getfield(-1, "id");
+        let found = L.tointeger(-1) == id;
+        L.pop(2);
+        if found {
+            // shift the remaining handlers down to keep the array contiguous
+            for j in range(i, len) {
+                L.rawgeti(ary, j + 1);
+                L.rawseti(ary, j);
+            }
+            L.pushnil();
+            L.rawseti(ary, len);
+            break;
+        }
+    }
+    L.settop(top);
+    true
+}
+
+// returns true if the handler with the given handle is still registered
+unsafe fn handler_registered(L: &mut lua::ExternState, id: int) -> bool {
+    push_handler_index(L);
+    L.pushinteger(id);
+    L.rawget(-2);
+    let registered = !L.isnil(-1);
+    L.pop(2);
+    registered
+}
+
+// pushes the table that maps handler handles to their event names
+unsafe fn push_handler_index(L: &mut lua::ExternState) {
+    L.getfield(lua::REGISTRYINDEX, HANDLER_INDEX);
+    if !L.istable(-1) {
+        L.pop(1);
+        L.newtable();
+        L.pushvalue(-1);
+        L.setfield(lua::REGISTRYINDEX, HANDLER_INDEX);
+    }
+}
+
+unsafe fn push_user(L: &mut lua::ExternState, user: &irc::User) {
+    L.createtable(0, 4);
+    L.pushbytes(user.raw());
+    L.setfield(-2, "raw");
+    L.pushbytes(user.nick());
+    L.setfield(-2, "nick");
+    match user.user() {
+        None => L.pushnil(),
+        Some(v) => L.pushbytes(v)
+    }
+    L.setfield(-2, "user");
+    match user.user() {
+        None => L.pushnil(),
+        Some(v) => L.pushbytes(v)
+    }
+    L.setfield(-2, "host");
+}
+
+// unsafe because the Conn isn't really 'static
+unsafe fn getconn(L: &mut lua::ExternState) -> &'static mut Conn<'static> {
+    L.pushlightuserdata(lua_require as *mut libc::c_void);
+    L.gettable(lua::REGISTRYINDEX);
+    let ptr = L.touserdata(-1) as *mut *mut Conn<'static>;
+    if ptr.is_null() {
+        L.errorstr("could not retrieve connection information");
+    }
+    let ptr = *ptr;
+    if ptr.is_null() {
+        L.errorstr("no active connection");
+    }
+    &mut *ptr
+}
+
+pub fn activate_conn(L: &mut lua::State, conn: &mut Conn) {
+    L.pushlightuserdata(lua_require as *mut libc::c_void);
+    L.gettable(lua::REGISTRYINDEX);
+    let ptr = L.touserdata(-1) as *mut *mut Conn;
+    if ptr.is_null() {
+        L.errorstr("could not retrieve connection information");
+    }
+    unsafe { *ptr = conn as *mut Conn };
+}
+
+pub fn deactivate_conn(L: &mut lua::State) {
+    L.pushlightuserdata(lua_require as *mut libc::c_void);
+    L.gettable(lua::REGISTRYINDEX);
+    let ptr = L.touserdata(-1) as *mut *mut Conn;
+    if ptr.is_null() {
+        L.errorstr("could not retrieve connection information");
+    }
+    unsafe { *ptr = ptr::mut_null() };
+}
+
+lua_extern! {
+    unsafe fn lua_addhandler(L: &mut lua::ExternState) -> i32 {
+        // 2 args: event, func
+        // returns a handle for irc.removehandler
+
+        add_handler(L, false, false)
+    }
+
+    unsafe fn lua_once(L: &mut lua::ExternState) -> i32 {
+        // 2 args: event, func
+        // returns a handle for irc.removehandler
+
+        add_handler(L, true, false)
+    }
+
+    unsafe fn lua_on(L: &mut lua::ExternState) -> i32 {
+        // 2 args: event, func
+        // returns a handle for irc.removehandler
+
+        add_handler(L, false, true)
+    }
+
+    unsafe fn lua_on_match(L: &mut lua::ExternState) -> i32 {
+        // 2-3 args: regex, func, priority
+        // returns a handle for irc.removehandler
+
+        pattern::push_regex(L, 1);
+        L.replace(1);
+        L.checktype(2, lua::Type::Function);
+        let priority = L.optinteger(3, 0);
+
+        L.pushvalue(1);
+        let regex = L.ref_(lua::REGISTRYINDEX);
+        L.pushstring(EVT_MATCH);
+        L.replace(1);
+        L.settop(2); // throw away any extra values
+        insert_handler(L, priority, false, true, Some(regex))
+    }
+
+    unsafe fn lua_removehandler(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: handle
+        // returns true if the handler was removed
+
+        let id = L.checkinteger(1);
+        L.pushboolean(remove_handler(L, id));
+        1
+    }
+
+// *** IRC package functions ***
+
+    unsafe fn lua_config(L: &mut lua::ExternState) -> i32 {
+        // 0 args
+
+        push_current_config(L);
+        1
+    }
+
+    unsafe fn lua_host(L: &mut lua::ExternState) -> i32 {
+        // 0 args
+
+        let conn = getconn(L);
+
+        // construct our response on the stack
+        let mut buf = [0u8, ..64];
+        let n = {
+            let mut w = BufWriter::new(buf);
+            match write!(&mut w, "{}", conn.host()).and_then(|_| w.tell()) {
+                Ok(n) => n,
+                Err(e) => {
+                    drop(e);
+                    L.errorstr("could not format host");
+                }
+            }
+        };
+        L.pushbytes(buf.slice_to(n as uint));
+        1
+    }
+
+    unsafe fn lua_me(L: &mut lua::ExternState) -> i32 {
+        // 0 args
+
+        let conn = getconn(L);
+
+        // return a user table
+        push_user(L, conn.me());
+        1
+    }
 
     unsafe fn lua_privmsg(L: &mut lua::ExternState) -> i32 {
         // 2 args: dst, message
@@ -390,4 +2117,552 @@ lua_extern! {
         conn.notice(dst, msg);
         0
     }
+
+    unsafe fn lua_after(L: &mut lua::ExternState) -> i32 {
+        // 2 args: seconds, func
+        // returns a timer for irc.cancel
+
+        add_timer(L, false)
+    }
+
+    unsafe fn lua_every(L: &mut lua::ExternState) -> i32 {
+        // 2 args: seconds, func
+        // returns a timer for irc.cancel
+
+        add_timer(L, true)
+    }
+
+    unsafe fn lua_cancel(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: timer
+        // returns true if the timer was pending
+
+        let id = L.checkinteger(1);
+        push_timers(L);
+        L.rawgeti(-1, id as i32);
+        let pending = L.istable(-1);
+        L.pop(1);
+        if pending {
+            L.pushnil();
+            L.rawseti(-2, id as i32);
+            get_timers(L).cancel(id as uint);
+        }
+        L.pushboolean(pending);
+        1
+    }
+
+    unsafe fn lua_wait(L: &mut lua::ExternState) -> i32 {
+        // 3 args: event, filter (optional), timeout in seconds (optional)
+        // yields until a matching event arrives and returns its arguments, or
+        // returns nothing if the timeout expires first
+
+        L.checkbytes(1);
+        normalize_event(L, 1);
+        if !L.isnoneornil(2) {
+            L.checktype(2, lua::Type::Function);
+        }
+        let timeout = L.optnumber(3, -1.0);
+        L.settop(3);
+        if L.pushthread() {
+            L.errorstr("irc.wait can only be called from a handler or timer");
+        }
+        // thread is stack entry 4
+        let owner = alloc::get(L).current();
+
+        push_waiters(L);
+        L.pushvalue(4);
+        L.createtable(0, 5);
+        L.pushvalue(4);
+        L.setfield(-2, "thread");
+        L.pushvalue(1);
+        L.setfield(-2, "event");
+        L.pushvalue(2);
+        L.setfield(-2, "filter");
+        L.pushinteger(owner as int);
+        L.setfield(-2, "owner");
+        if timeout >= 0.0 {
+            let id = get_timers(L).schedule((timeout * 1000.0) as u64);
+            push_timers(L);
+            L.createtable(0, 2);
+            L.pushvalue(4);
+            L.setfield(-2, "waiter");
+            L.pushinteger(owner as int);
+            L.setfield(-2, "owner");
+            L.rawseti(-2, id as i32);
+            L.pop(1);
+            L.pushinteger(id as int);
+            L.setfield(-2, "timer");
+        }
+        L.rawset(5); // waiters[thread] = record
+
+        L.settop(0);
+        mark_suspending(L);
+        L.yield_(0)
+    }
+
+    unsafe fn lua_whois(L: &mut lua::ExternState) -> i32 {
+        // 2 args: nick, callback (optional)
+
+        start_query(L, query::Whois, bytes!("WHOIS"), [])
+    }
+
+    unsafe fn lua_who(L: &mut lua::ExternState) -> i32 {
+        // 2 args: mask, callback (optional)
+
+        start_query(L, query::Who, bytes!("WHO"), [])
+    }
+
+    unsafe fn lua_names(L: &mut lua::ExternState) -> i32 {
+        // 2 args: channel, callback (optional)
+
+        start_query(L, query::Names, bytes!("NAMES"), [])
+    }
+
+    unsafe fn lua_banlist(L: &mut lua::ExternState) -> i32 {
+        // 2 args: channel, callback (optional)
+
+        start_query(L, query::BanList, bytes!("MODE"), bytes!(" +b"))
+    }
+
+    unsafe fn lua_numeric_name(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: code, as a number or a string such as "001"
+
+        let code = if L.isnumber(1) && !L.isstring(1) {
+            Some(L.tointeger(1) as uint)
+        } else {
+            parse_code(L.checkbytes(1))
+        };
+        match code.and_then(numerics::name) {
+            None => L.pushnil(),
+            Some(name) => L.pushstring(name)
+        }
+        1
+    }
+
+    unsafe fn lua_emit(L: &mut lua::ExternState) -> i32 {
+        // 1+ args: name, args...
+
+        let event = {
+            let name = L.checkbytes(1);
+            let valid = !name.is_empty() && name.iter().all(|&b| b != ':' as u8 && b > ' ' as u8);
+            L.argcheck(valid, 1, "event name may not be empty or contain ':' or spaces");
+            let alloc = alloc::get(L);
+            let mut event = alloc.name(alloc.current()).as_bytes().to_owned();
+            event.push(':' as u8);
+            event.push_all(name);
+            event
+        };
+
+        // arrange the stack as event name, sender, arguments
+        L.pushbytes(event);
+        L.replace(1);
+        L.pushnil();
+        L.insert(2);
+
+        dispatch_event_inner(L, None, None);
+        0
+    }
+
+    unsafe fn lua_has_role(L: &mut lua::ExternState) -> i32 {
+        // 2-3 args: user, role, chan
+        // user is a User table or a nick!user@host string
+
+        if L.istable(1) {
+            L.getfield(1, "raw");
+            L.replace(1);
+        }
+        L.checkbytes(1);
+        let role = match acl::Role::from_name(L.checkstring(2).unwrap_or("")) {
+            None => L.argerror(2, "role must be owner, admin or trusted"),
+            Some(role) => role
+        };
+        let has_chan = !L.isnoneornil(3);
+        if has_chan {
+            L.checkbytes(3);
+        }
+        // everything is checked, so the copies can't leak
+        let check = {
+            let mask = L.tobytes(1).unwrap().to_owned();
+            let chan = if has_chan { Some(L.tobytes(3).unwrap().to_owned()) } else { None };
+            get_acl(L).check(mask.as_slice(), role, chan.as_ref().map(|c| c.as_slice()))
+        };
+        L.pushboolean(check == acl::Granted);
+        1
+    }
+
+    unsafe fn lua_ignore(L: &mut lua::ExternState) -> i32 {
+        // 1-2 args: target, options table with duration, channel, server
+
+        // check every argument before copying any of them out, as an error
+        // would leak the copies
+        L.checkstring(1);
+        let (mut duration, mut channel, mut server) = (None, None, false);
+        if !L.isnoneornil(2) {
+            L.checktype(2, lua::Type::Table);
+            L.settop(2);
+            L.getfield(2, "duration");
+            if !L.isnil(-1) {
+                L.argcheck(L.isnumber(-1) && L.tonumber(-1) > 0.0, 2,
+                           "duration must be a positive number");
+                duration = Some(L.tonumber(-1).ceil() as i64);
+            }
+            L.getfield(2, "channel");
+            if !L.isnil(-1) {
+                L.argcheck(L.isstring(-1), 2, "channel must be a string");
+                channel = Some(L.gettop());
+            }
+            L.getfield(2, "server");
+            server = L.toboolean(-1);
+        }
+
+        let res = {
+            let target = L.tostring(1).unwrap().to_owned();
+            let channel = channel.map(|i| L.tostring(i).unwrap().to_owned());
+            let ignores = get_ignores(L);
+            let server = if server { Some(ignores.server().to_owned()) } else { None };
+            let entry = ignore::Entry::new(target.as_slice(), duration,
+                                           server.as_ref().map(|s| s.as_slice()),
+                                           channel.as_ref().map(|c| c.as_slice()));
+            match entry {
+                Err(e) => Err(e),
+                Ok(entry) => match ignores.add(entry) {
+                    Ok(()) => Ok(()),
+                    Err(e) => Err(format!("could not save ignore list: {}", e))
+                }
+            }
+        };
+        match res {
+            Ok(()) => (),
+            Err(e) => raise(L, e)
+        }
+        0
+    }
+
+    unsafe fn lua_ctcp_reply(L: &mut lua::ExternState) -> i32 {
+        // 2 args: CTCP type, reply (a string, a function, false or nil)
+
+        let valid = match L.checkstring(1) {
+            Some(cmd) => {
+                !cmd.is_empty() && !cmd.contains_char(' ') && !cmd.eq_ignore_ascii_case("ACTION")
+            }
+            None => false
+        };
+        L.argcheck(valid, 1, "invalid CTCP type");
+        let valid = match L.type_(2) {
+            None | Some(lua::Type::Nil) | Some(lua::Type::String) => true,
+            Some(lua::Type::Function) => true,
+            Some(lua::Type::Boolean) => !L.toboolean(2),
+            _ => false
+        };
+        L.argcheck(valid, 2, "expected a string, function, false or nil");
+
+        push_ctcp_replies(L);
+        if L.isnoneornil(2) {
+            // back to the built-in reply, if any
+            L.pushnil();
+        } else {
+            L.createtable(0, 2);
+            L.pushvalue(2);
+            L.setfield(-2, "reply");
+            // remember which plugin registered the reply so it runs on its behalf
+            L.pushinteger(alloc::get(L).current() as int);
+            L.setfield(-2, "owner");
+        }
+        {
+            let cmd = L.tostring(1).unwrap().to_ascii_upper();
+            L.setfield(-2, cmd.as_slice());
+        }
+        0
+    }
+
+    unsafe fn lua_unignore(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: target
+        // returns true if the target was ignored
+
+        L.checkstring(1);
+        let res = {
+            let target = L.tostring(1).unwrap().to_owned();
+            match get_ignores(L).remove(target.as_slice()) {
+                Ok(n) => Ok(n),
+                Err(e) => Err(format!("could not save ignore list: {}", e))
+            }
+        };
+        match res {
+            Ok(n) => L.pushboolean(n > 0),
+            Err(e) => raise(L, e)
+        }
+        1
+    }
+
+    unsafe fn lua_ignores(L: &mut lua::ExternState) -> i32 {
+        // 0 args
+        // returns an array of the ignore list entries
+
+        let entries = get_ignores(L).entries();
+        L.createtable(entries.len() as i32, 0);
+        for (i, e) in entries.iter().enumerate() {
+            L.createtable(0, 4);
+            L.pushstring(e.target.as_slice());
+            L.setfield(-2, "target");
+            match e.expires {
+                None => (),
+                Some(t) => {
+                    L.pushinteger(t as int);
+                    L.setfield(-2, "expires");
+                }
+            }
+            match e.server {
+                None => (),
+                Some(ref s) => {
+                    L.pushstring(s.as_slice());
+                    L.setfield(-2, "server");
+                }
+            }
+            match e.channel {
+                None => (),
+                Some(ref c) => {
+                    L.pushstring(c.as_slice());
+                    L.setfield(-2, "channel");
+                }
+            }
+            L.rawseti(-2, i as i32 + 1);
+        }
+        1
+    }
+
+    unsafe fn lua_verified_command(L: &mut lua::ExternState) -> i32 {
+        // 1-2 args: whois info, or nil and an error message
+        // upvalues: command record, event object, args
+
+        let (rec, ev, args) = (lua::upvalueindex(1), lua::upvalueindex(2), lua::upvalueindex(3));
+        let conn = getconn(L);
+        L.getfield(ev, "sender");
+        L.getfield(-1, "raw");
+        let mask = L.tobytes(-1).unwrap_or(&[]).to_owned();
+        L.getfield(-2, "nick");
+        let nick = L.tobytes(-1).unwrap_or(&[]).to_owned();
+        L.getfield(ev, "target");
+        let target = L.tobytes(-1).unwrap_or(&[]).to_owned();
+        L.getfield(ev, "is_private");
+        let private = L.toboolean(-1);
+        L.settop(0);
+
+        let chan = if private { None } else { Some(target.as_slice()) };
+        // an account that's still unknown means the sender isn't logged in
+        if check_role(L, rec, mask.as_slice(), chan) != acl::Granted {
+            let msg = denied(L, rec);
+            conn.privmsg(if private { nick.as_slice() } else { target.as_slice() }, msg.as_bytes());
+            return 0;
+        }
+
+        L.getfield(rec, "owner");
+        let owner = L.tointeger(-1) as uint;
+        L.pop(1);
+        L.getfield(rec, "fn");
+        L.pushvalue(ev);
+        let nargs = L.objlen(args) as i32;
+        for i in range_inclusive(1, nargs) {
+            L.rawgeti(args, i);
+        }
+        call_as(L, owner, nargs + 1, 0, "running command");
+        0
+    }
+
+    unsafe fn lua_command(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: spec table with name, handler, aliases, usage, help, min_args, role
+
+        L.checktype(1, lua::Type::Table);
+        L.settop(1);
+
+        // check every value before copying any of them out, as an error would
+        // leak the copies
+        L.getfield(1, "name");
+        let valid = match L.type_(-1) {
+            Some(lua::Type::String) => valid_command(L.tobytes(-1).unwrap()),
+            _ => false
+        };
+        L.argcheck(valid, 1, "name must be a string without spaces");
+        let name = L.gettop();
+        L.getfield(1, "aliases");
+        match L.type_(-1) {
+            None | Some(lua::Type::Nil) => (),
+            Some(lua::Type::Table) => {
+                for i in range_inclusive(1, L.objlen(-1) as i32) {
+                    L.rawgeti(-1, i);
+                    let valid = match L.type_(-1) {
+                        Some(lua::Type::String) => valid_command(L.tobytes(-1).unwrap()),
+                        _ => false
+                    };
+                    L.argcheck(valid, 1, "aliases must be strings without spaces");
+                    L.pop(1);
+                }
+            }
+            Some(_) => {
+                L.argerror(1, "aliases must be an array of strings");
+            }
+        }
+        let aliases = L.gettop();
+
+        L.getfield(1, "handler");
+        if !L.isfunction(-1) {
+            L.argerror(1, "handler must be a function");
+        }
+        let handler = L.gettop();
+        L.getfield(1, "min_args");
+        let min_args = if L.isnil(-1) { 0 } else if L.isnumber(-1) { L.tointeger(-1) } else { -1 };
+        L.argcheck(min_args >= 0, 1, "min_args must be a non-negative number");
+        L.getfield(1, "usage");
+        L.argcheck(L.isnil(-1) || L.isstring(-1), 1, "usage must be a string");
+        let usage = L.gettop();
+        L.getfield(1, "help");
+        L.argcheck(L.isnil(-1) || L.isstring(-1), 1, "help must be a string");
+        let help = L.gettop();
+        L.getfield(1, "role");
+        let valid = L.isnil(-1) || (L.type_(-1) == Some(lua::Type::String) &&
+                                    acl::Role::from_name(L.tostring(-1).unwrap()).is_some());
+        L.argcheck(valid, 1, "role must be owner, admin or trusted");
+        let role = L.gettop();
+
+        // gather every name the command answers to, lowercased
+        L.newtable();
+        let names = L.gettop();
+        {
+            let lowered = command::lower(L.tobytes(name).unwrap());
+            L.pushbytes(lowered.as_slice());
+        }
+        L.rawseti(names, 1);
+        let mut nnames = 1;
+        if L.istable(aliases) {
+            for i in range_inclusive(1, L.objlen(aliases) as i32) {
+                L.rawgeti(aliases, i);
+                {
+                    let lowered = command::lower(L.tobytes(-1).unwrap());
+                    L.pushbytes(lowered.as_slice());
+                }
+                nnames += 1;
+                L.rawseti(names, nnames);
+                L.pop(1);
+            }
+        }
+
+        push_commands(L);
+        let cmds = L.gettop();
+        for i in range_inclusive(1, nnames) {
+            L.rawgeti(names, i);
+            L.rawget(cmds);
+            if !L.isnil(-1) {
+                L.rawgeti(names, i);
+                let msg = format!("command {} is already registered",
+                                  str::from_utf8_lossy(L.tobytes(-1).unwrap()));
+                raise(L, msg);
+            }
+            L.pop(1);
+        }
+
+        L.createtable(0, 8);
+        L.rawgeti(names, 1);
+        L.setfield(-2, "name");
+        if L.istable(aliases) {
+            L.pushvalue(aliases);
+        } else {
+            L.newtable();
+        }
+        L.setfield(-2, "aliases");
+        L.pushvalue(usage);
+        L.setfield(-2, "usage");
+        L.pushvalue(help);
+        L.setfield(-2, "help");
+        L.pushinteger(min_args);
+        L.setfield(-2, "min_args");
+        L.pushvalue(role);
+        L.setfield(-2, "role");
+        L.pushvalue(handler);
+        L.setfield(-2, "fn");
+        // remember which plugin registered the command so it runs on its behalf
+        L.pushinteger(alloc::get(L).current() as int);
+        L.setfield(-2, "owner");
+        for i in range_inclusive(1, nnames) {
+            L.rawgeti(names, i);
+            L.pushvalue(-2);
+            L.rawset(cmds);
+        }
+        0
+    }
+
+    unsafe fn lua_event_reply(L: &mut lua::ExternState) -> i32 {
+        // 2 args: event, message
+
+        event_reply(L, |conn, dst, msg| conn.privmsg(dst, msg));
+        0
+    }
+
+    unsafe fn lua_event_notice_reply(L: &mut lua::ExternState) -> i32 {
+        // 2 args: event, message
+
+        event_reply(L, |conn, dst, msg| conn.notice(dst, msg));
+        0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use lua;
+    use super::super::alloc;
+    use super::{call_as, dispatch_event_inner, has_listeners, lua_wait};
+
+    lua_extern! {
+        // runs the global function waiter the way a handler would be run
+        unsafe fn run_waiter(L: &mut lua::ExternState) -> i32 {
+            L.getglobal("waiter");
+            call_as(L, 0, 0, 0, "running waiter");
+            0
+        }
+
+        // dispatches the event FOO, returning whether anything listened for it
+        unsafe fn dispatch_foo(L: &mut lua::ExternState) -> i32 {
+            L.settop(0);
+            L.pushstring("FOO");
+            let listening = has_listeners(L);
+            dispatch_event_inner(L, None, None);
+            L.settop(0);
+            L.pushboolean(listening);
+            1
+        }
+    }
+
+    fn call(L: &mut lua::State, f: lua::CFunction, nresults: i32) {
+        L.pushcfunction(f);
+        match L.pcall(0, nresults, 0) {
+            Ok(()) => (),
+            Err(e) => fail!("{}: {}", e, L.describe(-1))
+        }
+    }
+
+    #[test]
+    fn test_wait_without_handlers() {
+        let mut alloc = ~alloc::Allocator::new(None, None);
+        let mut L = alloc::new_state(&mut *alloc);
+        L.openlibs();
+        L.pushcfunction(lua_wait);
+        L.setglobal("wait");
+        match L.loadstring("function waiter() wait('FOO'); resumed = true end") {
+            Ok(()) => (),
+            Err(e) => fail!("{}: {}", e, L.describe(-1))
+        }
+        match L.pcall(0, 0, 0) {
+            Ok(()) => (),
+            Err(e) => fail!("{}: {}", e, L.describe(-1))
+        }
+
+        call(&mut L, run_waiter, 0);
+        L.getglobal("resumed");
+        assert!(L.isnil(-1));
+        L.pop(1);
+
+        // no handler is registered for FOO, but the wait still gets it
+        call(&mut L, dispatch_foo, 1);
+        assert!(L.toboolean(-1));
+        L.pop(1);
+        L.getglobal("resumed");
+        assert!(L.toboolean(-1));
+    }
 }
@@ -0,0 +1,205 @@
+//! Plugin manifests
+//!
+//! A plugin may describe itself with a manifest. A directory plugin can use a
+//! plugin.toml file in its directory. Any plugin can instead start with a
+//! header table, a Lua table inside a block comment at the very top of the
+//! plugin file:
+//!
+//!     --[[plugin
+//!     { version = "1.0", depends = {"util"}, enabled = true }
+//!     ]]
+//!
+//! Both forms accept the same keys, all optional:
+//!
+//! name: The plugin name; defaults to the file or directory name. As it names
+//!     the plugin's storage directory, it may only contain letters, digits,
+//!     - and _.
+//! version: A version string, shown by /plugins
+//! depends: An array of plugin names that must be loaded before this one
+//! enabled: Whether the plugin is loaded by default, default true. An enabled
+//!     key in the plugin's [plugins.<name>] config table takes precedence.
+
+#[allow(uppercase_variables)];
+
+use lua;
+use toml;
+use super::store;
+use std::{io, str};
+
+static HEADER_START: &'static str = "--[[plugin";
+static HEADER_END: &'static str = "]]";
+
+/// The manifest of a plugin
+pub struct Manifest {
+    name: ~str,
+    version: Option<~str>,
+    depends: ~[~str],
+    enabled: bool
+}
+
+impl Manifest {
+    /// Returns the manifest used for plugins that don't declare one
+    pub fn new(name: &str) -> Manifest {
+        Manifest {
+            name: name.to_owned(),
+            version: None,
+            depends: ~[],
+            enabled: true
+        }
+    }
+}
+
+/// Reads the manifest for the plugin with the given default name. `dir` is
+/// the plugin's directory for directory plugins, and `file` is the Lua file
+/// that's loaded for it.
+pub fn read(L: &mut lua::State, name: &str, dir: Option<&Path>,
+            file: &Path) -> Result<Manifest, ~str> {
+    let manifest = match dir {
+        Some(dir) if dir.join("plugin.toml").is_file() => {
+            read_toml(name, &dir.join("plugin.toml"))
+        }
+        _ => read_header(L, name, file)
+    };
+    match manifest {
+        Ok(ref m) if m.name.as_slice() != name && !store::valid_name(m.name.as_slice()) => {
+            Err(format!("name {} may only contain letters, digits, '-' and '_'", m.name))
+        }
+        m => m
+    }
+}
+
+fn read_toml(name: &str, path: &Path) -> Result<Manifest, ~str> {
+    let root = match toml::parse_from_path(path) {
+        Ok(v) => v,
+        Err(toml::ParseError) => return Err(~"plugin.toml could not be parsed"),
+        Err(toml::IOError(e)) => return Err(e.to_str())
+    };
+
+    let mut manifest = Manifest::new(name);
+    match root.lookup("name") {
+        None => (),
+        Some(v) => match v.get_str() {
+            None => return Err(~"name must be a string"),
+            Some(s) => manifest.name = s.clone()
+        }
+    }
+    match root.lookup("version") {
+        None => (),
+        Some(v) => match v.get_str() {
+            None => return Err(~"version must be a string"),
+            Some(s) => manifest.version = Some(s.clone())
+        }
+    }
+    match root.lookup("depends") {
+        None => (),
+        Some(v) => {
+            let deps = match v.get_vec() {
+                None => return Err(~"depends must be an array of plugin names"),
+                Some(deps) => deps
+            };
+            for dep in deps.iter() {
+                match dep.get_str() {
+                    None => return Err(~"depends must be an array of plugin names"),
+                    Some(s) => manifest.depends.push(s.clone())
+                }
+            }
+        }
+    }
+    match root.lookup("enabled") {
+        None => (),
+        Some(v) => match v.get_bool() {
+            None => return Err(~"enabled must be a boolean"),
+            Some(b) => manifest.enabled = b
+        }
+    }
+    Ok(manifest)
+}
+
+fn read_header(L: &mut lua::State, name: &str, file: &Path) -> Result<Manifest, ~str> {
+    let contents = match io::File::open(file).and_then(|mut f| f.read_to_end()) {
+        Ok(v) => v,
+        Err(e) => return Err(e.to_str())
+    };
+    if !contents.starts_with(HEADER_START.as_bytes()) {
+        return Ok(Manifest::new(name));
+    }
+    let rest = contents.slice_from(HEADER_START.len());
+    let end = match rest.windows(HEADER_END.len()).position(|w| w == HEADER_END.as_bytes()) {
+        None => return Err(~"header table is not terminated"),
+        Some(end) => end
+    };
+    let source = match str::from_utf8(rest.slice_to(end)) {
+        None => return Err(~"header table is not valid UTF-8"),
+        Some(s) => format!("return {}", s)
+    };
+
+    // evaluate the table with an empty environment, it's only data
+    let top = L.gettop();
+    match L.loadstring(source) {
+        Ok(()) => (),
+        Err(_) => {
+            let e = format!("header table is invalid: {}", L.describe(-1));
+            L.settop(top);
+            return Err(e);
+        }
+    }
+    L.newtable();
+    L.setfenv(-2);
+    match L.pcall(0, 1, 0) {
+        Ok(()) => (),
+        Err(_) => {
+            let e = format!("header table is invalid: {}", L.describe(-1));
+            L.settop(top);
+            return Err(e);
+        }
+    }
+    let result = manifest_from_table(L, name, top + 1);
+    L.settop(top);
+    result
+}
+
+// reads the manifest from the header table at the given index
+fn manifest_from_table(L: &mut lua::State, name: &str, idx: i32) -> Result<Manifest, ~str> {
+    if !L.istable(idx) {
+        return Err(~"header must be a table");
+    }
+
+    let mut manifest = Manifest::new(name);
+    L.getfield(idx, "name");
+    match L.type_(-1) {
+        None | Some(lua::Type::Nil) => (),
+        Some(lua::Type::String) => manifest.name = L.tostring(-1).unwrap().to_owned(),
+        Some(_) => return Err(~"name must be a string")
+    }
+    L.getfield(idx, "version");
+    match L.type_(-1) {
+        None | Some(lua::Type::Nil) => (),
+        Some(lua::Type::String) | Some(lua::Type::Number) => {
+            manifest.version = L.tostring(-1).map(|s| s.to_owned());
+        }
+        Some(_) => return Err(~"version must be a string")
+    }
+    L.getfield(idx, "depends");
+    match L.type_(-1) {
+        None | Some(lua::Type::Nil) => (),
+        Some(lua::Type::Table) => {
+            let deps = L.gettop();
+            for i in range(1, L.objlen(deps) as i32 + 1) {
+                L.rawgeti(deps, i);
+                if !L.isstring(-1) {
+                    return Err(~"depends must be an array of plugin names");
+                }
+                manifest.depends.push(L.tostring(-1).unwrap().to_owned());
+                L.pop(1);
+            }
+        }
+        Some(_) => return Err(~"depends must be an array of plugin names")
+    }
+    L.getfield(idx, "enabled");
+    match L.type_(-1) {
+        None | Some(lua::Type::Nil) => (),
+        Some(lua::Type::Boolean) => manifest.enabled = L.toboolean(-1),
+        Some(_) => return Err(~"enabled must be a boolean")
+    }
+    Ok(manifest)
+}
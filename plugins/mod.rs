@@ -1,30 +1,129 @@
 //! Plugin manager for Lua plugins
+//!
+//! Plugins are loaded from the plugin dir. A plugin is either a single file
+//! foo.lua, or a directory foo/ containing an init.lua. Directory plugins can
+//! require modules from their own directory, which are kept separate from the
+//! modules of other plugins. Modules shared between plugins go in the lib/
+//! directory inside the plugin dir, which is on package.path.
+//!
+//! Plugins are loaded in order of name, except that a plugin whose manifest
+//! declares dependencies is loaded after them. See the manifest module.
 
 #[allow(uppercase_variables)];
 
 use lua;
 use config;
+use timer;
+use toml;
 use std::{io, libc, str};
 
 static ERROR_HANDLER: &'static str = "error_handler";
+static TIMER_CLIENT: &'static str = "timer_client";
+static QUERY_COLLECTOR: &'static str = "query_collector";
+static COMMAND_PREFIXES: &'static str = "command_prefixes";
+static REGEX_CACHE: &'static str = "regex_cache";
+static ACL: &'static str = "acl";
+static IGNORE_LIST: &'static str = "ignore_list";
+static CTCP_CONFIG: &'static str = "ctcp_config";
+static PLUGIN_CONFIG: &'static str = "plugin_config";
+static PLUGIN_DIRS: &'static str = "plugin_dirs";
+static PLUGIN_MODULES: &'static str = "plugin_modules";
+static GLOBAL_REQUIRE: &'static str = "global_require";
 
 /// Manages the Lua state for plugins
 pub struct PluginManager {
+    // the state must be declared before the allocator so it's dropped first
     priv state: lua::State,
-    priv plugin_dir: Path
+    priv alloc: ~alloc::Allocator,
+    priv timers: ~timer::TimerClient,
+    priv queries: ~query::Collector,
+    priv prefixes: ~command::Prefixes,
+    priv regexes: ~pattern::Cache,
+    priv acl: ~acl::Acl, // outlives the Lua state, so it remembers what it learned
+    priv ignores: ~ignore::IgnoreList,
+    priv limiter: ratelimit::Limiter,
+    priv ctcp: ~config::Ctcp, // the built-in CTCP replies
+    priv plugin_dir: Path,
+    priv config_path: Path,
+    priv plugin_config: ~[(~str, toml::Value)], // the [plugins.<name>] tables
+    priv config_mtime: u64, // when the config file was modified, when it was last read
+    priv data_dir: Path, // where plugins store their data for the current server
+    // allocator owner ids and versions of the successfully loaded plugins
+    priv loaded: ~[(uint, Option<~str>)],
+    // whether the plugins have been sent LOADED, which waits for the first
+    // connection at startup so they can use it
+    priv announced: bool,
+    // whether SHUTDOWN has been sent
+    priv shut_down: bool
+}
+
+// a plugin found in the plugin dir
+struct Plugin {
+    manifest: manifest::Manifest,
+    file: Path, // the Lua file to run
+    dir: Option<Path> // the directory of a directory plugin
+}
+
+#[deriving(Eq)]
+enum LoadStatus {
+    Pending,
+    Loading,
+    Loaded,
+    Failed,
+    Disabled
 }
 
 impl PluginManager {
     /// Creates a new PluginManager and loads all the plugins
-    pub fn new(conf: &config::Config) -> PluginManager {
-        let L = lua::State::new();
+    pub fn new(conf: &config::Config, timers: timer::TimerClient) -> PluginManager {
+        let mut alloc = ~alloc::Allocator::new(conf.memory_limit, conf.plugin_memory_limit);
+        let L = alloc::new_state(&mut *alloc);
+        // TODO: this should follow the server once multiple servers are supported
+        let server = conf.servers.head();
+        let server_name = server.map_or("default", |s| s.name.as_slice());
+        let data_dir = conf.config_dir.join("data").join(server_name);
+        let prefixes = prefixes_for(server);
 
-        let mut manager = PluginManager { state: L, plugin_dir: conf.plugin_dir.clone() };
+        let mut manager = PluginManager {
+            state: L,
+            alloc: alloc,
+            timers: ~timers,
+            queries: ~query::Collector::new(),
+            prefixes: ~prefixes,
+            regexes: ~pattern::Cache::new(),
+            acl: ~acl::Acl::new(conf.roles.as_slice()),
+            ignores: ~ignore::IgnoreList::load(conf.config_dir.join("ignore.list"), server_name),
+            limiter: ratelimit::Limiter::new(conf.rate_limit.clone()),
+            ctcp: ~conf.ctcp.clone(),
+            plugin_dir: conf.plugin_dir.clone(),
+            config_path: conf.config_path.clone(),
+            plugin_config: conf.plugin_config.clone(),
+            config_mtime: mtime_of(&conf.config_path),
+            data_dir: data_dir,
+            loaded: ~[],
+            announced: false,
+            shut_down: false
+        };
         manager.setup();
         manager
     }
 
     fn setup(&mut self) {
+        self.loaded.clear();
+        self.setup_state();
+
+        // load the plugins in name order, with each plugin's dependencies before it
+        let plugins = self.find_plugins();
+        let mut status = plugins.iter().map(|p| {
+            if p.manifest.enabled { Pending } else { Disabled }
+        }).collect::<~[LoadStatus]>();
+        for i in range(0, plugins.len()) {
+            self.load_with_deps(plugins, status, i);
+        }
+    }
+
+    // prepares a fresh Lua state for loading plugins into
+    fn setup_state(&mut self) {
         let L = &mut self.state;
         L.openlibs();
 
@@ -37,6 +136,41 @@ impl PluginManager {
         }
         L.setfield(lua::REGISTRYINDEX, ERROR_HANDLER);
 
+        // give the irc package access to the timer source
+        L.pushlightuserdata(&mut *self.timers as *mut timer::TimerClient as *mut libc::c_void);
+        L.setfield(lua::REGISTRYINDEX, TIMER_CLIENT);
+
+        // and to the query collector
+        L.pushlightuserdata(&mut *self.queries as *mut query::Collector as *mut libc::c_void);
+        L.setfield(lua::REGISTRYINDEX, QUERY_COLLECTOR);
+
+        // and to the command prefixes
+        L.pushlightuserdata(&*self.prefixes as *command::Prefixes as *mut libc::c_void);
+        L.setfield(lua::REGISTRYINDEX, COMMAND_PREFIXES);
+
+        // and to the compiled regexes
+        L.pushlightuserdata(&mut *self.regexes as *mut pattern::Cache as *mut libc::c_void);
+        L.setfield(lua::REGISTRYINDEX, REGEX_CACHE);
+
+        // and to the roles
+        L.pushlightuserdata(&mut *self.acl as *mut acl::Acl as *mut libc::c_void);
+        L.setfield(lua::REGISTRYINDEX, ACL);
+
+        // and to the ignore list
+        L.pushlightuserdata(&mut *self.ignores as *mut ignore::IgnoreList as *mut libc::c_void);
+        L.setfield(lua::REGISTRYINDEX, IGNORE_LIST);
+
+        // and to the built-in CTCP replies
+        L.pushlightuserdata(&*self.ctcp as *config::Ctcp as *mut libc::c_void);
+        L.setfield(lua::REGISTRYINDEX, CTCP_CONFIG);
+
+        // tell the store where plugin data lives
+        store::set_store_dir(L, &self.data_dir);
+
+        // plugin config tables are kept by plugin name for irc.config()
+        L.newtable();
+        L.setfield(lua::REGISTRYINDEX, PLUGIN_CONFIG);
+
         // set up our packages for loading
         L.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
         L.pushcfunction(lua_setup_packages);
@@ -48,57 +182,214 @@ impl PluginManager {
         }
         L.pop(1); // pop error handler
 
-        match io::fs::readdir(&self.plugin_dir) {
+        // shared modules live in the lib dir inside the plugin dir
+        let lib = self.plugin_dir.join("lib");
+        L.getglobal("package");
+        let mut path = lib.join("?.lua").as_vec().to_owned();
+        path.push(';' as u8);
+        path.push_all(lib.join_many(["?", "init.lua"]).as_vec());
+        path.push(';' as u8);
+        L.getfield(-1, "path");
+        path.push_all(L.tobytes(-1).unwrap_or(&[]));
+        L.pop(1);
+        L.pushbytes(path);
+        L.setfield(-2, "path");
+        L.pop(1);
+
+        // directory plugins get their own modules first, see lua_require
+        L.newtable();
+        L.setfield(lua::REGISTRYINDEX, PLUGIN_DIRS);
+        L.newtable();
+        L.setfield(lua::REGISTRYINDEX, PLUGIN_MODULES);
+        L.getglobal("require");
+        L.setfield(lua::REGISTRYINDEX, GLOBAL_REQUIRE);
+        L.pushcfunction(lua_require);
+        L.setglobal("require");
+    }
+
+    // returns every plugin in the plugin dir, sorted by name
+    fn find_plugins(&mut self) -> ~[Plugin] {
+        let paths = match io::fs::readdir(&self.plugin_dir) {
             Err(e) => {
                 println!("Warning: Could not read plugin dir `{}': {}",
                          self.plugin_dir.display(), e);
+                return ~[];
+            }
+            Ok(paths) => paths
+        };
+
+        let mut plugins: ~[Plugin] = ~[];
+        for path in paths.iter() {
+            if path.as_vec() == bytes!(".") || path.as_vec() == bytes!("..") {
+                continue;
             }
-            Ok(paths) => {
-                for path in paths.iter() {
-                    if path.as_vec() == bytes!(".") || path.as_vec() == bytes!("..") {
-                        continue;
+            // a plugin is either foo.lua, or a directory foo/ with an init.lua
+            let (name, file, dir) = if path.is_dir() {
+                let init = path.join("init.lua");
+                if path.filename() == Some(bytes!("lib")) || !init.is_file() {
+                    continue;
+                }
+                (str::from_utf8_lossy(path.filename().unwrap()).into_owned(), init,
+                 Some(path.clone()))
+            } else if path.is_file() && path.extension() == Some(bytes!("lua")) {
+                (str::from_utf8_lossy(path.filestem().unwrap()).into_owned(), path.clone(), None)
+            } else {
+                continue;
+            };
+
+            let mut manifest = match manifest::read(&mut self.state, name.as_slice(), dir.as_ref(),
+                                              &file) {
+                Ok(m) => m,
+                Err(e) => {
+                    println!("Error reading manifest of plugin {}: {}", path.filename_display(), e);
+                    continue;
+                }
+            };
+            // the config file can override whether the plugin is enabled
+            match self.plugin_config.iter().find(|&&(ref n, _)| *n == manifest.name)
+                                    .and_then(|&(_, ref v)| v.lookup("enabled"))
+                                    .and_then(|v| v.get_bool()) {
+                None => (),
+                Some(b) => manifest.enabled = b
+            }
+            if plugins.iter().any(|p| p.manifest.name == manifest.name) {
+                println!("Error loading plugin {}: another plugin is named {}",
+                         path.filename_display(), manifest.name);
+                continue;
+            }
+            plugins.push(Plugin { manifest: manifest, file: file, dir: dir });
+        }
+        plugins.sort_by(|a, b| a.manifest.name.cmp(&b.manifest.name));
+        plugins
+    }
+
+    // loads the plugin at index i after its dependencies, returning whether it loaded
+    fn load_with_deps(&mut self, plugins: &[Plugin], status: &mut [LoadStatus], i: uint) -> bool {
+        match status[i] {
+            Loaded => return true,
+            Failed | Disabled => return false,
+            Loading => {
+                println!("Error loading plugin {}: circular dependency", plugins[i].manifest.name);
+                return false;
+            }
+            Pending => ()
+        }
+
+        let plugin = &plugins[i];
+        status[i] = Loading;
+        for dep in plugin.manifest.depends.iter() {
+            let ok = match plugins.iter().position(|p| p.manifest.name == *dep) {
+                None => {
+                    println!("Not loading plugin {}: dependency {} was not found",
+                             plugin.manifest.name, *dep);
+                    false
+                }
+                Some(j) if status[j] == Disabled => {
+                    println!("Not loading plugin {}: dependency {} is disabled",
+                             plugin.manifest.name, *dep);
+                    false
+                }
+                Some(j) => {
+                    let ok = self.load_with_deps(plugins, status, j);
+                    if !ok {
+                        println!("Not loading plugin {}: dependency {} failed to load",
+                                 plugin.manifest.name, *dep);
                     }
-                    if !path.is_file() { continue; }
-                    if path.extension() == Some(bytes!("lua")) {
-                        // found a plugin
-                        debug!("Loading plugin {}", path.filename_display());
-                        L.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
-                        match L.loadfile(Some(path)) {
-                            Ok(()) => (),
-                            Err(_) => {
-                                println!("Error loading plugin {}: {}", path.filename_display(),
-                                         L.describe(-1));
-                                L.pop(2); // pop error, error handler
-                                continue;
-                            }
-                        }
-                        // call the plugin's chunk with a single argument, the name of the plugin
-                        let name = str::from_utf8_lossy(path.filestem().unwrap());
-                        L.pushstring(name.as_slice());
-                        match L.pcall(1, 0, -3) {
-                            Ok(()) => (),
-                            Err(e) => {
-                                println!("Error running plugin {}: {}: {}",
-                                         path.filename_display(), e, L.describe(-1));
-                                L.pop(2); // pop error, error handler
-                                continue;
-                            }
-                        }
-                        L.pop(1); // pop error handler
+                    ok
+                }
+            };
+            if !ok {
+                status[i] = Failed;
+                return false;
+            }
+        }
+
+        let ok = self.load_plugin(plugin);
+        status[i] = if ok { Loaded } else { Failed };
+        ok
+    }
+
+    // runs the plugin's chunk, returning whether it succeeded
+    fn load_plugin(&mut self, plugin: &Plugin) -> bool {
+        let L = &mut self.state;
+        let name = plugin.manifest.name.as_slice();
+        debug!("Loading plugin {}", name);
+        match plugin.dir {
+            None => (),
+            Some(ref dir) => {
+                // let require find the plugin's own modules
+                L.getfield(lua::REGISTRYINDEX, PLUGIN_DIRS);
+                L.pushbytes(dir.as_vec());
+                L.setfield(-2, name);
+                L.pop(1);
+            }
+        }
+        // charge everything the plugin allocates while loading to it
+        let owner = self.alloc.owner_id(name);
+        let prev = self.alloc.set_current(owner);
+        L.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
+        match L.loadfile(Some(&plugin.file)) {
+            Ok(()) => (),
+            Err(_) => {
+                println!("Error loading plugin {}: {}", name, L.describe(-1));
+                L.pop(2); // pop error, error handler
+                self.alloc.set_current(prev);
+                return false;
+            }
+        }
+        // call the plugin's chunk with the name of the plugin and its config
+        L.pushstring(name);
+        push_plugin_config(L, self.plugin_config.as_slice(), name);
+        match L.pcall(2, 0, -4) {
+            Ok(()) => (),
+            Err(e) => {
+                match self.alloc.take_denied() {
+                    Some((denied, limit)) => {
+                        println!("Error running plugin {}: {} exceeded {}", name,
+                                 self.alloc.name(denied), limit);
+                    }
+                    None => {
+                        println!("Error running plugin {}: {}: {}", name, e, L.describe(-1));
                     }
                 }
+                L.pop(2); // pop error, error handler
+                self.alloc.set_current(prev);
+                return false;
             }
         }
+        L.pop(1); // pop error handler
+        self.alloc.set_current(prev);
+        self.loaded.push((owner, plugin.manifest.version.clone()));
+        true
     }
 
     /// Reloads all plugins
     pub fn reload_plugins(&mut self, conn: &mut irc::conn::Conn) {
+        // give the old plugins a chance to clean up and hand over their state
+        irc::activate_conn(&mut self.state, conn);
+        let handoff = self.unload_plugins();
+        irc::deactivate_conn(&mut self.state);
+
         // do this by setting up a brand new lua::State and re-initializing
-        self.state = lua::State::new();
+        // the old state's timers and pending queries go away with it
+        self.timers.cancel_all();
+        self.queries.clear();
+        // pick up any changes to the plugin config
+        self.config_mtime = mtime_of(&self.config_path);
+        match config::parse_file(&self.config_path) {
+            Ok(root) => self.reread_config(&root),
+            Err(_) => {
+                println!("Warning: Could not re-read config from `{}', keeping the old one",
+                         self.config_path.display());
+            }
+        }
+        self.state = alloc::new_state(&mut *self.alloc);
         self.setup();
 
-        // dispatch the RELOADED event
+        // dispatch the LOADED and RELOADED events
         irc::activate_conn(&mut self.state, conn);
+        self.dispatch_loaded(handoff.as_slice());
+        self.announced = true;
         self.state.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
         self.state.pushcfunction(irc::lua_dispatch_reloaded);
         match self.state.pcall(0, 0, -2) {
@@ -112,9 +403,93 @@ impl PluginManager {
         irc::deactivate_conn(&mut self.state);
     }
 
-    /// Dispatches an IRC event
+    /// Tells the plugins that the bot is quitting, with the connection if it's
+    /// still open. Only the first call does anything, so every way out of
+    /// the bot can call this.
+    pub fn shutdown(&mut self, conn: Option<&mut irc::conn::Conn>) {
+        if self.shut_down {
+            return;
+        }
+        self.shut_down = true;
+        match conn {
+            None => (),
+            Some(conn) => irc::activate_conn(&mut self.state, conn)
+        }
+        self.state.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
+        self.state.pushcfunction(irc::lua_dispatch_shutdown);
+        match self.state.pcall(0, 0, -2) {
+            Ok(()) => (),
+            Err(e) => {
+                println!("Error dispatching SHUTDOWN event: {}: {}", e, self.state.describe(-1));
+                self.state.pop(1);
+            }
+        }
+        self.state.pop(1);
+        irc::deactivate_conn(&mut self.state);
+    }
+
+    // dispatches the UNLOADING event to each plugin, returning the serialized
+    // state handed over by each plugin that returned one, by plugin name
+    fn unload_plugins(&mut self) -> ~[(~str, ~[u8])] {
+        let mut handoff = ~[];
+        for &(owner, _) in self.loaded.iter() {
+            self.state.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
+            self.state.pushcfunction(irc::lua_dispatch_unloading);
+            self.state.pushinteger(owner as int);
+            match self.state.pcall(1, 1, -3) {
+                Ok(()) => {
+                    match self.state.tobytes(-1) {
+                        None => (),
+                        Some(state) => {
+                            handoff.push((self.alloc.name(owner).to_owned(), state.to_owned()));
+                        }
+                    }
+                }
+                Err(e) => {
+                    println!("Error dispatching UNLOADING event: {}: {}", e,
+                             self.state.describe(-1));
+                }
+            }
+            self.state.pop(2); // pop result or error, error handler
+        }
+        handoff
+    }
+
+    // dispatches the LOADED event to each plugin, along with its handed over state
+    fn dispatch_loaded(&mut self, handoff: &[(~str, ~[u8])]) {
+        for &(owner, _) in self.loaded.iter() {
+            let name = self.alloc.name(owner);
+            self.state.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
+            self.state.pushcfunction(irc::lua_dispatch_loaded);
+            self.state.pushinteger(owner as int);
+            match handoff.iter().find(|&&(ref n, _)| n.as_slice() == name) {
+                None => self.state.pushnil(),
+                Some(&(_, ref state)) => self.state.pushbytes(*state)
+            }
+            match self.state.pcall(2, 0, -4) {
+                Ok(()) => (),
+                Err(e) => {
+                    println!("Error dispatching LOADED event: {}: {}", e,
+                             self.state.describe(-1));
+                    self.state.pop(1);
+                }
+            }
+            self.state.pop(1);
+        }
+    }
+
+    /// Dispatches an IRC event. The first connection also sends the plugins
+    /// loaded at startup their LOADED event.
     pub fn dispatch_irc_event(&mut self, conn: &mut irc::conn::Conn, event: &irc::conn::Event) {
+        self.refresh_plugin_config();
         irc::activate_conn(&mut self.state, conn);
+        match *event {
+            irc::conn::Connected if !self.announced => {
+                self.dispatch_loaded([]);
+                self.announced = true;
+            }
+            _ => ()
+        }
         self.state.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
         self.state.pushcfunction(irc::lua_dispatch_event);
         self.state.pushlightuserdata(event as *irc::conn::Event as *mut libc::c_void);
@@ -128,6 +503,234 @@ impl PluginManager {
         self.state.pop(1);
         irc::deactivate_conn(&mut self.state);
     }
+
+    // re-reads the reloadable settings from the parsed config file, keeping the
+    // old value of any section that's broken
+    fn reread_config(&mut self, root: &toml::Value) {
+        match config::plugin_config_from(root) {
+            Ok(c) => self.plugin_config = c,
+            Err(_) => {
+                println!("Warning: Could not re-read plugin config from `{}', keeping the old one",
+                         self.config_path.display());
+            }
+        }
+        match config::roles_from(root) {
+            Ok(roles) => self.acl.set_grants(roles.as_slice()),
+            Err(_) => {
+                println!("Warning: Could not re-read roles from `{}', keeping the old ones",
+                         self.config_path.display());
+            }
+        }
+        self.limiter.set_limits(config::rate_limit_from(root));
+        match config::ctcp_from(root) {
+            Ok(ctcp) => *self.ctcp = ctcp,
+            Err(_) => {
+                println!("Warning: Could not re-read CTCP replies from `{}', keeping the old ones",
+                         self.config_path.display());
+            }
+        }
+        // TODO: this should follow the server once multiple servers are supported
+        match config::servers_from(root) {
+            Ok(servers) => *self.prefixes = prefixes_for(servers.head()),
+            Err(_) => {
+                println!("Warning: Could not re-read prefixes from `{}', keeping the old ones",
+                         self.config_path.display());
+            }
+        }
+    }
+
+    // re-reads the plugin config for irc.config() if the config file has
+    // changed since it was last read
+    fn refresh_plugin_config(&mut self) {
+        let mtime = mtime_of(&self.config_path);
+        if mtime == self.config_mtime {
+            return;
+        }
+        self.config_mtime = mtime;
+        match config::parse_plugin_config(&self.config_path) {
+            Ok(c) => self.plugin_config = c,
+            Err(_) => {
+                println!("Warning: Could not re-read plugin config from `{}', keeping the old one",
+                         self.config_path.display());
+                return;
+            }
+        }
+        for &(owner, _) in self.loaded.iter() {
+            store_plugin_config(&mut self.state, self.plugin_config.as_slice(),
+                                self.alloc.name(owner));
+        }
+    }
+
+    /// Runs the plugin timer with the given id
+    pub fn fire_timer(&mut self, conn: &mut irc::conn::Conn, id: uint) {
+        irc::activate_conn(&mut self.state, conn);
+        self.state.getfield(lua::REGISTRYINDEX, ERROR_HANDLER);
+        self.state.pushcfunction(irc::lua_dispatch_timer);
+        self.state.pushinteger(id as int);
+        match self.state.pcall(1, 0, -3) {
+            Ok(()) => (),
+            Err(e) => {
+                println!("Error dispatching timer: {}: {}", e, self.state.describe(-1));
+                self.state.pop(1);
+            }
+        }
+        self.state.pop(1);
+        irc::deactivate_conn(&mut self.state);
+    }
+
+    /// Returns true if the event is a message from an ignored user. Account
+    /// entries only match if the sender's account is already known.
+    pub fn is_ignored(&mut self, event: &irc::conn::Event) -> bool {
+        use irc::conn::{LineReceived, Line, IRCCmd, IRCAction, IRCCTCP, IRCCTCPReply};
+        let (user, dst) = match *event {
+            LineReceived(Line{ref command, ref args, prefix: Some(ref user)}) => {
+                match *command {
+                    IRCCmd(ref cmd) if !args.is_empty() => match cmd.as_slice() {
+                        "PRIVMSG" | "NOTICE" => (user, args[0].as_slice()),
+                        // the channel is the second argument of an INVITE
+                        "INVITE" if args.len() >= 2 => (user, args[1].as_slice()),
+                        _ => return false
+                    },
+                    IRCAction(ref dst) | IRCCTCP(_, ref dst) | IRCCTCPReply(_, ref dst) => {
+                        (user, dst.as_slice())
+                    }
+                    _ => return false
+                }
+            }
+            _ => return false
+        };
+        let chan = if irc::is_channel(dst) { Some(dst) } else { None };
+        self.ignores.is_ignored(user.raw(), self.acl.account_of(user.raw()), chan)
+    }
+
+    /// Counts a bot command or CTCP query against the rate limits, returning
+    /// true if the event should be dropped. A user over the limit is ignored
+    /// for a while.
+    pub fn rate_limit(&mut self, conn: &mut irc::conn::Conn, event: &irc::conn::Event) -> bool {
+        use irc::conn::{LineReceived, Line, IRCCmd, IRCCTCP};
+        let (user, dst, text) = match *event {
+            LineReceived(Line{command: IRCCmd(ref cmd), ref args, prefix: Some(ref user)})
+                    if cmd.as_slice() == "PRIVMSG" && args.len() == 2 => {
+                (user, args[0].as_slice(), Some(args[1].as_slice()))
+            }
+            // every CTCP query may be answered, so they all count
+            LineReceived(Line{command: IRCCTCP(_, ref dst), prefix: Some(ref user), ..}) => {
+                (user, dst.as_slice(), None)
+            }
+            _ => return false
+        };
+        let private = !irc::is_channel(dst);
+        match text {
+            None => (),
+            Some(text) => {
+                let prefix = if private {
+                    self.prefixes.default_prefix()
+                } else {
+                    self.prefixes.prefix_for(dst)
+                };
+                let bare = private && self.prefixes.bare_private();
+                if command::parse(text, prefix.as_bytes(), conn.me().nick(), bare).is_none() {
+                    return false;
+                }
+            }
+        }
+        if self.acl.check(user.raw(), acl::Owner, None) == acl::Granted {
+            return false;
+        }
+
+        // users are counted by user@host, so changing nicks doesn't help, but a
+        // prefix without one can only be told apart by the whole prefix
+        let key = match (user.user(), user.host()) {
+            (Some(_), Some(_)) => command::lower(user.raw().slice_from(user.nick().len())),
+            _ => command::lower(user.raw())
+        };
+        match self.limiter.check(key.as_slice(), if private { None } else { Some(dst) }) {
+            ratelimit::Allowed => false,
+            ratelimit::ChannelLimited => true,
+            ratelimit::UserLimited => {
+                self.limiter.reset(key.as_slice());
+                let secs = self.limiter.limits().ignore_time;
+                if secs == 0 {
+                    return true;
+                }
+                // the same user@host the limits count by
+                let mask = match (user.user(), user.host()) {
+                    (Some(u), Some(host)) => {
+                        format!("*!{}@{}", str::from_utf8_lossy(u), str::from_utf8_lossy(host))
+                    }
+                    _ => str::from_utf8_lossy(user.raw()).into_owned()
+                };
+                let server = self.ignores.server().to_owned();
+                let entry = ignore::Entry::new(mask.as_slice(), Some(secs as i64),
+                                               Some(server.as_slice()), None);
+                match entry {
+                    Ok(entry) => match self.ignores.add(entry) {
+                        Ok(()) => println!("Ignoring {} for {} seconds, too many commands",
+                                           mask, secs),
+                        Err(e) => println!("Error saving ignore list: {}", e)
+                    },
+                    Err(e) => println!("Error ignoring {}: {}", mask, e)
+                }
+                if self.limiter.limits().notice {
+                    let msg = format!("You're sending commands too quickly, so I'll ignore you \
+                                       for {} seconds", secs);
+                    conn.notice(user.nick(), msg.as_bytes());
+                }
+                true
+            }
+        }
+    }
+
+    /// Returns the ignore list
+    pub fn ignores<'a>(&'a mut self) -> &'a mut ignore::IgnoreList {
+        &mut *self.ignores
+    }
+
+    /// Checks whether the user with the given nick!user@host has a role.
+    /// `chan` is the channel the role is needed in, if any.
+    pub fn check_role(&self, mask: &[u8], role: acl::Role, chan: Option<&[u8]>) -> acl::Check {
+        self.acl.check(mask, role, chan)
+    }
+
+    /// Returns the names of the loaded plugins
+    pub fn plugin_names(&self) -> ~[~str] {
+        self.loaded.iter().map(|&(owner, _)| self.alloc.name(owner).to_owned()).collect()
+    }
+
+    /// Describes the loaded plugins along with their memory usage, or only
+    /// the named plugin if `only` is given, as lines of output
+    pub fn describe_plugins(&self, only: Option<&str>) -> ~[~str] {
+        let loaded = self.loaded.iter().filter(|&&(owner, _)| {
+            only.map_or(true, |name| self.alloc.name(owner) == name)
+        }).collect::<~[&(uint, Option<~str>)]>();
+        let mut lines = ~[];
+        match only {
+            Some(name) if loaded.is_empty() => {
+                lines.push(format!("No plugin named {} is loaded", name));
+                return lines;
+            }
+            Some(_) => (),
+            None => lines.push(format!("Loaded plugins ({}):", loaded.len()))
+        }
+        for &&(owner, ref version) in loaded.iter() {
+            let name = match *version {
+                None => self.alloc.name(owner).to_owned(),
+                Some(ref v) => format!("{} {}", self.alloc.name(owner), *v)
+            };
+            lines.push(match self.alloc.plugin_limit() {
+                None => format!("  {}: {} bytes", name, self.alloc.usage(owner)),
+                Some(n) => format!("  {}: {} of {} bytes", name, self.alloc.usage(owner), n)
+            });
+        }
+        if only.is_some() {
+            return lines;
+        }
+        lines.push(match self.alloc.limit() {
+            None => format!("Total memory: {} bytes", self.alloc.total()),
+            Some(n) => format!("Total memory: {} of {} bytes", self.alloc.total(), n)
+        });
+        lines
+    }
 }
 
 lua_extern! {
@@ -140,9 +743,323 @@ lua_extern! {
         L.pushcfunction(irc::lua_require);
         L.setfield(-2, "irc");
 
+        // irc.db
+        L.pushcfunction(db::lua_require);
+        L.setfield(-2, "irc.db");
+
+        // irc.regex
+        L.pushcfunction(pattern::lua_require);
+        L.setfield(-2, "irc.regex");
+
         L.pop(2);
         0
     }
 }
 
+lua_extern! {
+    // require(), which looks for modules in the directory of a directory
+    // plugin before falling back to the global require. Plugin-local modules
+    // are cached separately for each plugin, so two plugins may each have a
+    // module of the same name.
+    unsafe fn lua_require(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: module name
+
+        // the module and plugin names stay on the stack, as anything owned here
+        // would leak if the module raised an error
+        L.checkstring(1);
+        L.settop(1);
+        {
+            let plugin = {
+                let alloc = alloc::get(L);
+                alloc.name(alloc.current()).to_owned()
+            };
+            L.pushstring(plugin.as_slice());
+        }
+
+        L.getfield(lua::REGISTRYINDEX, PLUGIN_DIRS);
+        L.pushvalue(2);
+        L.gettable(-2);
+        let dir = L.gettop();
+        if L.isstring(dir) {
+            L.getfield(lua::REGISTRYINDEX, PLUGIN_MODULES);
+            L.pushvalue(2);
+            L.gettable(-2);
+            if L.isnil(-1) {
+                L.pop(1);
+                L.newtable();
+                L.pushvalue(2);
+                L.pushvalue(-2);
+                L.settable(-4);
+            }
+            let loaded = L.gettop();
+            L.pushvalue(1);
+            L.gettable(loaded);
+            if !L.isnil(-1) {
+                return 1;
+            }
+            L.pop(1);
+
+            // returns whether the module's chunk is now on the stack
+            let found = {
+                let dir = Path::new(L.tobytes(dir).unwrap());
+                let rel = L.tostring(1).unwrap().replace(".", "/");
+                let file = dir.join(format!("{}.lua", rel));
+                let file = if file.is_file() { Some(file) } else {
+                    let file = dir.join_many([rel.as_slice(), "init.lua"]);
+                    if file.is_file() { Some(file) } else { None }
+                };
+                match file {
+                    None => Ok(false),
+                    Some(file) => match L.loadfile(Some(&file)) {
+                        Ok(()) => Ok(true),
+                        Err(_) => {
+                            let err = L.describe(-1);
+                            Err(format!("error loading module '{}' from file '{}':\n\t{}",
+                                        L.tostring(1).unwrap(), file.display(), err))
+                        }
+                    }
+                }
+            };
+            match found {
+                Ok(false) => (),
+                Ok(true) => {
+                    L.pushvalue(1);
+                    L.call(1, 1);
+                    if L.isnil(-1) {
+                        L.pop(1);
+                        L.pushboolean(true);
+                    }
+                    L.pushvalue(1);
+                    L.pushvalue(-2);
+                    L.settable(loaded);
+                    return 1;
+                }
+                Err(msg) => raise(L, msg)
+            }
+        }
+
+        L.getfield(lua::REGISTRYINDEX, GLOBAL_REQUIRE);
+        L.pushvalue(1);
+        L.call(1, 1);
+        1
+    }
+}
+
+// returns the command prefixes configured for the server
+fn prefixes_for(server: Option<&config::Server>) -> command::Prefixes {
+    match server {
+        None => command::Prefixes::new("!", [], false),
+        Some(s) => command::Prefixes::new(s.command_prefix.as_slice(),
+                                          s.channel_prefixes.as_slice(),
+                                          s.bare_private_commands)
+    }
+}
+
+// returns when the file was last modified, or 0 if that isn't known
+fn mtime_of(path: &Path) -> u64 {
+    match path.stat() {
+        Ok(st) => st.modified,
+        Err(_) => 0
+    }
+}
+
+/// Pushes the config table for the named plugin, also recording a separate
+/// copy for irc.config(). Plugins without a [plugins.<name>] table get an
+/// empty one.
+fn push_plugin_config(L: &mut lua::State, config: &[(~str, toml::Value)], name: &str) {
+    store_plugin_config(L, config, name);
+    match config.iter().find(|&&(ref n, _)| n.as_slice() == name) {
+        None => L.newtable(),
+        Some(&(_, ref val)) => push_toml(L, val)
+    }
+}
+
+/// Records the config table for the named plugin, for irc.config()
+fn store_plugin_config(L: &mut lua::State, config: &[(~str, toml::Value)], name: &str) {
+    L.getfield(lua::REGISTRYINDEX, PLUGIN_CONFIG);
+    match config.iter().find(|&&(ref n, _)| n.as_slice() == name) {
+        None => L.newtable(),
+        Some(&(_, ref val)) => push_toml(L, val)
+    }
+    L.setfield(-2, name);
+    L.pop(1);
+}
+
+/// Pushes the Lua equivalent of a TOML value
+fn push_toml(L: &mut lua::State, val: &toml::Value) {
+    match *val {
+        toml::NoValue => L.pushnil(),
+        toml::Boolean(b) => L.pushboolean(b),
+        toml::PosInt(n) => L.pushnumber(n as f64),
+        toml::NegInt(n) => L.pushnumber(-(n as f64)),
+        toml::Float(n) => L.pushnumber(n),
+        toml::String(ref s) => L.pushstring(s.as_slice()),
+        toml::Datetime(y, mo, d, h, mi, s) => {
+            L.pushstring(format!("{:04u}-{:02u}-{:02u}T{:02u}:{:02u}:{:02u}Z",
+                                 y, mo, d, h, mi, s).as_slice());
+        }
+        toml::Array(ref vals) | toml::TableArray(ref vals) => {
+            L.createtable(vals.len() as i32, 0);
+            for (i, v) in vals.iter().enumerate() {
+                push_toml(L, v);
+                L.rawseti(-2, i as i32 + 1);
+            }
+        }
+        toml::Table(_, ref table) => {
+            L.createtable(0, table.len() as i32);
+            for (k, v) in table.iter() {
+                push_toml(L, v);
+                L.setfield(-2, k.as_slice());
+            }
+        }
+    }
+}
+
+/// Pushes the config table of the plugin that's currently running
+unsafe fn push_current_config(L: &mut lua::ExternState) {
+    L.getfield(lua::REGISTRYINDEX, PLUGIN_CONFIG);
+    {
+        let alloc = alloc::get(L);
+        L.getfield(-1, alloc.name(alloc.current()));
+    }
+    L.remove(-2);
+    if L.isnil(-1) {
+        L.pop(1);
+        L.newtable();
+        return;
+    }
+    // each caller gets its own copy, so changes to it don't leak
+    copy_config(L, -1);
+    L.remove(-2);
+}
+
+// pushes a deep copy of the config table at the given index
+unsafe fn copy_config(L: &mut lua::ExternState, idx: i32) {
+    let idx = if idx < 0 { L.gettop() + idx + 1 } else { idx };
+    L.newtable();
+    L.pushnil();
+    while L.next(idx) {
+        if L.istable(-1) {
+            copy_config(L, -1);
+            L.remove(-2);
+        }
+        L.pushvalue(-2); // copy the key
+        L.insert(-2); // move it behind the value
+        L.settable(-4);
+    }
+}
+
+/// Retrieves the TimerClient from inside a Lua callback
+unsafe fn get_timers(L: &mut lua::ExternState) -> &'static mut timer::TimerClient {
+    L.getfield(lua::REGISTRYINDEX, TIMER_CLIENT);
+    let ptr = L.touserdata(-1) as *mut timer::TimerClient;
+    L.pop(1);
+    if ptr.is_null() {
+        L.errorstr("could not retrieve timer source");
+    }
+    &mut *ptr
+}
+
+/// Retrieves the query Collector from inside a Lua callback
+unsafe fn get_queries(L: &mut lua::ExternState) -> &'static mut query::Collector {
+    L.getfield(lua::REGISTRYINDEX, QUERY_COLLECTOR);
+    let ptr = L.touserdata(-1) as *mut query::Collector;
+    L.pop(1);
+    if ptr.is_null() {
+        L.errorstr("could not retrieve query collector");
+    }
+    &mut *ptr
+}
+
+/// Retrieves the command Prefixes from inside a Lua callback
+unsafe fn get_prefixes(L: &mut lua::ExternState) -> &'static command::Prefixes {
+    L.getfield(lua::REGISTRYINDEX, COMMAND_PREFIXES);
+    let ptr = L.touserdata(-1) as *command::Prefixes;
+    L.pop(1);
+    if ptr.is_null() {
+        L.errorstr("could not retrieve command prefixes");
+    }
+    &*ptr
+}
+
+/// Retrieves the regex Cache from inside a Lua callback
+unsafe fn get_regexes(L: &mut lua::ExternState) -> &'static mut pattern::Cache {
+    L.getfield(lua::REGISTRYINDEX, REGEX_CACHE);
+    let ptr = L.touserdata(-1) as *mut pattern::Cache;
+    L.pop(1);
+    if ptr.is_null() {
+        L.errorstr("could not retrieve regex cache");
+    }
+    &mut *ptr
+}
+
+/// Retrieves the IgnoreList from inside a Lua callback
+unsafe fn get_ignores(L: &mut lua::ExternState) -> &'static mut ignore::IgnoreList {
+    L.getfield(lua::REGISTRYINDEX, IGNORE_LIST);
+    let ptr = L.touserdata(-1) as *mut ignore::IgnoreList;
+    L.pop(1);
+    if ptr.is_null() {
+        L.errorstr("could not retrieve ignore list");
+    }
+    &mut *ptr
+}
+
+/// Retrieves the built-in CTCP replies from inside a Lua callback
+unsafe fn get_ctcp_config(L: &mut lua::ExternState) -> &'static config::Ctcp {
+    L.getfield(lua::REGISTRYINDEX, CTCP_CONFIG);
+    let ptr = L.touserdata(-1) as *config::Ctcp;
+    L.pop(1);
+    if ptr.is_null() {
+        L.errorstr("could not retrieve CTCP config");
+    }
+    &*ptr
+}
+
+/// Retrieves the Acl from inside a Lua callback
+unsafe fn get_acl(L: &mut lua::ExternState) -> &'static mut acl::Acl {
+    L.getfield(lua::REGISTRYINDEX, ACL);
+    let ptr = L.touserdata(-1) as *mut acl::Acl;
+    L.pop(1);
+    if ptr.is_null() {
+        L.errorstr("could not retrieve acl");
+    }
+    &mut *ptr
+}
+
+/// Raises a Lua error with the given message. The message is freed first, as
+/// raising an error unwinds past Rust destructors.
+unsafe fn raise(L: &mut lua::ExternState, msg: ~str) -> ! {
+    {
+        let msg = msg;
+        L.pushstring(msg.as_slice());
+    }
+    L.error()
+}
+
+/// Raises a Lua error about argument narg with the given message, freeing
+/// the message first like raise.
+unsafe fn raise_arg(L: &mut lua::ExternState, narg: i32, msg: ~str) -> ! {
+    {
+        let msg = msg;
+        L.pushstring(msg.as_slice());
+    }
+    // the copy on the stack outlives the error
+    let msg: &'static str = ::std::cast::transmute(L.tostring(-1).unwrap());
+    L.argerror(narg, msg)
+}
+
+pub mod acl;
+mod alloc;
+pub mod command;
+pub mod ctcp;
+mod db;
+pub mod ignore;
+mod manifest;
+mod numerics;
+mod pattern;
+mod query;
+mod ratelimit;
+mod regex;
+mod store;
 mod irc;
+
@@ -0,0 +1,491 @@
+//! Regular expressions for plugins
+//!
+//! Vends a package named 'irc.regex', which is also available as irc.regex.
+//! Patterns are regular expressions rather than Lua patterns, with the syntax
+//! described in the regex module.
+//!
+//! regex.compile(pattern): Returns a compiled Regex, or raises an error if the
+//!     pattern is invalid
+//! regex.match(s, re, init): Returns the captures of the first match in s,
+//!     starting at byte init (default 1), or nil if there is none
+//! regex.gmatch(s, re): Returns an iterator over the captures of each match
+//!     in s, for use with a generic for
+//! regex.gsub(s, re, repl, n): Replaces the first n matches in s (default
+//!     all), returning the new string and the number of replacements
+//!
+//! re may be a compiled Regex or a pattern string. Compiled Regexes also have
+//! match, gmatch and gsub methods, which take the same arguments minus re,
+//! e.g. re:match(s).
+//!
+//! Like their string library counterparts, match and gmatch produce the
+//! capture groups of a match, or the whole match if the pattern has no groups.
+//! Groups that didn't participate in the match are false.
+//!
+//! The repl argument of gsub may be a string, in which $0 through $9 (or ${n}
+//! for larger numbers) stand for capture groups and $$ for a literal $. It may
+//! also be a table, which is indexed by the first capture without invoking
+//! metamethods, or a function, which is called with the captures. If the table
+//! or function gives false or nil, the match is kept unchanged.
+//!
+//! Each distinct pattern is compiled once and shared by every plugin that
+//! uses it.
+
+#[allow(uppercase_variables)];
+
+use lua;
+use super::{get_regexes, raise_arg};
+use super::regex::{Captures, Regex};
+use std::{cmp, mem, ptr, str, vec};
+
+static REGEX_MT: &'static str = "irc.Regex";
+
+/// The compiled regexes in use by plugins, each shared by every user of the
+/// same pattern
+pub struct Cache {
+    // entries are boxed so references stay valid as the cache grows
+    priv entries: ~[Option<~Entry>]
+}
+
+struct Entry {
+    source: ~str,
+    regex: Regex,
+    refs: uint
+}
+
+impl Cache {
+    pub fn new() -> Cache {
+        Cache { entries: ~[] }
+    }
+
+    /// Returns the id of the compiled pattern, compiling it if it isn't
+    /// already in use. Each call must be balanced by a call to release.
+    pub fn acquire(&mut self, source: &str) -> Result<uint, ~str> {
+        for (i, entry) in self.entries.mut_iter().enumerate() {
+            match *entry {
+                Some(ref mut e) if e.source.as_slice() == source => {
+                    e.refs += 1;
+                    return Ok(i);
+                }
+                _ => ()
+            }
+        }
+        let regex = match Regex::new(source) {
+            Ok(r) => r,
+            Err(e) => return Err(e)
+        };
+        let entry = Some(~Entry { source: source.to_owned(), regex: regex, refs: 1 });
+        match self.entries.iter().position(|e| e.is_none()) {
+            Some(i) => {
+                self.entries[i] = entry;
+                Ok(i)
+            }
+            None => {
+                self.entries.push(entry);
+                Ok(self.entries.len() - 1)
+            }
+        }
+    }
+
+    /// Releases a reference to the compiled pattern with the given id
+    pub fn release(&mut self, id: uint) {
+        let unused = match self.entries[id] {
+            None => false,
+            Some(ref mut e) => {
+                e.refs -= 1;
+                e.refs == 0
+            }
+        };
+        if unused {
+            self.entries[id] = None;
+        }
+    }
+
+    /// Returns the compiled pattern with the given id
+    pub fn get<'a>(&'a self, id: uint) -> &'a Regex {
+        match self.entries[id] {
+            None => fail!("regex {} is not in use", id),
+            Some(ref e) => &e.regex
+        }
+    }
+
+    /// Returns the source of the pattern with the given id
+    pub fn source<'a>(&'a self, id: uint) -> &'a str {
+        match self.entries[id] {
+            None => fail!("regex {} is not in use", id),
+            Some(ref e) => e.source.as_slice()
+        }
+    }
+}
+
+lua_extern_pub! {
+    unsafe fn lua_require(L: &mut lua::ExternState) -> i32 {
+        // 1 argument is passed: modname
+
+        push_module(L);
+        1
+    }
+}
+
+/// Pushes the regex module table
+pub unsafe fn push_module(L: &mut lua::ExternState) {
+    if L.newmetatable(REGEX_MT) {
+        L.newtable();
+        L.registerlib(None, [
+            ("match", lua_re_match),
+            ("gmatch", lua_re_gmatch),
+            ("gsub", lua_re_gsub)
+        ]);
+        L.setfield(-2, "__index");
+        L.pushcfunction(lua_re_gc);
+        L.setfield(-2, "__gc");
+        L.pushcfunction(lua_re_tostring);
+        L.setfield(-2, "__tostring");
+    }
+    L.pop(1);
+
+    L.newtable();
+    L.registerlib(None, [
+        ("compile", lua_compile),
+        ("match", lua_match),
+        ("gmatch", lua_gmatch),
+        ("gsub", lua_gsub)
+    ]);
+}
+
+/// Pushes the compiled Regex for the value at the given index, which may be a
+/// Regex or a pattern string
+pub unsafe fn push_regex(L: &mut lua::ExternState, idx: i32) {
+    if L.type_(idx) == Some(lua::Type::Userdata) {
+        L.checkudata(idx, REGEX_MT);
+        L.pushvalue(idx);
+        return;
+    }
+    let id = match get_regexes(L).acquire(L.checkstring(idx).unwrap_or("")) {
+        Ok(id) => id,
+        Err(e) => raise_arg(L, idx, format!("invalid regex: {}", e))
+    };
+    let ud = L.newuserdata(mem::size_of::<uint>()) as *mut uint;
+    *ud = id;
+    L.getfield(lua::REGISTRYINDEX, REGEX_MT);
+    L.setmetatable(-2);
+}
+
+/// Returns the cache id of the Regex at the given index
+pub unsafe fn regex_id(L: &mut lua::ExternState, idx: i32) -> uint {
+    *(L.checkudata(idx, REGEX_MT) as *uint)
+}
+
+// replaces the value at the given index with its compiled Regex, which keeps
+// it alive while it's used, and returns it
+unsafe fn check_regex(L: &mut lua::ExternState, idx: i32) -> &'static Regex {
+    push_regex(L, idx);
+    L.replace(idx);
+    get_regexes(L).get(regex_id(L, idx))
+}
+
+// returns the subject string at the given index
+unsafe fn subject(L: &mut lua::ExternState, idx: i32) -> ~str {
+    str::from_utf8_lossy(L.checkbytes(idx)).into_owned()
+}
+
+/// Pushes the capture groups of a match against text, or the whole match if
+/// the regex has no groups, returning the number of values pushed
+pub unsafe fn push_captures(L: &mut lua::ExternState, caps: &Captures, text: &str) -> i32 {
+    if caps.len() <= 1 {
+        let (s, e) = caps.pos(0).unwrap();
+        L.pushstring(text.slice(s, e));
+        return 1;
+    }
+    for i in range(1, caps.len()) {
+        match caps.pos(i) {
+            None => L.pushboolean(false),
+            Some((s, e)) => L.pushstring(text.slice(s, e))
+        }
+    }
+    caps.len() as i32 - 1
+}
+
+// moves a Regex method's receiver after the subject, to match the module functions
+unsafe fn method_args(L: &mut lua::ExternState) {
+    L.checkudata(1, REGEX_MT);
+    L.checkany(2);
+    L.pushvalue(1);
+    L.remove(1);
+    L.insert(2);
+}
+
+// expands the $ references of a gsub replacement string
+fn expand(repl: &str, caps: &Captures, text: &str, out: &mut ~str) {
+    let group = |i: uint, out: &mut ~str| {
+        if i < caps.len() {
+            match caps.pos(i) {
+                None => (),
+                Some((s, e)) => out.push_str(text.slice(s, e))
+            }
+        }
+    };
+    let bytes = repl.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != '$' as u8 || i + 1 == bytes.len() {
+            // copy everything up to the next $
+            let next = bytes.slice_from(i + 1).iter().position(|&b| b == '$' as u8)
+                            .map_or(bytes.len(), |n| i + 1 + n);
+            out.push_str(repl.slice(i, next));
+            i = next;
+            continue;
+        }
+        let c = bytes[i + 1];
+        if c == '$' as u8 {
+            out.push_char('$');
+            i += 2;
+        } else if c >= '0' as u8 && c <= '9' as u8 {
+            group((c - '0' as u8) as uint, out);
+            i += 2;
+        } else if c == '{' as u8 {
+            match bytes.slice_from(i + 2).iter().position(|&b| b == '}' as u8) {
+                Some(n) => {
+                    match from_str::<uint>(repl.slice(i + 2, i + 2 + n)) {
+                        Some(g) => group(g, out),
+                        None => out.push_str(repl.slice(i, i + 3 + n))
+                    }
+                    i += 3 + n;
+                }
+                None => {
+                    out.push_str(repl.slice_from(i));
+                    i = bytes.len();
+                }
+            }
+        } else {
+            out.push_char('$');
+            i += 1;
+        }
+    }
+}
+
+// regex.match; args: s, re, init
+unsafe fn match_(L: &mut lua::ExternState) -> i32 {
+    // check the arguments before copying the subject, which an error would leak
+    L.checkbytes(1);
+    let re = check_regex(L, 2);
+    let init = L.optinteger(3, 1);
+    let text = subject(L, 1);
+    let len = text.len() as int;
+    let mut start = if init > 0 {
+        cmp::min(init - 1, len)
+    } else if init < 0 {
+        cmp::max(len + init, 0)
+    } else {
+        0
+    } as uint;
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+
+    let text = text.slice_from(start);
+    match re.captures(text) {
+        None => {
+            L.pushnil();
+            1
+        }
+        Some(caps) => push_captures(L, &caps, text)
+    }
+}
+
+// regex.gmatch; args: s, re
+unsafe fn gmatch(L: &mut lua::ExternState) -> i32 {
+    let re = check_regex(L, 2);
+    L.settop(2);
+    {
+        let text = subject(L, 1);
+        L.pushstring(text.as_slice());
+    }
+    L.pushvalue(2);
+    L.pushinteger(0);
+    // the record of tried states is shared by the whole scan
+    let words = re.visited_len(L.objlen(3) as uint); // the subject's length
+    let visited = L.newuserdata(words * mem::size_of::<u32>()) as *mut u32;
+    ptr::set_memory(visited, 0, words);
+    L.pushcclosure(lua_gmatch_next, 4);
+    1
+}
+
+// regex.gsub; args: s, re, repl, n
+unsafe fn gsub(L: &mut lua::ExternState) -> i32 {
+    L.checkbytes(1);
+    let re = check_regex(L, 2);
+    let is_string = match L.type_(3) {
+        Some(lua::Type::String) | Some(lua::Type::Number) => true,
+        Some(lua::Type::Table) | Some(lua::Type::Function) => false,
+        _ => {
+            L.argerror(3, "string/function/table expected");
+        }
+    };
+    let max = L.optinteger(4, -1);
+    L.settop(4);
+
+    // an error from the replacement is left on the stack and only raised once
+    // the buffers here have been freed
+    let failed = {
+        let text = subject(L, 1);
+        let text = text.as_slice();
+        let repl = if is_string { Some(L.tostring(3).unwrap().to_owned()) } else { None };
+        let mut out = ~"";
+        let mut last = 0;
+        let mut count = 0;
+        let mut failed = false;
+        for caps in re.captures_iter(text) {
+            if max >= 0 && count >= max {
+                break;
+            }
+            let (s, e) = caps.pos(0).unwrap();
+            out.push_str(text.slice(last, s));
+            last = e;
+            count += 1;
+            match repl {
+                Some(ref repl) => {
+                    expand(repl.as_slice(), &caps, text, &mut out);
+                    continue;
+                }
+                None => ()
+            }
+
+            if L.istable(3) {
+                // index the table with the first capture
+                push_captures(L, &caps, text);
+                L.settop(5);
+                L.rawget(3);
+            } else {
+                L.pushvalue(3);
+                let n = push_captures(L, &caps, text);
+                if L.pcall(n, 1, 0).is_err() {
+                    failed = true;
+                    break;
+                }
+            }
+            if !L.toboolean(-1) {
+                out.push_str(text.slice(s, e));
+            } else if L.isstring(-1) {
+                out.push_str(str::from_utf8_lossy(L.tobytes(-1).unwrap()).as_slice());
+            } else {
+                let msg = format!("invalid replacement value (a {})", L.typename(-1));
+                L.pushstring(msg.as_slice());
+                failed = true;
+                break;
+            }
+            L.settop(4);
+        }
+        if !failed {
+            out.push_str(text.slice_from(last));
+            L.pushstring(out.as_slice());
+            L.pushinteger(count);
+        }
+        failed
+    };
+    if failed {
+        L.error();
+    }
+    2
+}
+
+lua_extern! {
+    unsafe fn lua_compile(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: pattern
+
+        L.checkstring(1);
+        push_regex(L, 1);
+        1
+    }
+
+    unsafe fn lua_match(L: &mut lua::ExternState) -> i32 {
+        // 2-3 args: s, re, init
+
+        match_(L)
+    }
+
+    unsafe fn lua_gmatch(L: &mut lua::ExternState) -> i32 {
+        // 2 args: s, re
+
+        gmatch(L)
+    }
+
+    unsafe fn lua_gmatch_next(L: &mut lua::ExternState) -> i32 {
+        // upvalues: subject, regex, position, record of tried states
+
+        // the subject upvalue outlives this call
+        let text: &'static str = mem::transmute(L.tostring(lua::upvalueindex(1)).unwrap_or(""));
+        let re = get_regexes(L).get(regex_id(L, lua::upvalueindex(2)));
+        let pos = L.tointeger(lua::upvalueindex(3)) as uint;
+        if pos > text.len() {
+            return 0;
+        }
+
+        let visited = L.touserdata(lua::upvalueindex(4)) as *mut u32;
+        let found = vec::raw::mut_buf_as_slice(visited, re.visited_len(text.len()),
+                                               |v| re.captures_from(text, pos, v));
+        match found {
+            None => {
+                L.pushinteger(text.len() as int + 1);
+                L.replace(lua::upvalueindex(3));
+                0
+            }
+            Some(caps) => {
+                let (s, e) = caps.pos(0).unwrap();
+                // step past an empty match so the next call makes progress
+                let next = if s < e {
+                    e
+                } else if e < text.len() {
+                    text.char_range_at(e).next
+                } else {
+                    text.len() + 1
+                };
+                L.pushinteger(next as int);
+                L.replace(lua::upvalueindex(3));
+                push_captures(L, &caps, text)
+            }
+        }
+    }
+
+    unsafe fn lua_gsub(L: &mut lua::ExternState) -> i32 {
+        // 3-4 args: s, re, repl, n
+
+        gsub(L)
+    }
+
+    unsafe fn lua_re_match(L: &mut lua::ExternState) -> i32 {
+        // 2-3 args: re, s, init
+
+        method_args(L);
+        match_(L)
+    }
+
+    unsafe fn lua_re_gmatch(L: &mut lua::ExternState) -> i32 {
+        // 2 args: re, s
+
+        method_args(L);
+        gmatch(L)
+    }
+
+    unsafe fn lua_re_gsub(L: &mut lua::ExternState) -> i32 {
+        // 3-4 args: re, s, repl, n
+
+        method_args(L);
+        gsub(L)
+    }
+
+    unsafe fn lua_re_gc(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: re
+
+        let id = regex_id(L, 1);
+        get_regexes(L).release(id);
+        0
+    }
+
+    unsafe fn lua_re_tostring(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: re
+
+        let id = regex_id(L, 1);
+        L.pushstring(format!("regex: {}", get_regexes(L).source(id)).as_slice());
+        1
+    }
+}
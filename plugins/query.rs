@@ -0,0 +1,448 @@
+//! Collectors for multi-line query replies
+//!
+//! Queries such as WHOIS are answered by the server with a series of numeric
+//! replies followed by an end marker. The Collector watches incoming lines for
+//! the replies to each pending query and assembles them into a single result,
+//! which is handed back once the end marker (or an error) arrives.
+//!
+//! Replies are matched to the query by the channel or mask they name. The
+//! NAMES reply the server sends on its own when the bot joins a channel is
+//! skipped, so it isn't mistaken for the answer to a NAMES query.
+
+#[allow(uppercase_variables)];
+
+use lua;
+use irc::conn;
+use std::str;
+use std::default::Default;
+use super::acl::glob_match;
+use super::irc::is_channel;
+
+/// The kind of a query
+#[deriving(Eq)]
+pub enum Kind {
+    Whois,
+    Who,
+    Names,
+    BanList
+}
+
+/// The aggregated reply to a WHOIS
+#[deriving(Default)]
+pub struct WhoisInfo {
+    nick: ~[u8],
+    user: Option<~[u8]>,
+    host: Option<~[u8]>,
+    real: Option<~[u8]>,
+    server: Option<~[u8]>,
+    server_info: Option<~[u8]>,
+    operator: bool,
+    idle: Option<uint>,
+    signon: Option<uint>,
+    channels: ~[~[u8]],
+    account: Option<~[u8]>,
+    away: Option<~[u8]>
+}
+
+/// A single line of a WHO reply
+pub struct WhoEntry {
+    channel: ~[u8],
+    user: ~[u8],
+    host: ~[u8],
+    server: ~[u8],
+    nick: ~[u8],
+    flags: ~[u8],
+    hops: Option<uint>,
+    real: ~[u8]
+}
+
+/// A single entry of a ban list
+pub struct BanEntry {
+    mask: ~[u8],
+    setter: Option<~[u8]>,
+    time: Option<uint>
+}
+
+/// The aggregated reply to a query
+pub enum Reply {
+    WhoisReply(WhoisInfo),
+    WhoReply(~[WhoEntry]),
+    NamesReply(~[~[u8]]),
+    BanListReply(~[BanEntry])
+}
+
+/// A query that has finished, successfully or not
+pub struct Completed {
+    /// The registry reference for the callback or coroutine to deliver to
+    callback: i32,
+    /// The allocator owner of the plugin that made the query
+    owner: uint,
+    reply: Reply,
+    error: Option<~[u8]>
+}
+
+struct Query {
+    kind: Kind,
+    target: ~[u8], // lowercased
+    callback: i32,
+    owner: uint,
+    reply: Reply
+}
+
+/// Tracks pending queries
+pub struct Collector {
+    priv pending: ~[Query],
+    // lowercased channels we've joined whose NAMES reply hasn't finished
+    priv joining: ~[~[u8]]
+}
+
+impl Collector {
+    pub fn new() -> Collector {
+        Collector { pending: ~[], joining: ~[] }
+    }
+
+    /// Starts tracking a new query. The caller is responsible for sending it.
+    pub fn start(&mut self, kind: Kind, target: &[u8], callback: i32, owner: uint) {
+        let reply = match kind {
+            Whois => WhoisReply(WhoisInfo { nick: target.to_owned(), ..Default::default() }),
+            Who => WhoReply(~[]),
+            Names => NamesReply(~[]),
+            BanList => BanListReply(~[])
+        };
+        self.pending.push(Query {
+            kind: kind,
+            target: lower(target),
+            callback: callback,
+            owner: owner,
+            reply: reply
+        });
+    }
+
+    /// Forgets every pending query without completing them
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Fails every pending query with the given error. Used when the
+    /// connection is lost, so it also forgets any channels being joined.
+    pub fn abort_all(&mut self, error: &str) -> ~[Completed] {
+        self.joining.clear();
+        let pending = ::std::mem::replace(&mut self.pending, ~[]);
+        pending.move_iter().map(|q| {
+            Completed { callback: q.callback, owner: q.owner, reply: q.reply,
+                        error: Some(error.as_bytes().to_owned()) }
+        }).collect()
+    }
+
+    /// Feeds a received line to the pending queries, returning any that
+    /// completed as a result. me is the bot's current nick.
+    pub fn feed(&mut self, line: &conn::Line, me: &[u8]) -> ~[Completed] {
+        let code = match line.command {
+            conn::IRCCode(code) => code,
+            conn::IRCCmd(ref cmd) if cmd.as_slice() == "JOIN" && line.args.len() >= 1 => {
+                match line.prefix {
+                    Some(ref user) if lower(user.nick()) == lower(me) => {
+                        // the server follows our own JOIN with a NAMES reply
+                        self.joining.push(lower(line.args[0]));
+                    }
+                    _ => ()
+                }
+                return ~[];
+            }
+            _ => return ~[]
+        };
+        // the first argument of a numeric is always our own nick
+        let args = if line.args.len() > 1 { line.args.slice_from(1) } else { return ~[] };
+        // RPL_NAMREPLY leads with the channel type before the channel
+        let key = if code == 353 && args.len() > 1 { lower(args[1]) } else { lower(args[0]) };
+
+        if (code == 353 || code == 366) && self.joining.contains(&key) {
+            // this is the reply to our JOIN, not to a query
+            if code == 366 {
+                self.joining.retain(|c| *c != key);
+            }
+            return ~[];
+        }
+
+        let mut done = ~[];
+        let mut who_fed = false;
+        let mut i = 0;
+        while i < self.pending.len() {
+            let finished = {
+                let q = &mut self.pending[i];
+                match q.kind {
+                    Whois if q.target == key => feed_whois(q, code, args),
+                    // an entry goes to the oldest WHO whose channel or mask it matches
+                    Who if code != 352 || (!who_fed && who_matches(q, args)) => {
+                        if code == 352 {
+                            who_fed = true;
+                        }
+                        feed_who(q, code, args, key.as_slice())
+                    }
+                    Names if q.target == key => feed_names(q, code, args),
+                    BanList if q.target == key => feed_banlist(q, code, args),
+                    _ => None
+                }
+            };
+            match finished {
+                None => i += 1,
+                Some(error) => {
+                    let q = self.pending.remove(i).unwrap();
+                    done.push(Completed { callback: q.callback, owner: q.owner, reply: q.reply,
+                                          error: error });
+                }
+            }
+        }
+        done
+    }
+}
+
+// each feed function returns Some(error) when the query is finished
+
+fn feed_whois(q: &mut Query, code: uint, args: &[~[u8]]) -> Option<Option<~[u8]>> {
+    let info = match q.reply {
+        WhoisReply(ref mut info) => info,
+        _ => return None
+    };
+    match code {
+        311 => {
+            // nick user host * :real
+            info.nick = args[0].clone();
+            info.user = args.get(1).map(|a| a.clone());
+            info.host = args.get(2).map(|a| a.clone());
+            info.real = args.get(4).map(|a| a.clone());
+        }
+        312 => {
+            // nick server :info
+            info.server = args.get(1).map(|a| a.clone());
+            info.server_info = args.get(2).map(|a| a.clone());
+        }
+        313 => info.operator = true,
+        317 => {
+            // nick idle signon :seconds idle, signon time
+            info.idle = args.get(1).and_then(|a| parse_uint(*a));
+            info.signon = args.get(2).and_then(|a| parse_uint(*a));
+        }
+        319 => {
+            // nick :channels
+            match args.get(1) {
+                None => (),
+                Some(chans) => {
+                    for chan in chans.split(|&b| b == ' ' as u8).filter(|c| !c.is_empty()) {
+                        info.channels.push(chan.to_owned());
+                    }
+                }
+            }
+        }
+        330 => info.account = args.get(1).map(|a| a.clone()),
+        301 => info.away = args.get(1).map(|a| a.clone()),
+        318 => return Some(None),
+        401 | 402 => return Some(Some(last(args))),
+        _ => ()
+    }
+    None
+}
+
+fn feed_who(q: &mut Query, code: uint, args: &[~[u8]], key: &[u8]) -> Option<Option<~[u8]>> {
+    let entries = match q.reply {
+        WhoReply(ref mut entries) => entries,
+        _ => return None
+    };
+    match code {
+        352 if args.len() >= 7 => {
+            // channel user host server nick flags :hops real
+            let (hops, real) = {
+                let tail = args[6].as_slice();
+                match tail.iter().position(|&b| b == ' ' as u8) {
+                    None => (parse_uint(tail), ~[]),
+                    Some(idx) => {
+                        (parse_uint(tail.slice_to(idx)), tail.slice_from(idx+1).to_owned())
+                    }
+                }
+            };
+            entries.push(WhoEntry {
+                channel: args[0].clone(),
+                user: args[1].clone(),
+                host: args[2].clone(),
+                server: args[3].clone(),
+                nick: args[4].clone(),
+                flags: args[5].clone(),
+                hops: hops,
+                real: real
+            });
+        }
+        315 if q.target.as_slice() == key => return Some(None),
+        403 | 442 | 481 if q.target.as_slice() == key => return Some(Some(last(args))),
+        _ => ()
+    }
+    None
+}
+
+// returns true if the WHO entry in args answers the WHO query
+// entries for a channel name it, and otherwise they're matched against the
+// mask the way the server does
+fn who_matches(q: &Query, args: &[~[u8]]) -> bool {
+    if args.len() < 7 {
+        return false;
+    }
+    let target = q.target.as_slice();
+    if is_channel(target) {
+        return lower(args[0]) == q.target;
+    }
+    // channel user host server nick flags :hops real
+    let real: &[u8] = match args[6].iter().position(|&b| b == ' ' as u8) {
+        None => &[],
+        Some(idx) => args[6].slice_from(idx+1)
+    };
+    let mut full = args[4].clone();
+    full.push('!' as u8);
+    full.push_all(args[1]);
+    full.push('@' as u8);
+    full.push_all(args[2]);
+    [args[4].as_slice(), full.as_slice(), args[2].as_slice(), args[3].as_slice(), real]
+        .iter().any(|s| glob_match(target, lower(*s).as_slice()))
+}
+
+fn feed_names(q: &mut Query, code: uint, args: &[~[u8]]) -> Option<Option<~[u8]>> {
+    let names = match q.reply {
+        NamesReply(ref mut names) => names,
+        _ => return None
+    };
+    match code {
+        353 if args.len() >= 3 => {
+            // type channel :names
+            for name in args[2].split(|&b| b == ' ' as u8).filter(|n| !n.is_empty()) {
+                names.push(name.to_owned());
+            }
+        }
+        366 => return Some(None),
+        403 | 442 => return Some(Some(last(args))),
+        _ => ()
+    }
+    None
+}
+
+fn feed_banlist(q: &mut Query, code: uint, args: &[~[u8]]) -> Option<Option<~[u8]>> {
+    let bans = match q.reply {
+        BanListReply(ref mut bans) => bans,
+        _ => return None
+    };
+    match code {
+        367 if args.len() >= 2 => {
+            // channel mask [setter time]
+            bans.push(BanEntry {
+                mask: args[1].clone(),
+                setter: args.get(2).map(|a| a.clone()),
+                time: args.get(3).and_then(|a| parse_uint(*a))
+            });
+        }
+        368 => return Some(None),
+        403 | 442 | 482 => return Some(Some(last(args))),
+        _ => ()
+    }
+    None
+}
+
+fn last(args: &[~[u8]]) -> ~[u8] {
+    args.last().map(|a| a.clone()).unwrap_or_else(|| ~[])
+}
+
+fn lower(s: &[u8]) -> ~[u8] {
+    s.iter().map(|&b| if b >= 'A' as u8 && b <= 'Z' as u8 { b + 32 } else { b }).collect()
+}
+
+fn parse_uint(s: &[u8]) -> Option<uint> {
+    str::from_utf8(s).and_then(|s| from_str::<uint>(s))
+}
+
+/// Pushes the Lua representation of a reply
+pub unsafe fn push_reply(L: &mut lua::ExternState, reply: &Reply) {
+    match *reply {
+        WhoisReply(ref info) => {
+            L.createtable(0, 12);
+            L.pushbytes(info.nick);
+            L.setfield(-2, "nick");
+            push_opt(L, &info.user);
+            L.setfield(-2, "user");
+            push_opt(L, &info.host);
+            L.setfield(-2, "host");
+            push_opt(L, &info.real);
+            L.setfield(-2, "real");
+            push_opt(L, &info.server);
+            L.setfield(-2, "server");
+            push_opt(L, &info.server_info);
+            L.setfield(-2, "server_info");
+            L.pushboolean(info.operator);
+            L.setfield(-2, "operator");
+            push_opt_uint(L, info.idle);
+            L.setfield(-2, "idle");
+            push_opt_uint(L, info.signon);
+            L.setfield(-2, "signon");
+            push_list(L, info.channels);
+            L.setfield(-2, "channels");
+            push_opt(L, &info.account);
+            L.setfield(-2, "account");
+            push_opt(L, &info.away);
+            L.setfield(-2, "away");
+        }
+        WhoReply(ref entries) => {
+            L.createtable(entries.len() as i32, 0);
+            for (i, e) in entries.iter().enumerate() {
+                L.createtable(0, 8);
+                L.pushbytes(e.channel);
+                L.setfield(-2, "channel");
+                L.pushbytes(e.user);
+                L.setfield(-2, "user");
+                L.pushbytes(e.host);
+                L.setfield(-2, "host");
+                L.pushbytes(e.server);
+                L.setfield(-2, "server");
+                L.pushbytes(e.nick);
+                L.setfield(-2, "nick");
+                L.pushbytes(e.flags);
+                L.setfield(-2, "flags");
+                push_opt_uint(L, e.hops);
+                L.setfield(-2, "hops");
+                L.pushbytes(e.real);
+                L.setfield(-2, "real");
+                L.rawseti(-2, i as i32 + 1);
+            }
+        }
+        NamesReply(ref names) => push_list(L, *names),
+        BanListReply(ref bans) => {
+            L.createtable(bans.len() as i32, 0);
+            for (i, b) in bans.iter().enumerate() {
+                L.createtable(0, 3);
+                L.pushbytes(b.mask);
+                L.setfield(-2, "mask");
+                push_opt(L, &b.setter);
+                L.setfield(-2, "setter");
+                push_opt_uint(L, b.time);
+                L.setfield(-2, "time");
+                L.rawseti(-2, i as i32 + 1);
+            }
+        }
+    }
+}
+
+unsafe fn push_opt(L: &mut lua::ExternState, v: &Option<~[u8]>) {
+    match *v {
+        None => L.pushnil(),
+        Some(ref v) => L.pushbytes(*v)
+    }
+}
+
+unsafe fn push_opt_uint(L: &mut lua::ExternState, v: Option<uint>) {
+    match v {
+        None => L.pushnil(),
+        Some(v) => L.pushinteger(v as int)
+    }
+}
+
+unsafe fn push_list(L: &mut lua::ExternState, items: &[~[u8]]) {
+    L.createtable(items.len() as i32, 0);
+    for (i, item) in items.iter().enumerate() {
+        L.pushbytes(*item);
+        L.rawseti(-2, i as i32 + 1);
+    }
+}
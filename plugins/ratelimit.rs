@@ -0,0 +1,100 @@
+//! Rate limits for bot commands
+//!
+//! Each user (by user@host, so changing nicks doesn't help) and each channel
+//! may only send so many commands within a sliding window, configured in the
+//! [rate_limit] section of the config file. A user over the limit is added to
+//! the ignore list for a while. Commands over a channel's limit are dropped
+//! until the window frees up, without ignoring anyone. CTCP queries count as
+//! commands, as the bot answers them.
+
+use config;
+use std::cmp;
+use time;
+
+/// The result of counting a command against the limits
+#[deriving(Eq)]
+pub enum Verdict {
+    Allowed,
+    /// The channel is over its limit
+    ChannelLimited,
+    /// The user is over their limit
+    UserLimited
+}
+
+/// Counts commands against the limits
+pub struct Limiter {
+    priv limits: config::RateLimit,
+    priv users: ~[(~[u8], ~[u64])], // user@host, command times in ns
+    priv channels: ~[(~[u8], ~[u64])] // channel, command times in ns
+}
+
+impl Limiter {
+    pub fn new(limits: config::RateLimit) -> Limiter {
+        Limiter { limits: limits, users: ~[], channels: ~[] }
+    }
+
+    /// Returns the limits
+    pub fn limits<'a>(&'a self) -> &'a config::RateLimit {
+        &self.limits
+    }
+
+    /// Replaces the limits, keeping the commands already counted
+    pub fn set_limits(&mut self, limits: config::RateLimit) {
+        self.limits = limits;
+    }
+
+    /// Counts a command from the user, sent to the channel if any. A command
+    /// that isn't allowed doesn't count against any limit.
+    pub fn check(&mut self, user: &[u8], chan: Option<&[u8]>) -> Verdict {
+        let now = time::precise_time_ns();
+        let (user_max, user_window) = (self.limits.user_commands, self.limits.user_window);
+        let (chan_max, chan_window) = (self.limits.channel_commands, self.limits.channel_window);
+        expire(&mut self.users, now, user_window);
+        expire(&mut self.channels, now, chan_window);
+
+        if user_max > 0 && count(self.users.as_slice(), user) >= user_max {
+            return UserLimited;
+        }
+        match chan {
+            Some(c) if chan_max > 0 && count(self.channels.as_slice(), c) >= chan_max => {
+                return ChannelLimited;
+            }
+            _ => ()
+        }
+
+        if user_max > 0 {
+            record(&mut self.users, user, now);
+        }
+        match chan {
+            Some(c) if chan_max > 0 => record(&mut self.channels, c, now),
+            _ => ()
+        }
+        Allowed
+    }
+
+    /// Forgets the commands of the user, so they start over once they're
+    /// no longer ignored
+    pub fn reset(&mut self, user: &[u8]) {
+        self.users.retain(|&(ref u, _)| u.as_slice() != user);
+    }
+}
+
+// drops the times that have left the window, along with any keys left with none
+fn expire(times: &mut ~[(~[u8], ~[u64])], now: u64, window: uint) {
+    let start = now - cmp::min(now, window as u64 * 1000000000);
+    for entry in times.mut_iter() {
+        entry.mut1().retain(|&t| t > start);
+    }
+    times.retain(|&(_, ref ts)| !ts.is_empty());
+}
+
+fn count(times: &[(~[u8], ~[u64])], key: &[u8]) -> uint {
+    times.iter().find(|&&(ref k, _)| k.as_slice() == key).map_or(0, |&(_, ref ts)| ts.len())
+}
+
+fn record(times: &mut ~[(~[u8], ~[u64])], key: &[u8], now: u64) {
+    match times.iter().position(|&(ref k, _)| k.as_slice() == key) {
+        Some(idx) => times[idx].mut1().push(now),
+        None => times.push((key.to_owned(), ~[now]))
+    }
+}
@@ -0,0 +1,771 @@
+//! Regular expressions
+//!
+//! A small backtracking regex engine for the regex module and triggers. It
+//! supports the common syntax:
+//!
+//! Literals, with \ escaping any punctuation, and \n, \t, \r, \f, \v and
+//!     \xHH or \x{H...} for other characters
+//! . for any character but \n (or any character at all with the s flag)
+//! Classes such as [a-z_] and [^,], and \d, \w and \s along with their
+//!     negations \D, \W and \S, which may also be used inside classes
+//! ^ and $ for the start and end of the text, \b and \B for word boundaries
+//! Groups (...), non-capturing groups (?:...), and alternation a|b
+//! The repetitions *, +, ?, {n}, {n,} and {n,m}, and their lazy forms *?,
+//!     +?, ??, {n,}? and {n,m}?
+//! Flags (?i) for ASCII case-insensitive matching and (?s) to let . match
+//!     \n, which apply to the rest of the enclosing group, or as (?i:...)
+//!     to only the group
+//!
+//! Matching never tries the same instruction at the same position twice, so
+//! it takes at most time proportional to the size of the pattern times the
+//! length of the text. That holds for a whole scan with captures_iter too,
+//! which shares that record between its searches.
+
+use std::vec;
+
+// the most instructions a pattern may compile to
+static MAX_PROGRAM: uint = 20000;
+// the most a counted repetition may repeat
+static MAX_REPEAT: uint = 1000;
+
+/// A compiled regular expression
+pub struct Regex {
+    priv prog: ~[Inst],
+    priv ngroups: uint // including the whole match
+}
+
+/// The positions of the groups of a match, as byte offsets into the text
+pub struct Captures {
+    priv locs: ~[Option<uint>]
+}
+
+/// An iterator over the successive matches in a text
+pub struct FindCaptures<'r, 't> {
+    priv re: &'r Regex,
+    priv text: &'t str,
+    priv pos: uint,
+    priv last_end: Option<uint>,
+    priv visited: ~[u32] // shared by each search, see exec
+}
+
+#[deriving(Clone)]
+enum Perl {
+    Digit,
+    Word,
+    Space
+}
+
+#[deriving(Clone)]
+struct Class {
+    ranges: ~[(char, char)],
+    perls: ~[(Perl, bool)], // the class, and whether it's negated
+    negated: bool,
+    icase: bool
+}
+
+#[deriving(Clone)]
+enum Node {
+    Empty,
+    Literal(char, bool), // the char, and whether case is ignored
+    AnyChar(bool), // whether \n matches
+    Set(Class),
+    Start,
+    End,
+    WordBoundary(bool), // false for \B
+    Group(~Node, Option<uint>), // the capture index, if it captures
+    Concat(~[Node]),
+    Alternate(~[Node]),
+    Repeat(~Node, uint, Option<uint>, bool) // min, max, greedy
+}
+
+enum Inst {
+    IChar(char, bool),
+    IAny(bool),
+    IClass(Class),
+    ISplit(uint, uint), // try the first, then the second
+    IJmp(uint),
+    ISave(uint),
+    IStart,
+    IEnd,
+    IWordBoundary(bool),
+    IMatch
+}
+
+enum Escape {
+    EChar(char),
+    EPerl(Perl, bool),
+    EBoundary(bool)
+}
+
+enum Job {
+    Try(uint, uint), // pc, pos
+    Restore(uint, Option<uint>) // slot, old value
+}
+
+impl Regex {
+    /// Compiles a pattern, returning a description of the problem if it's
+    /// invalid
+    pub fn new(pattern: &str) -> Result<Regex, ~str> {
+        let mut parser = Parser {
+            chars: pattern.chars().collect(),
+            pos: 0,
+            ngroups: 0,
+            icase: false,
+            dotall: false
+        };
+        let node = match parser.parse_alternate() {
+            Ok(n) => n,
+            Err(e) => return Err(e)
+        };
+        if parser.pos < parser.chars.len() {
+            return Err(~"unmatched )");
+        }
+        let mut compiler = Compiler { prog: ~[ISave(0)] };
+        match compiler.compile(&node) {
+            Ok(()) => (),
+            Err(e) => return Err(e)
+        }
+        compiler.prog.push(ISave(1));
+        compiler.prog.push(IMatch);
+        Ok(Regex { prog: compiler.prog, ngroups: parser.ngroups + 1 })
+    }
+
+    /// Returns the groups of the first match in the text, if any
+    pub fn captures(&self, text: &str) -> Option<Captures> {
+        let mut visited = vec::from_elem(self.visited_len(text.len()), 0u32);
+        self.exec(text, 0, visited).map(|locs| Captures { locs: locs })
+    }
+
+    /// Returns an iterator over the groups of each match in the text
+    pub fn captures_iter<'r, 't>(&'r self, text: &'t str) -> FindCaptures<'r, 't> {
+        FindCaptures { re: self, text: text, pos: 0, last_end: None,
+                       visited: vec::from_elem(self.visited_len(text.len()), 0u32) }
+    }
+
+    /// Returns the groups of the first match at or after start. visited
+    /// records the states tried so far, and starts out as visited_len zeroed
+    /// words; it may be kept for later searches of the same text that start
+    /// at or after the end of this match.
+    pub fn captures_from(&self, text: &str, start: uint,
+                         visited: &mut [u32]) -> Option<Captures> {
+        self.exec(text, start, visited).map(|locs| Captures { locs: locs })
+    }
+
+    /// Returns the number of words in the record of tried states for a text
+    /// of the given length, one bit for each instruction at each position
+    pub fn visited_len(&self, len: uint) -> uint {
+        (self.prog.len() * (len + 1) + 31) / 32
+    }
+
+    // finds the first match at or after start, returning its group positions
+    // a state marked in visited can't lead to a match, whatever the search
+    // started from, so the record may be shared by successive searches as
+    // long as they move forward
+    fn exec(&self, text: &str, start: uint, visited: &mut [u32]) -> Option<~[Option<uint>]> {
+        let len = text.len();
+        let mut locs = vec::from_elem(self.ngroups * 2, None);
+        let mut sp = start;
+        loop {
+            if self.backtrack(text, sp, visited, locs) {
+                // the states on the path to the match are marked too, and the
+                // next search may pass through them where this match ended
+                let end = locs[1].unwrap();
+                for pc in range(0, self.prog.len()) {
+                    let bit = pc * (len + 1) + end;
+                    visited[bit / 32] &= !(1 << (bit % 32));
+                }
+                return Some(locs);
+            }
+            if sp >= len {
+                return None;
+            }
+            sp = text.char_range_at(sp).next;
+        }
+    }
+
+    // runs the program from the given position, leaving the group positions
+    // of a match in locs
+    fn backtrack(&self, text: &str, start: uint, visited: &mut [u32],
+                 locs: &mut [Option<uint>]) -> bool {
+        let len = text.len();
+        let mut jobs = ~[Try(0, start)];
+        loop {
+            let (mut pc, mut pos) = match jobs.pop() {
+                None => return false,
+                Some(Restore(slot, old)) => {
+                    locs[slot] = old;
+                    continue;
+                }
+                Some(Try(pc, pos)) => (pc, pos)
+            };
+            loop {
+                // a state that was tried before failed then, and would again
+                let bit = pc * (len + 1) + pos;
+                if visited[bit / 32] & (1 << (bit % 32)) != 0 {
+                    break;
+                }
+                visited[bit / 32] |= 1 << (bit % 32);
+
+                let next = if pos < len { Some(text.char_range_at(pos)) } else { None };
+                match self.prog[pc] {
+                    IChar(c, icase) => match next {
+                        Some(r) if r.ch == c || (icase && fold(r.ch) == c) => {
+                            pc += 1;
+                            pos = r.next;
+                        }
+                        _ => break
+                    },
+                    IAny(newline) => match next {
+                        Some(r) if newline || r.ch != '\n' => {
+                            pc += 1;
+                            pos = r.next;
+                        }
+                        _ => break
+                    },
+                    IClass(ref class) => match next {
+                        Some(r) if class.matches(r.ch) => {
+                            pc += 1;
+                            pos = r.next;
+                        }
+                        _ => break
+                    },
+                    ISplit(a, b) => {
+                        jobs.push(Try(b, pos));
+                        pc = a;
+                    }
+                    IJmp(a) => pc = a,
+                    ISave(slot) => {
+                        jobs.push(Restore(slot, locs[slot]));
+                        locs[slot] = Some(pos);
+                        pc += 1;
+                    }
+                    IStart => {
+                        if pos != 0 {
+                            break;
+                        }
+                        pc += 1;
+                    }
+                    IEnd => {
+                        if pos != len {
+                            break;
+                        }
+                        pc += 1;
+                    }
+                    IWordBoundary(want) => {
+                        let before = pos > 0 && is_word(text.char_range_at_reverse(pos).ch);
+                        let after = next.map_or(false, |r| is_word(r.ch));
+                        if (before != after) != want {
+                            break;
+                        }
+                        pc += 1;
+                    }
+                    IMatch => return true
+                }
+            }
+        }
+    }
+}
+
+impl Captures {
+    /// Returns the number of groups, including the whole match
+    pub fn len(&self) -> uint {
+        self.locs.len() / 2
+    }
+
+    /// Returns the start and end of the group, or None if it didn't
+    /// participate in the match
+    pub fn pos(&self, i: uint) -> Option<(uint, uint)> {
+        if i >= self.len() {
+            return None;
+        }
+        match (self.locs[i * 2], self.locs[i * 2 + 1]) {
+            (Some(s), Some(e)) => Some((s, e)),
+            _ => None
+        }
+    }
+}
+
+impl<'r, 't> Iterator<Captures> for FindCaptures<'r, 't> {
+    fn next(&mut self) -> Option<Captures> {
+        let len = self.text.len();
+        loop {
+            if self.pos > len {
+                return None;
+            }
+            let locs = match self.re.exec(self.text, self.pos, self.visited) {
+                None => {
+                    self.pos = len + 1;
+                    return None;
+                }
+                Some(locs) => locs
+            };
+            let (s, e) = (locs[0].unwrap(), locs[1].unwrap());
+            if s == e {
+                // step past an empty match so the next search makes progress
+                self.pos = if e < len { self.text.char_range_at(e).next } else { len + 1 };
+                // and skip one right where the previous match ended
+                if self.last_end == Some(e) {
+                    continue;
+                }
+            } else {
+                self.pos = e;
+            }
+            self.last_end = Some(e);
+            return Some(Captures { locs: locs });
+        }
+    }
+}
+
+impl Perl {
+    fn matches(&self, c: char) -> bool {
+        match *self {
+            Digit => c.is_digit(),
+            Word => is_word(c),
+            Space => c.is_whitespace()
+        }
+    }
+}
+
+impl Class {
+    fn matches(&self, c: char) -> bool {
+        let found = self.contains(c) || (self.icase && (self.contains(fold(c)) ||
+                                                        self.contains(unfold(c))));
+        found != self.negated
+    }
+
+    fn contains(&self, c: char) -> bool {
+        self.ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) ||
+            self.perls.iter().any(|&(ref p, neg)| p.matches(c) != neg)
+    }
+}
+
+struct Parser {
+    chars: ~[char],
+    pos: uint,
+    ngroups: uint,
+    icase: bool,
+    dotall: bool
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        if self.pos < self.chars.len() { Some(self.chars[self.pos]) } else { None }
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        if c.is_some() {
+            self.pos += 1;
+        }
+        c
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse_alternate(&mut self) -> Result<Node, ~str> {
+        let mut alts = ~[];
+        loop {
+            match self.parse_concat() {
+                Ok(n) => alts.push(n),
+                Err(e) => return Err(e)
+            }
+            if !self.eat('|') {
+                break;
+            }
+        }
+        Ok(if alts.len() == 1 { alts.pop().unwrap() } else { Alternate(alts) })
+    }
+
+    fn parse_concat(&mut self) -> Result<Node, ~str> {
+        let mut items = ~[];
+        loop {
+            match self.peek() {
+                None | Some('|') | Some(')') => break,
+                _ => ()
+            }
+            let atom = match self.parse_atom() {
+                Ok(n) => n,
+                Err(e) => return Err(e)
+            };
+            match self.parse_repeats(atom) {
+                Ok(n) => items.push(n),
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(if items.len() == 1 { items.pop().unwrap() } else { Concat(items) })
+    }
+
+    fn parse_atom(&mut self) -> Result<Node, ~str> {
+        match self.next().unwrap() {
+            '(' => self.parse_group(),
+            '[' => self.parse_class(),
+            '.' => Ok(AnyChar(self.dotall)),
+            '^' => Ok(Start),
+            '$' => Ok(End),
+            '\\' => match self.parse_escape() {
+                Ok(EChar(c)) => Ok(Literal(c, self.icase)),
+                Ok(EPerl(p, neg)) => Ok(Set(self.class(~[], ~[(p, neg)], false))),
+                Ok(EBoundary(b)) => Ok(WordBoundary(b)),
+                Err(e) => Err(e)
+            },
+            '*' | '+' | '?' => Err(~"repetition operator missing expression"),
+            c => Ok(Literal(if self.icase { fold(c) } else { c }, self.icase))
+        }
+    }
+
+    // parses what follows a (
+    fn parse_group(&mut self) -> Result<Node, ~str> {
+        let (icase, dotall) = (self.icase, self.dotall);
+        let mut index = None;
+        if self.eat('?') {
+            // flags, which end the group, or start a non-capturing one with :
+            let mut on = true;
+            loop {
+                match self.next() {
+                    Some('i') => self.icase = on,
+                    Some('s') => self.dotall = on,
+                    Some('-') if on => on = false,
+                    Some(':') => break,
+                    Some(')') => return Ok(Empty),
+                    _ => return Err(~"unsupported group syntax")
+                }
+            }
+        } else {
+            self.ngroups += 1;
+            index = Some(self.ngroups);
+        }
+        let inner = match self.parse_alternate() {
+            Ok(n) => n,
+            Err(e) => return Err(e)
+        };
+        if !self.eat(')') {
+            return Err(~"unclosed group");
+        }
+        self.icase = icase;
+        self.dotall = dotall;
+        Ok(Group(~inner, index))
+    }
+
+    fn parse_class(&mut self) -> Result<Node, ~str> {
+        let negated = self.eat('^');
+        let (mut ranges, mut perls) = (~[], ~[]);
+        let mut first = true;
+        loop {
+            let lo = match self.next() {
+                None => return Err(~"unclosed character class"),
+                Some(']') if !first => break,
+                Some('\\') => match self.parse_escape() {
+                    Ok(EChar(c)) => c,
+                    Ok(EPerl(p, neg)) => {
+                        perls.push((p, neg));
+                        first = false;
+                        continue;
+                    }
+                    Ok(EBoundary(_)) => return Err(~"\\b and \\B aren't allowed in a class"),
+                    Err(e) => return Err(e)
+                },
+                Some(c) => c
+            };
+            first = false;
+            let is_range = self.peek() == Some('-') && self.pos + 1 < self.chars.len() &&
+                           self.chars[self.pos + 1] != ']';
+            if !is_range {
+                ranges.push((lo, lo));
+                continue;
+            }
+            self.pos += 1;
+            let hi = match self.next() {
+                Some('\\') => match self.parse_escape() {
+                    Ok(EChar(c)) => c,
+                    Ok(_) => return Err(~"invalid class range"),
+                    Err(e) => return Err(e)
+                },
+                Some(c) => c,
+                None => return Err(~"unclosed character class")
+            };
+            if hi < lo {
+                return Err(~"invalid class range");
+            }
+            ranges.push((lo, hi));
+        }
+        Ok(Set(self.class(ranges, perls, negated)))
+    }
+
+    fn class(&self, ranges: ~[(char, char)], perls: ~[(Perl, bool)], negated: bool) -> Class {
+        Class { ranges: ranges, perls: perls, negated: negated, icase: self.icase }
+    }
+
+    // parses what follows a \
+    fn parse_escape(&mut self) -> Result<Escape, ~str> {
+        let c = match self.next() {
+            None => return Err(~"trailing backslash"),
+            Some(c) => c
+        };
+        Ok(match c {
+            'd' => EPerl(Digit, false),
+            'D' => EPerl(Digit, true),
+            'w' => EPerl(Word, false),
+            'W' => EPerl(Word, true),
+            's' => EPerl(Space, false),
+            'S' => EPerl(Space, true),
+            'b' => EBoundary(true),
+            'B' => EBoundary(false),
+            'n' => EChar('\n'),
+            't' => EChar('\t'),
+            'r' => EChar('\r'),
+            'f' => EChar('\x0c'),
+            'v' => EChar('\x0b'),
+            'x' => match self.parse_hex() {
+                Some(c) => EChar(c),
+                None => return Err(~"invalid \\x escape")
+            },
+            c if !c.is_alphanumeric() => EChar(c),
+            c => return Err(format!("unknown escape \\\\{}", c))
+        })
+    }
+
+    // parses the HH or {H...} of a \x escape
+    fn parse_hex(&mut self) -> Option<char> {
+        let digits = if self.eat('{') {
+            let start = self.pos;
+            while self.peek().map_or(false, |c| c != '}') {
+                self.pos += 1;
+            }
+            if !self.eat('}') {
+                return None;
+            }
+            self.chars.slice(start, self.pos - 1).to_owned()
+        } else {
+            if self.pos + 2 > self.chars.len() {
+                return None;
+            }
+            self.pos += 2;
+            self.chars.slice(self.pos - 2, self.pos).to_owned()
+        };
+        let mut n = 0u32;
+        if digits.is_empty() || digits.len() > 6 {
+            return None;
+        }
+        for &d in digits.iter() {
+            match d.to_digit(16) {
+                None => return None,
+                Some(v) => n = n * 16 + v as u32
+            }
+        }
+        ::std::char::from_u32(n)
+    }
+
+    fn parse_repeats(&mut self, atom: Node) -> Result<Node, ~str> {
+        let mut node = atom;
+        loop {
+            let (min, max) = match self.peek() {
+                Some('*') => { self.pos += 1; (0, None) }
+                Some('+') => { self.pos += 1; (1, None) }
+                Some('?') => { self.pos += 1; (0, Some(1)) }
+                Some('{') => match self.parse_counted() {
+                    None => break, // a literal {
+                    Some(Ok(counts)) => counts,
+                    Some(Err(e)) => return Err(e)
+                },
+                _ => break
+            };
+            match node {
+                Empty => return Err(~"repetition operator missing expression"),
+                _ => ()
+            }
+            let greedy = !self.eat('?');
+            node = Repeat(~node, min, max, greedy);
+        }
+        Ok(node)
+    }
+
+    // parses a {n}, {n,} or {n,m}, consuming it only if it's well formed
+    fn parse_counted(&mut self) -> Option<Result<(uint, Option<uint>), ~str>> {
+        let start = self.pos;
+        self.pos += 1;
+        let min = self.parse_number();
+        let max = if self.eat(',') { self.parse_number() } else { min };
+        let min = match min {
+            Some(n) if self.eat('}') => n,
+            _ => {
+                self.pos = start;
+                return None;
+            }
+        };
+        if min > MAX_REPEAT || max.map_or(false, |m| m > MAX_REPEAT) {
+            return Some(Err(format!("repetition count is over {}", MAX_REPEAT)));
+        }
+        if max.map_or(false, |m| m < min) {
+            return Some(Err(~"invalid repetition count"));
+        }
+        Some(Ok((min, max)))
+    }
+
+    // parses a decimal number, which is capped just past MAX_REPEAT
+    fn parse_number(&mut self) -> Option<uint> {
+        let mut n = None;
+        loop {
+            match self.peek().and_then(|c| c.to_digit(10)) {
+                None => return n,
+                Some(d) => {
+                    let v = n.unwrap_or(0u) * 10 + d;
+                    n = Some(if v > MAX_REPEAT { MAX_REPEAT + 1 } else { v });
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+}
+
+struct Compiler {
+    prog: ~[Inst]
+}
+
+impl Compiler {
+    fn push(&mut self, inst: Inst) -> uint {
+        self.prog.push(inst);
+        self.prog.len() - 1
+    }
+
+    fn compile(&mut self, node: &Node) -> Result<(), ~str> {
+        if self.prog.len() > MAX_PROGRAM {
+            return Err(~"pattern is too large");
+        }
+        match *node {
+            Empty => (),
+            Literal(c, icase) => { self.push(IChar(c, icase)); }
+            AnyChar(newline) => { self.push(IAny(newline)); }
+            Set(ref class) => { self.push(IClass(class.clone())); }
+            Start => { self.push(IStart); }
+            End => { self.push(IEnd); }
+            WordBoundary(want) => { self.push(IWordBoundary(want)); }
+            Group(ref inner, None) => return self.compile(*inner),
+            Group(ref inner, Some(i)) => {
+                self.push(ISave(i * 2));
+                match self.compile(*inner) {
+                    Ok(()) => (),
+                    Err(e) => return Err(e)
+                }
+                self.push(ISave(i * 2 + 1));
+            }
+            Concat(ref nodes) => {
+                for n in nodes.iter() {
+                    match self.compile(n) {
+                        Ok(()) => (),
+                        Err(e) => return Err(e)
+                    }
+                }
+            }
+            Alternate(ref nodes) => {
+                // each alternative but the last is tried first, then the rest
+                let mut jumps = ~[];
+                for (i, n) in nodes.iter().enumerate() {
+                    let last = i + 1 == nodes.len();
+                    let split = if last { None } else { Some(self.push(ISplit(0, 0))) };
+                    match self.compile(n) {
+                        Ok(()) => (),
+                        Err(e) => return Err(e)
+                    }
+                    match split {
+                        None => (),
+                        Some(split) => {
+                            jumps.push(self.push(IJmp(0)));
+                            self.prog[split] = ISplit(split + 1, self.prog.len());
+                        }
+                    }
+                }
+                let end = self.prog.len();
+                for &j in jumps.iter() {
+                    self.prog[j] = IJmp(end);
+                }
+            }
+            Repeat(ref inner, min, max, greedy) => {
+                for _ in range(0, min) {
+                    match self.compile(*inner) {
+                        Ok(()) => (),
+                        Err(e) => return Err(e)
+                    }
+                }
+                match max {
+                    None => {
+                        let split = self.push(ISplit(0, 0));
+                        match self.compile(*inner) {
+                            Ok(()) => (),
+                            Err(e) => return Err(e)
+                        }
+                        self.push(IJmp(split));
+                        self.prog[split] = split_inst(split + 1, self.prog.len(), greedy);
+                    }
+                    Some(max) => {
+                        let mut splits = ~[];
+                        for _ in range(min, max) {
+                            splits.push(self.push(ISplit(0, 0)));
+                            match self.compile(*inner) {
+                                Ok(()) => (),
+                                Err(e) => return Err(e)
+                            }
+                        }
+                        let end = self.prog.len();
+                        for &s in splits.iter() {
+                            self.prog[s] = split_inst(s + 1, end, greedy);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// a split that prefers the first branch if greedy, the second otherwise
+fn split_inst(body: uint, out: uint, greedy: bool) -> Inst {
+    if greedy { ISplit(body, out) } else { ISplit(out, body) }
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// lowercases an ASCII letter
+fn fold(c: char) -> char {
+    if c >= 'A' && c <= 'Z' { ((c as u8) + 32) as char } else { c }
+}
+
+// uppercases an ASCII letter
+fn unfold(c: char) -> char {
+    if c >= 'a' && c <= 'z' { ((c as u8) - 32) as char } else { c }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Regex;
+
+    // returns the positions of each match of the pattern in the text
+    fn find_all(pattern: &str, text: &str) -> ~[(uint, uint)] {
+        let re = Regex::new(pattern).unwrap();
+        re.captures_iter(text).map(|c| c.pos(0).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_many_matches() {
+        let text = "ab".repeat(50000);
+        let found = find_all("a(b)", text.as_slice());
+        assert_eq!(found.len(), 50000);
+        assert_eq!(found[0], (0, 2));
+        assert_eq!(found[49999], (99998, 100000));
+    }
+
+    #[test]
+    fn test_match_where_previous_ended() {
+        // the a* branch matches empty at 2, which is skipped, rather than b
+        assert_eq!(find_all("a*|b", "aab"), ~[(0, 2), (3, 3)]);
+        assert_eq!(find_all("a|b", "abba"), ~[(0, 1), (1, 2), (2, 3), (3, 4)]);
+    }
+}
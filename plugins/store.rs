@@ -0,0 +1,389 @@
+//! Persistent key-value storage for plugins
+//!
+//! Vends irc.store(namespace), which returns a store object backed by a file
+//! under the plugin data dir. Stores are namespaced by server name and by the
+//! plugin that opened them, so two plugins can use the same namespace without
+//! colliding. Each change is written out immediately, by writing a new file
+//! and renaming it over the old one.
+//!
+//! Store objects have the following methods:
+//!
+//! store:get(key): Returns the value for key, or nil
+//! store:set(key, value): Sets the value for key; a nil value deletes it
+//! store:delete(key): Deletes the value for key
+//! store:pairs(): Returns an iterator over the keys and values, for use with
+//!     a generic for
+//!
+//! Keys must be strings or numbers. Values are limited to nil, booleans,
+//! numbers, strings and tables of those. Tables are copied going in and out of
+//! the store, so changes to a table must be saved with store:set.
+
+#[allow(uppercase_variables)];
+
+use lua;
+use super::{alloc, raise, raise_arg};
+use std::{f64, io, str};
+use std::io::IoError;
+
+static STORE_DIR: &'static str = "store_dir";
+static STORE_CACHE: &'static str = "store_cache";
+static STORE_MT: &'static str = "irc.Store";
+
+// tables nested deeper than this are assumed to be cyclic
+static MAX_DEPTH: uint = 64;
+
+/// Sets the directory that stores are kept in
+pub fn set_store_dir(L: &mut lua::State, dir: &Path) {
+    L.pushbytes(dir.as_vec());
+    L.setfield(lua::REGISTRYINDEX, STORE_DIR);
+}
+
+lua_extern_pub! {
+    unsafe fn lua_store(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: namespace
+
+        let ns = L.checkstring(1).unwrap_or("");
+        L.argcheck(valid_name(ns), 1, "namespace may only contain letters, digits, '-' and '_'");
+
+        let path = plugin_dir(L).join(format!("{}.lua", ns));
+
+        // reuse the store if this plugin already opened it
+        L.getfield(lua::REGISTRYINDEX, STORE_CACHE);
+        if !L.istable(-1) {
+            L.pop(1);
+            L.newtable();
+            L.pushvalue(-1);
+            L.setfield(lua::REGISTRYINDEX, STORE_CACHE);
+        }
+        let cache = L.gettop();
+        L.pushbytes(path.as_vec());
+        L.rawget(cache);
+        if L.istable(-1) {
+            return 1;
+        }
+        L.pop(1);
+
+        L.createtable(0, 2);
+        L.pushbytes(path.as_vec());
+        L.setfield(-2, "path");
+        match load(L, &path) {
+            Ok(()) => (),
+            Err(e) => raise(L, format!("could not load store {}: {}", path.display(), e))
+        }
+        L.setfield(-2, "data");
+        if L.newmetatable(STORE_MT) {
+            L.newtable();
+            L.registerlib(None, [
+                ("get", lua_store_get),
+                ("set", lua_store_set),
+                ("delete", lua_store_delete),
+                ("pairs", lua_store_pairs)
+            ]);
+            L.setfield(-2, "__index");
+        }
+        L.setmetatable(-2);
+
+        L.pushbytes(path.as_vec());
+        L.pushvalue(-2);
+        L.rawset(cache);
+        1
+    }
+}
+
+/// Returns the data directory of the plugin that's currently running
+pub unsafe fn plugin_dir(L: &mut lua::ExternState) -> Path {
+    L.getfield(lua::REGISTRYINDEX, STORE_DIR);
+    let dir = match L.tobytes(-1) {
+        None => L.errorstr("no plugin data dir is configured"),
+        Some(dir) => Path::new(dir)
+    };
+    L.pop(1);
+    let alloc = alloc::get(L);
+    dir.join(alloc.name(alloc.current()))
+}
+
+lua_extern! {
+    unsafe fn lua_store_get(L: &mut lua::ExternState) -> i32 {
+        // 2 args: store, key
+
+        check_key(L, 2);
+        L.settop(2);
+        push_data(L, 1);
+        L.pushvalue(2);
+        L.rawget(-2);
+        let value = L.gettop();
+        match copy_value(L, value, 0) {
+            Ok(()) => 1,
+            Err(e) => raise(L, e)
+        }
+    }
+
+    unsafe fn lua_store_set(L: &mut lua::ExternState) -> i32 {
+        // 3 args: store, key, value
+
+        store_set(L)
+    }
+
+    unsafe fn lua_store_delete(L: &mut lua::ExternState) -> i32 {
+        // 2 args: store, key
+
+        L.settop(2);
+        L.pushnil();
+        store_set(L)
+    }
+
+    unsafe fn lua_store_pairs(L: &mut lua::ExternState) -> i32 {
+        // 1 arg: store
+
+        // iterate over a copy, so the store may be changed during iteration
+        push_data(L, 1);
+        let data = L.gettop();
+        L.getglobal("next");
+        match copy_value(L, data, 0) {
+            Ok(()) => (),
+            Err(e) => raise(L, e)
+        }
+        L.pushnil();
+        3
+    }
+}
+
+// sets store[key] = value for the store, key and value at indices 1 through 3
+unsafe fn store_set(L: &mut lua::ExternState) -> i32 {
+    check_key(L, 2);
+    L.checkany(3);
+    L.settop(3);
+    push_data(L, 1);
+    let data = L.gettop();
+    // copy the value first, which also checks that it can be stored
+    match copy_value(L, 3, 0) {
+        Ok(()) => (),
+        Err(e) => raise_arg(L, 3, e)
+    }
+    let value = L.gettop();
+    L.pushvalue(2);
+    L.rawget(data);
+    let old = L.gettop();
+
+    L.pushvalue(2);
+    L.pushvalue(value);
+    L.rawset(data);
+    match save(L, 1, data) {
+        Ok(()) => 0,
+        Err(e) => {
+            // put back the old value so the store matches the file
+            L.pushvalue(2);
+            L.pushvalue(old);
+            L.rawset(data);
+            raise(L, format!("could not save store: {}", e))
+        }
+    }
+}
+
+// pushes the data table of the store at the given index
+unsafe fn push_data(L: &mut lua::ExternState, idx: i32) {
+    L.checktype(idx, lua::Type::Table);
+    L.getfield(idx, "data");
+    if !L.istable(-1) {
+        L.argerror(idx, "expected store");
+    }
+}
+
+unsafe fn check_key(L: &mut lua::ExternState, idx: i32) {
+    match L.type_(idx) {
+        Some(lua::Type::String) | Some(lua::Type::Number) => (),
+        _ => {
+            L.argerror(idx, "key must be a string or number");
+        }
+    }
+}
+
+/// Returns whether the name is safe to use as part of a file name
+pub fn valid_name(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+}
+
+// pushes a deep copy of the value at the given index, or returns an error if
+// it can't be stored
+unsafe fn copy_value(L: &mut lua::ExternState, idx: i32, depth: uint) -> Result<(), ~str> {
+    match L.type_(idx) {
+        None | Some(lua::Type::Nil) | Some(lua::Type::Boolean) | Some(lua::Type::Number) |
+        Some(lua::Type::String) => {
+            L.pushvalue(idx);
+            Ok(())
+        }
+        Some(lua::Type::Table) => {
+            if depth >= MAX_DEPTH {
+                return Err(~"tables are nested too deeply (or contain a cycle)");
+            }
+            let top = L.gettop();
+            L.newtable();
+            L.pushnil(); // first key
+            while L.next(idx) {
+                // key is top+2, value is top+3
+                match L.type_(top + 2) {
+                    Some(lua::Type::String) | Some(lua::Type::Number) => (),
+                    _ => {
+                        L.settop(top);
+                        return Err(~"table keys must be strings or numbers");
+                    }
+                }
+                match copy_value(L, top + 3, depth + 1) {
+                    Ok(()) => (),
+                    Err(e) => {
+                        L.settop(top);
+                        return Err(e);
+                    }
+                }
+                L.pushvalue(top + 2);
+                L.insert(-2);
+                L.rawset(top + 1);
+                L.pop(1); // pop the value, leave the key for next
+            }
+            Ok(())
+        }
+        Some(_) => Err(format!("cannot store a {} value", L.typename(idx)))
+    }
+}
+
+// appends the Lua source for the value at the given index
+unsafe fn serialize(L: &mut lua::ExternState, idx: i32, out: &mut ~[u8]) {
+    match L.type_(idx) {
+        Some(lua::Type::Boolean) => {
+            out.push_all(if L.toboolean(idx) { bytes!("true") } else { bytes!("false") });
+        }
+        Some(lua::Type::Number) => {
+            let n = L.tonumber(idx);
+            let s = if n.is_nan() {
+                ~"0/0"
+            } else if n.is_infinite() {
+                if n > 0.0 { ~"1/0" } else { ~"-1/0" }
+            } else if n == n.floor() && n.abs() < 9007199254740992.0 {
+                format!("{}", n as i64)
+            } else {
+                f64::to_str_exp_digits(n, 17, false)
+            };
+            out.push_all(s.as_bytes());
+        }
+        Some(lua::Type::String) => {
+            out.push('"' as u8);
+            for &b in L.tobytes(idx).unwrap().iter() {
+                match b as char {
+                    '"' => out.push_all(bytes!("\\\"")),
+                    '\\' => out.push_all(bytes!("\\\\")),
+                    '\n' => out.push_all(bytes!("\\n")),
+                    ' ' .. '~' => out.push(b),
+                    _ => out.push_all(format!("\\{:03u}", b).as_bytes())
+                }
+            }
+            out.push('"' as u8);
+        }
+        Some(lua::Type::Table) => {
+            let top = L.gettop();
+            out.push('{' as u8);
+            L.pushnil(); // first key
+            while L.next(idx) {
+                out.push('[' as u8);
+                serialize(L, top + 1, out);
+                out.push_all(bytes!("]="));
+                serialize(L, top + 2, out);
+                out.push_all(bytes!(",\n"));
+                L.pop(1); // pop the value, leave the key for next
+            }
+            out.push('}' as u8);
+        }
+        _ => out.push_all(bytes!("nil"))
+    }
+}
+
+// pushes the data table loaded from the given path, or a new table if the
+// file doesn't exist yet
+unsafe fn load(L: &mut lua::ExternState, path: &Path) -> Result<(), ~str> {
+    if !path.exists() {
+        L.newtable();
+        return Ok(());
+    }
+    let contents = match io::File::open(path).and_then(|mut f| f.read_to_end()) {
+        Ok(v) => v,
+        Err(e) => return Err(e.to_str())
+    };
+    match deserialize_value(L, contents) {
+        Ok(()) => (),
+        Err(e) => return Err(e)
+    }
+    if !L.istable(-1) {
+        L.pop(1);
+        return Err(~"file is not valid");
+    }
+    Ok(())
+}
+
+/// Serializes the value at the given index to Lua source that recreates it.
+/// The value is subject to the same restrictions as store values.
+pub unsafe fn serialize_value(L: &mut lua::ExternState, idx: i32) -> Result<~[u8], ~str> {
+    let top = L.gettop();
+    let idx = if idx < 0 { top + idx + 1 } else { idx };
+    // copying the value checks that it can be serialized
+    match copy_value(L, idx, 0) {
+        Ok(()) => (),
+        Err(e) => return Err(e)
+    }
+    let mut out = bytes!("return ").to_owned();
+    serialize(L, top + 1, &mut out);
+    out.push('\n' as u8);
+    L.settop(top);
+    Ok(out)
+}
+
+/// Pushes the value recreated from the output of serialize_value
+pub unsafe fn deserialize_value(L: &mut lua::ExternState, source: &[u8]) -> Result<(), ~str> {
+    // the serializer only writes ASCII
+    let source = match str::from_utf8(source) {
+        None => return Err(~"serialized data is not valid"),
+        Some(s) => s
+    };
+    match L.loadstring(source) {
+        Ok(()) => (),
+        Err(_) => {
+            let e = format!("{}", L.describe(-1));
+            L.pop(1);
+            return Err(e);
+        }
+    }
+    // run it with an empty environment, it only needs to build a value
+    L.newtable();
+    L.setfenv(-2);
+    match L.pcall(0, 1, 0) {
+        Ok(()) => Ok(()),
+        Err(_) => {
+            let e = format!("{}", L.describe(-1));
+            L.pop(1);
+            Err(e)
+        }
+    }
+}
+
+// writes the data table at index data for the store at index store
+unsafe fn save(L: &mut lua::ExternState, store: i32, data: i32) -> Result<(), IoError> {
+    L.getfield(store, "path");
+    let path = Path::new(L.tobytes(-1).unwrap());
+    L.pop(1);
+
+    let mut out = bytes!("return ").to_owned();
+    serialize(L, data, &mut out);
+    out.push('\n' as u8);
+
+    match io::fs::mkdir_recursive(&path.dir_path(), io::UserDir) {
+        Ok(()) => (),
+        Err(e) => return Err(e)
+    }
+    // write to a temporary file and rename it into place, so the store is
+    // never left half-written
+    let mut tmp = path.clone();
+    tmp.set_extension("tmp");
+    match io::File::create(&tmp).and_then(|mut f| f.write(out)) {
+        Ok(()) => (),
+        Err(e) => return Err(e)
+    }
+    io::fs::rename(&tmp, &path)
+}
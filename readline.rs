@@ -0,0 +1,171 @@
+/// Line editing for the console, using the readline library
+///
+/// Only the stdin listener task may use this, as readline keeps its state in
+/// globals.
+
+use std::{cmp, libc, mem, ptr, str};
+use std::libc::{c_char, c_int};
+
+// the completer given to the read_line in progress, as a *mut to its closure
+static mut COMPLETER: uint = 0;
+
+// words are only split on whitespace, as nicks may contain most punctuation
+static WORD_BREAK: &'static [u8] = bytes!(" \t\n\x00");
+
+/// Returns true if stdin is a terminal, so lines can be edited
+pub fn is_interactive() -> bool {
+    unsafe { libc::isatty(libc::STDIN_FILENO) != 0 }
+}
+
+/// Reads a line, returning None at the end of input. On tab, `complete` is
+/// called with the line up to the word being completed and the word, and
+/// returns the words it could be completed to.
+pub fn read_line(prompt: &str, complete: |&str, &str| -> ~[~str]) -> Option<~str> {
+    let mut complete = complete;
+    unsafe {
+        // leave ^C to the bot's own handler
+        ffi::rl_catch_signals = 0;
+        ffi::rl_basic_word_break_characters = WORD_BREAK.as_ptr() as *c_char;
+        ffi::rl_attempted_completion_function = attempted_completion;
+        COMPLETER = (&mut complete as *mut |&str, &str| -> ~[~str]) as uint;
+        let line = prompt.with_c_str(|p| ffi::readline(p));
+        COMPLETER = 0;
+        if line.is_null() {
+            return None;
+        }
+        let s = str::raw::from_c_str(line as *c_char);
+        libc::free(line as *mut libc::c_void);
+        Some(s)
+    }
+}
+
+/// Adds a line to the history, unless it repeats the previous one
+pub fn add_history(line: &str) {
+    unsafe {
+        let last = ffi::history_get(ffi::history_base + ffi::history_length - 1);
+        if last.is_not_null() && str::raw::from_c_str((*last).line).as_slice() == line {
+            return;
+        }
+        line.with_c_str(|l| ffi::add_history(l));
+    }
+}
+
+/// Loads the history from the given file, keeping at most `max` lines. It's
+/// fine for the file not to exist yet.
+pub fn load_history(path: &Path, max: uint) {
+    unsafe {
+        ffi::using_history();
+        ffi::stifle_history(max as c_int);
+        path.with_c_str(|p| ffi::read_history(p));
+    }
+}
+
+/// Writes the history to the given file
+pub fn save_history(path: &Path) {
+    let rc = unsafe { path.with_c_str(|p| ffi::write_history(p)) };
+    if rc != 0 {
+        println!("Error saving console history to {}: error {}", path.display(), rc);
+    }
+}
+
+/// Puts the terminal back the way it was, for when the bot exits in the
+/// middle of reading a line
+pub fn restore_terminal() {
+    unsafe {
+        ffi::rl_cleanup_after_signal();
+    }
+}
+
+// returns the completions of the word between start and end of the line
+// buffer as a malloc'd array for readline: the text to replace the word with,
+// then each completion, then null. Returns null if there are none.
+extern "C" fn attempted_completion(text: *c_char, start: c_int,
+                                   _end: c_int) -> *mut *mut c_char {
+    unsafe {
+        // don't fall back to completing filenames
+        ffi::rl_attempted_completion_over = 1;
+        if COMPLETER == 0 {
+            return ptr::mut_null();
+        }
+        let complete = COMPLETER as *mut |&str, &str| -> ~[~str];
+        let buffer = str::raw::from_c_str(ffi::rl_line_buffer);
+        let word = str::raw::from_c_str(text);
+        let start = start as uint;
+        let before = if buffer.is_char_boundary(start) { buffer.slice_to(start) } else { "" };
+        let matches = (*complete)(before, word.as_slice());
+        if matches.is_empty() {
+            return ptr::mut_null();
+        }
+
+        let array = libc::malloc(((matches.len() + 2) * mem::size_of::<*mut c_char>()) as
+                                 libc::size_t) as *mut *mut c_char;
+        // completions may differ from the word in case, so keep the word if
+        // they don't share as much of it
+        let prefix = common_prefix(matches.as_slice());
+        let prefix = if prefix.len() < word.len() { word.as_slice() } else { prefix };
+        *array = to_malloc_str(prefix);
+        for (i, m) in matches.iter().enumerate() {
+            *array.offset(i as int + 1) = to_malloc_str(m.as_slice());
+        }
+        *array.offset(matches.len() as int + 1) = ptr::mut_null();
+        array
+    }
+}
+
+// the longest prefix shared by every completion
+fn common_prefix<'a>(matches: &'a [~str]) -> &'a str {
+    let first = matches[0].as_slice();
+    let mut len = first.len();
+    for m in matches.slice_from(1).iter() {
+        let same = first.bytes().zip(m.bytes()).take_while(|&(a, b)| a == b)
+                        .fold(0u, |n, _| n + 1);
+        len = cmp::min(len, same);
+    }
+    while !first.is_char_boundary(len) {
+        len -= 1;
+    }
+    first.slice_to(len)
+}
+
+// copies the string into memory from malloc, for readline to free
+unsafe fn to_malloc_str(s: &str) -> *mut c_char {
+    let p = libc::malloc((s.len() + 1) as libc::size_t) as *mut u8;
+    ptr::copy_nonoverlapping_memory(p, s.as_ptr(), s.len());
+    *p.offset(s.len() as int) = 0;
+    p as *mut c_char
+}
+
+#[allow(non_camel_case_types, non_uppercase_statics)]
+mod ffi {
+    use std::libc::{c_char, c_int, c_void};
+
+    pub type rl_completion_func_t = extern "C" fn(text: *c_char, start: c_int,
+                                                  end: c_int) -> *mut *mut c_char;
+
+    pub struct HIST_ENTRY {
+        line: *c_char,
+        timestamp: *c_char,
+        data: *c_void
+    }
+
+    #[link(name = "readline")]
+    extern "C" {
+        pub static mut rl_line_buffer: *c_char;
+        pub static mut rl_catch_signals: c_int;
+        pub static mut rl_basic_word_break_characters: *c_char;
+        pub static mut rl_attempted_completion_function: rl_completion_func_t;
+        pub static mut rl_attempted_completion_over: c_int;
+        pub static mut history_base: c_int;
+        pub static mut history_length: c_int;
+
+        pub fn readline(prompt: *c_char) -> *mut c_char;
+        pub fn rl_cleanup_after_signal();
+
+        pub fn using_history();
+        pub fn stifle_history(max: c_int);
+        pub fn add_history(line: *c_char);
+        pub fn history_get(offset: c_int) -> *HIST_ENTRY;
+        pub fn read_history(filename: *c_char) -> c_int;
+        pub fn write_history(filename: *c_char) -> c_int;
+    }
+}
@@ -1,24 +1,258 @@
 /// Handle stdin commands
+///
+/// When stdin is a terminal, lines are read with readline, with the history
+/// kept in console_history next to the config file. Tab completes command
+/// names, channels, the nicks in the channel being addressed (or in the
+/// current target) and, after /plugins, plugin names.
 
 use {Cmd, State};
-use std::{io,task};
+use readline;
+use std::{io,str,task};
+use std::ascii::StrAsciiExt;
 use sync::MutexArc;
+use irc::conn;
 use irc::conn::Conn;
+use plugins::{ctcp, ignore};
+use time;
 
-/// Spawns a new (unwatched) task to handle stdin
-pub fn spawn_stdin_listener(arc: MutexArc<Option<Sender<Cmd>>>) {
+/// Spawns a new (unwatched) task to handle stdin. `history` is the file
+/// the console history is kept in.
+pub fn spawn_stdin_listener(arc: MutexArc<Option<Sender<Cmd>>>,
+                            completions: MutexArc<Completions>, history: Path) {
     task::task().named("stdin listener").spawn(proc() {
-        handle_stdin(arc);
+        handle_stdin(arc, completions, history);
     });
 }
 
-fn handle_stdin(arc: MutexArc<Option<Sender<Cmd>>>) {
+/// What the console can complete: the channels the bot is in along with the
+/// nicks in them, and the loaded plugins. Kept up to date by the connection.
+pub struct Completions {
+    priv channels: ~[(~str, ~[~str])],
+    priv plugins: ~[~str]
+}
+
+impl Completions {
+    pub fn new() -> Completions {
+        Completions { channels: ~[], plugins: ~[] }
+    }
+
+    /// Sets the names of the loaded plugins
+    pub fn set_plugins(&mut self, plugins: &[~str]) {
+        self.plugins = plugins.to_owned();
+    }
+
+    /// Forgets the channels, for when the connection is lost
+    pub fn clear(&mut self) {
+        self.channels.clear();
+    }
+
+    /// Follows the channels and their nicks from a line received from the
+    /// server. `me` is the bot's nick.
+    pub fn feed(&mut self, line: &conn::Line, me: &[u8]) {
+        let conn::Line{ref command, ref args, ref prefix} = *line;
+        let me = str::from_utf8_lossy(me).into_owned();
+        let nick = prefix.as_ref().map_or(~"", |u| str::from_utf8_lossy(u.nick()).into_owned());
+        let arg = |i: uint| str::from_utf8_lossy(args[i]).into_owned();
+        match *command {
+            // RPL_NAMREPLY: me symbol chan :names
+            conn::IRCCode(353) if args.len() >= 4 => {
+                let chan = arg(2);
+                for name in arg(3).words() {
+                    // with userhost-in-names, the name is a full hostmask
+                    let name = name.trim_left_chars(& &['@', '+', '%', '&', '~', '!']);
+                    let name = name.splitn('!', 1).next().unwrap();
+                    self.add_nick(chan.as_slice(), name);
+                }
+            }
+            conn::IRCCmd(ref cmd) => match cmd.as_slice() {
+                "JOIN" if args.len() >= 1 => {
+                    let chan = arg(0);
+                    if same(nick.as_slice(), me.as_slice()) {
+                        // a NAMES reply follows
+                        self.channels.retain(|&(ref c, _)| !same(c.as_slice(), chan.as_slice()));
+                        self.channels.push((chan, ~[]));
+                    } else {
+                        self.add_nick(chan.as_slice(), nick.as_slice());
+                    }
+                }
+                "PART" if args.len() >= 1 => {
+                    self.left(arg(0).as_slice(), nick.as_slice(), me.as_slice());
+                }
+                "KICK" if args.len() >= 2 => {
+                    self.left(arg(0).as_slice(), arg(1).as_slice(), me.as_slice());
+                }
+                "QUIT" => {
+                    for entry in self.channels.mut_iter() {
+                        entry.mut1().retain(|n| !same(n.as_slice(), nick.as_slice()));
+                    }
+                }
+                "NICK" if args.len() >= 1 => {
+                    let new = arg(0);
+                    for entry in self.channels.mut_iter() {
+                        for n in entry.mut1().mut_iter() {
+                            if same(n.as_slice(), nick.as_slice()) {
+                                *n = new.clone();
+                            }
+                        }
+                    }
+                }
+                _ => ()
+            },
+            _ => ()
+        }
+    }
+
+    fn add_nick(&mut self, chan: &str, nick: &str) {
+        if nick.is_empty() {
+            return;
+        }
+        for entry in self.channels.mut_iter() {
+            let (ref c, ref mut nicks) = *entry;
+            if same(c.as_slice(), chan) && !nicks.iter().any(|n| same(n.as_slice(), nick)) {
+                nicks.push(nick.to_owned());
+            }
+        }
+    }
+
+    fn left(&mut self, chan: &str, nick: &str, me: &str) {
+        if same(nick, me) {
+            self.channels.retain(|&(ref c, _)| !same(c.as_slice(), chan));
+            return;
+        }
+        for entry in self.channels.mut_iter() {
+            let (ref c, ref mut nicks) = *entry;
+            if same(c.as_slice(), chan) {
+                nicks.retain(|n| !same(n.as_slice(), nick));
+            }
+        }
+    }
+}
+
+// returns the completions of the word at the end of the line, given the line
+// before it and the current target
+fn complete(comps: &Completions, target: Option<&str>, before: &str, word: &str) -> ~[~str] {
+    let words = before.words().collect::<~[&str]>();
+    let mut found = if words.is_empty() && word.starts_with("/") {
+        COMMANDS.iter().map(|&(name, _, _)| format!("/{}", name)).collect()
+    } else if words.len() == 1 && words[0] == "/help" {
+        COMMANDS.iter().map(|&(name, _, _)| name.to_owned()).collect()
+    } else if words.len() == 1 && words[0] == "/plugins" {
+        comps.plugins.clone()
+    } else if is_channel(word) {
+        comps.channels.iter().map(|&(ref c, _)| c.clone()).collect()
+    } else {
+        // the nicks of the channel the line names, or else the current target
+        let chan = words.iter().map(|w| *w).find(|w| is_channel(*w)).or(target);
+        let nicks = chan.and_then(|c| {
+            comps.channels.iter().find(|&&(ref ch, _)| same(ch.as_slice(), c))
+        });
+        nicks.map_or(~[], |&(_, ref nicks)| nicks.clone())
+    };
+    let word = word.to_ascii_lower();
+    found.retain(|f| f.to_ascii_lower().starts_with(word.as_slice()));
+    found.sort();
+    found
+}
+
+fn is_channel(s: &str) -> bool {
+    s.starts_with("#") || s.starts_with("&")
+}
+
+// compares nicks or channels, ignoring case
+fn same(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+/// The console commands, with their arguments and what they do
+pub static COMMANDS: &'static [(&'static str, &'static str, &'static str)] = &[
+    ("msg", "<target> <text>", "Sends a message to a channel or nick"),
+    ("me", "<text>", "Sends an action to the current target"),
+    ("notice", "<target> <text>", "Sends a notice to a channel or nick"),
+    ("ctcp", "<target> <type> [args]", "Sends a CTCP query"),
+    ("target", "[channel or nick]", "Shows or sets where lines without a / are sent"),
+    ("join", "<channels> [keys]", "Joins channels, separated by commas"),
+    ("part", "<channels> [message]", "Leaves channels, separated by commas"),
+    ("nick", "<nick>", "Changes the bot's nick"),
+    ("mode", "<target> [modes]", "Shows or changes the modes of a channel or nick"),
+    ("topic", "<channel> [topic]", "Shows or changes the topic of a channel"),
+    ("kick", "<channel> <nick> [reason]", "Kicks a user from a channel"),
+    ("quit", "[message]", "Disconnects from the server"),
+    ("raw", "<line>", "Sends a line to the server as is"),
+    ("reload", "", "Reloads the plugins"),
+    ("plugins", "[name]", "Lists the loaded plugins, or shows one of them"),
+    ("ignore", "[target [duration] [channel] [server]]",
+     "Shows the ignore list, or ignores a hostmask, $a:account or nick"),
+    ("unignore", "<target>", "Removes a target from the ignore list"),
+    ("help", "[command]", "Lists the commands, or shows how to use one")
+];
+
+// the most lines kept in the console history
+static HISTORY_SIZE: uint = 1000;
+
+static NO_TARGET: &'static str = "no current target, use /target <channel or nick> to set one";
+
+fn handle_stdin(arc: MutexArc<Option<Sender<Cmd>>>, completions: MutexArc<Completions>,
+                history: Path) {
+    let interactive = readline::is_interactive();
     let mut stdin = io::BufferedReader::new(io::stdin());
-    for line in stdin.lines() {
-        let line = line.unwrap(); // ignore error handling
-        match parse_line(line) {
-            None => (),
-            Some(cmd) => {
+    if interactive {
+        readline::load_history(&history, HISTORY_SIZE);
+    }
+    // where lines without a / go
+    let mut target: Option<~str> = None;
+    loop {
+        let line = if interactive {
+            let current = target.clone();
+            let res = readline::read_line("> ", |before, word| {
+                let current = current.as_ref().map(|t| t.as_slice());
+                completions.access(|c| complete(c, current, before, word))
+            });
+            match res {
+                None => break,
+                Some(line) => line
+            }
+        } else {
+            match stdin.read_line() {
+                Err(_) => break,
+                Ok(line) => line
+            }
+        };
+        let line = line.trim_right_chars(& &['\r', '\n']);
+        if line.is_empty() {
+            continue;
+        }
+        if interactive {
+            readline::add_history(line);
+            readline::save_history(&history);
+        }
+
+        // some commands only concern the console itself
+        let (cmd, args) = parse_word(line);
+        match cmd {
+            "/help" => {
+                match help(args.trim()) {
+                    Ok(lines) => for l in lines.iter() { println!("{}", *l); },
+                    Err(e) => println!("Error: {}", e)
+                }
+                continue;
+            }
+            "/target" => {
+                let (name, _) = parse_word(args);
+                if name != "" {
+                    target = Some(name.to_owned());
+                }
+                match target {
+                    None => println!("No current target"),
+                    Some(ref t) => println!("Current target: {}", *t)
+                }
+                continue;
+            }
+            _ => ()
+        }
+
+        match parse_line(line, target.as_ref().map(|t| t.as_slice())) {
+            Err(e) => println!("Error: {}", e),
+            Ok(cmd) => {
                 let mut cmd = Some(cmd);
                 if !arc.access(|chan| {
                     match *chan {
@@ -33,22 +267,65 @@ fn handle_stdin(arc: MutexArc<Option<Sender<Cmd>>>) {
     }
 }
 
-fn parse_line(line: &str) -> Option<Cmd> {
+/// Parses a console command. A line that doesn't start with a / is a message
+/// to `target`, which also receives actions sent with /me.
+pub fn parse_line(line: &str, target: Option<&str>) -> Result<Cmd, ~str> {
+    let line = line.trim_right_chars(& &['\r', '\n']);
     if !line.starts_with("/") {
-        return None;
+        return match target {
+            None => Err(NO_TARGET.to_owned()),
+            Some(t) => cmd_msg(format!("{} {}", t, line).as_slice())
+        };
     }
-    let line = line.trim_right_chars(& &['\r', '\n']);
     let mut iter = line.slice_from(1).splitn(' ', 1);
     let cmd = iter.next().unwrap();
     let line = iter.next().unwrap_or("");
     match cmd {
         "msg" => cmd_msg(line),
+        "me" => cmd_me(line, target),
+        "notice" => cmd_notice(line),
+        "ctcp" => cmd_ctcp(line),
         "join" => cmd_join(line),
         "part" => cmd_part(line),
+        "nick" => cmd_nick(line),
+        "mode" => cmd_mode(line),
+        "topic" => cmd_topic(line),
+        "kick" => cmd_kick(line),
         "quit" => cmd_quit(line),
         "raw" => cmd_raw(line),
         "reload" => cmd_reload(line),
-        _ => None
+        "plugins" => cmd_plugins(line),
+        "ignore" => cmd_ignore(line),
+        "unignore" => cmd_unignore(line),
+        "help" | "target" => Err(format!("/{} is only available on the console", cmd)),
+        _ => Err(format!("unknown command /{}, try /help", cmd))
+    }
+}
+
+/// Returns the help for a command, or a list of the commands if `cmd` is empty
+pub fn help(cmd: &str) -> Result<~[~str], ~str> {
+    let cmd = cmd.trim_left_chars(&'/');
+    if cmd.is_empty() {
+        let mut lines = ~[~"Commands:"];
+        for &(name, args, _) in COMMANDS.iter() {
+            lines.push(format!("  /{} {}", name, args).trim_right().to_owned());
+        }
+        lines.push(~"Lines without a / are sent to the current target");
+        return Ok(lines);
+    }
+    match COMMANDS.iter().find(|&&(name, _, _)| name == cmd) {
+        None => Err(format!("unknown command /{}", cmd)),
+        Some(&(name, args, desc)) => {
+            Ok(~[format!("Usage: /{} {}", name, args).trim_right().to_owned(), desc.to_owned()])
+        }
+    }
+}
+
+// the error for a command given too few arguments
+fn usage(cmd: &str) -> ~str {
+    match COMMANDS.iter().find(|&&(name, _, _)| name == cmd) {
+        None => format!("missing arguments for /{}", cmd),
+        Some(&(name, args, _)) => format!("missing arguments, usage: /{} {}", name, args)
     }
 }
 
@@ -62,64 +339,280 @@ fn parse_word<'a>(line: &'a str) -> (&'a str, &'a str) {
     }
 }
 
-fn cmd_msg(line: &str) -> Option<Cmd> {
+fn cmd_msg(line: &str) -> Result<Cmd, ~str> {
     let (dst, msg) = parse_word(line);
     if dst == "" || msg == "" {
-        return None;
+        return Err(usage("msg"));
     }
 
     let dst = dst.to_owned();
     let msg = msg.to_owned();
-    Some(proc(conn: &mut Conn, _state: &mut State) {
+    Ok(proc(conn: &mut Conn, _state: &mut State) {
         conn.privmsg(dst.as_bytes(), msg.as_bytes());
     })
 }
 
-fn cmd_join(line: &str) -> Option<Cmd> {
+fn cmd_me(line: &str, target: Option<&str>) -> Result<Cmd, ~str> {
+    let dst = match target {
+        None => return Err(NO_TARGET.to_owned()),
+        Some(t) => t.to_owned()
+    };
+    if line.trim() == "" {
+        return Err(usage("me"));
+    }
+
+    let msg = ctcp::frame("ACTION", line.as_bytes());
+    Ok(proc(conn: &mut Conn, _state: &mut State) {
+        conn.privmsg(dst.as_bytes(), msg);
+    })
+}
+
+fn cmd_notice(line: &str) -> Result<Cmd, ~str> {
+    let (dst, msg) = parse_word(line);
+    if dst == "" || msg == "" {
+        return Err(usage("notice"));
+    }
+
+    let dst = dst.to_owned();
+    let msg = msg.to_owned();
+    Ok(proc(conn: &mut Conn, _state: &mut State) {
+        conn.notice(dst.as_bytes(), msg.as_bytes());
+    })
+}
+
+fn cmd_ctcp(line: &str) -> Result<Cmd, ~str> {
+    let (dst, line) = parse_word(line);
+    let (kind, args) = parse_word(line);
+    if dst == "" || kind == "" {
+        return Err(usage("ctcp"));
+    }
+
+    let dst = dst.to_owned();
+    let msg = ctcp::frame(kind.to_ascii_upper().as_slice(), args.as_bytes());
+    Ok(proc(conn: &mut Conn, _state: &mut State) {
+        conn.privmsg(dst.as_bytes(), msg);
+    })
+}
+
+fn cmd_join(line: &str) -> Result<Cmd, ~str> {
     let (chans, line) = parse_word(line);
     let line = line.trim_left();
     if chans == "" {
-        return None;
+        return Err(usage("join"));
     }
 
     let chans = chans.to_owned();
     let keys = if line == "" { None } else { Some(line.to_owned()) };
-    Some(proc(conn: &mut Conn, _state: &mut State) {
+    Ok(proc(conn: &mut Conn, _state: &mut State) {
         conn.join(chans.as_bytes(), keys.as_ref().map_or(&[], |s| s.as_bytes()));
     })
 }
 
-fn cmd_part(line: &str) -> Option<Cmd> {
+fn cmd_part(line: &str) -> Result<Cmd, ~str> {
     let (chans, msg) = parse_word(line);
     if chans == "" {
-        return None;
+        return Err(usage("part"));
     }
 
     let chans = chans.to_owned();
     let msg = if msg == "" { None } else { Some(msg.to_owned()) };
-    Some(proc(conn: &mut Conn, _state: &mut State) {
+    Ok(proc(conn: &mut Conn, _state: &mut State) {
         conn.part(chans.as_bytes(), msg.as_ref().map_or(&[], |s| s.as_bytes()));
     })
 }
 
-fn cmd_quit(line: &str) -> Option<Cmd> {
+fn cmd_nick(line: &str) -> Result<Cmd, ~str> {
+    let (nick, _) = parse_word(line);
+    if nick == "" {
+        return Err(usage("nick"));
+    }
+
+    let line = format!("NICK {}", nick);
+    Ok(proc(conn: &mut Conn, _state: &mut State) {
+        conn.send_raw(line.as_bytes());
+    })
+}
+
+fn cmd_mode(line: &str) -> Result<Cmd, ~str> {
+    let (dst, modes) = parse_word(line);
+    if dst == "" {
+        return Err(usage("mode"));
+    }
+
+    let modes = modes.trim();
+    let line = if modes == "" {
+        format!("MODE {}", dst)
+    } else {
+        format!("MODE {} {}", dst, modes)
+    };
+    Ok(proc(conn: &mut Conn, _state: &mut State) {
+        conn.send_raw(line.as_bytes());
+    })
+}
+
+fn cmd_topic(line: &str) -> Result<Cmd, ~str> {
+    let (chan, topic) = parse_word(line);
+    if chan == "" {
+        return Err(usage("topic"));
+    }
+
+    let line = if topic == "" {
+        format!("TOPIC {}", chan)
+    } else {
+        format!("TOPIC {} :{}", chan, topic)
+    };
+    Ok(proc(conn: &mut Conn, _state: &mut State) {
+        conn.send_raw(line.as_bytes());
+    })
+}
+
+fn cmd_kick(line: &str) -> Result<Cmd, ~str> {
+    let (chan, line) = parse_word(line);
+    let (nick, reason) = parse_word(line);
+    if chan == "" || nick == "" {
+        return Err(usage("kick"));
+    }
+
+    let line = if reason == "" {
+        format!("KICK {} {}", chan, nick)
+    } else {
+        format!("KICK {} {} :{}", chan, nick, reason)
+    };
+    Ok(proc(conn: &mut Conn, _state: &mut State) {
+        conn.send_raw(line.as_bytes());
+    })
+}
+
+fn cmd_quit(line: &str) -> Result<Cmd, ~str> {
     let line = line.trim_left();
     let line = if line == "" { None } else { Some(line.to_owned()) };
-    Some(proc(conn: &mut Conn, _state: &mut State) {
+    Ok(proc(conn: &mut Conn, state: &mut State) {
+        state.plugins().shutdown(Some(&mut *conn));
         conn.quit(line.as_ref().map_or(&[], |s| s.as_bytes()));
     })
 }
 
-fn cmd_raw(line: &str) -> Option<Cmd> {
+fn cmd_raw(line: &str) -> Result<Cmd, ~str> {
+    if line.trim().is_empty() {
+        return Err(usage("raw"));
+    }
     let line = line.to_owned();
-    Some(proc(conn: &mut Conn, _state: &mut State) {
+    Ok(proc(conn: &mut Conn, _state: &mut State) {
         conn.send_raw(line.as_bytes());
     })
 }
 
-fn cmd_reload(_line: &str) -> Option<Cmd> {
-    Some(proc(conn: &mut Conn, state: &mut State) {
-        println!("Reloading plugins...");
-        state.plugins.reload_plugins(conn);
+fn cmd_reload(_line: &str) -> Result<Cmd, ~str> {
+    Ok(proc(conn: &mut Conn, state: &mut State) {
+        state.print(conn, "Reloading plugins...");
+        state.plugins().reload_plugins(conn);
+        let names = state.plugins().plugin_names();
+        state.completions.access(|c| c.set_plugins(names.as_slice()));
     })
 }
+
+fn cmd_plugins(line: &str) -> Result<Cmd, ~str> {
+    let (name, _) = parse_word(line);
+    let name = if name == "" { None } else { Some(name.to_owned()) };
+    Ok(proc(conn: &mut Conn, state: &mut State) {
+        let lines = state.plugins().describe_plugins(name.as_ref().map(|n| n.as_slice()));
+        for l in lines.iter() {
+            state.print(conn, l.as_slice());
+        }
+    })
+}
+
+fn cmd_ignore(line: &str) -> Result<Cmd, ~str> {
+    let words = line.words().map(|w| w.to_owned()).collect::<~[~str]>();
+    Ok(proc(conn: &mut Conn, state: &mut State) {
+        if words.is_empty() {
+            let lines = describe_ignores(state.plugins().ignores());
+            for l in lines.iter() {
+                state.print(conn, l.as_slice());
+            }
+            return;
+        }
+
+        // the target is followed by an optional duration, channel, or "server"
+        // to limit the entry to the current server
+        let (mut duration, mut server, mut channel) = (None, None, None);
+        for w in words.slice_from(1).iter() {
+            if w.as_slice() == "server" {
+                server = Some(state.plugins().ignores().server().to_owned());
+            } else if w.starts_with("#") || w.starts_with("&") {
+                channel = Some(w.clone());
+            } else {
+                match ignore::parse_duration(w.as_slice()) {
+                    None => {
+                        let msg = format!("Error: expected a duration, channel or `server', \
+                                           found `{}'", w);
+                        state.print(conn, msg.as_slice());
+                        return;
+                    }
+                    Some(d) => duration = Some(d)
+                }
+            }
+        }
+        let server = server.as_ref().map(|s| s.as_slice());
+        let channel = channel.as_ref().map(|c| c.as_slice());
+        let entry = match ignore::Entry::new(words[0], duration, server, channel) {
+            Ok(e) => e,
+            Err(e) => {
+                state.print(conn, format!("Error: {}", e).as_slice());
+                return;
+            }
+        };
+        let target = entry.target.clone();
+        let msg = match state.plugins().ignores().add(entry) {
+            Ok(()) => format!("Ignoring {}", target),
+            Err(e) => format!("Error saving ignore list: {}", e)
+        };
+        state.print(conn, msg.as_slice());
+    })
+}
+
+fn cmd_unignore(line: &str) -> Result<Cmd, ~str> {
+    let (target, _) = parse_word(line);
+    if target == "" {
+        return Err(usage("unignore"));
+    }
+
+    let target = target.to_owned();
+    Ok(proc(conn: &mut Conn, state: &mut State) {
+        let msg = match state.plugins().ignores().remove(target.as_slice()) {
+            Ok(0) => format!("{} is not ignored", target),
+            Ok(_) => format!("No longer ignoring {}", target),
+            Err(e) => format!("Error saving ignore list: {}", e)
+        };
+        state.print(conn, msg.as_slice());
+    })
+}
+
+// describes the ignore list as lines of output
+fn describe_ignores(ignores: &mut ignore::IgnoreList) -> ~[~str] {
+    let entries = ignores.entries();
+    if entries.is_empty() {
+        return ~[~"Nobody is ignored"];
+    }
+    let mut lines = ~[~"Ignored:"];
+    for e in entries.iter() {
+        let mut desc = e.target.clone();
+        match e.channel {
+            None => (),
+            Some(ref c) => desc.push_str(format!(" in {}", c))
+        }
+        match e.server {
+            None => (),
+            Some(ref s) => desc.push_str(format!(" on {}", s))
+        }
+        match e.expires {
+            None => (),
+            Some(t) => {
+                let tm = time::at(time::Timespec::new(t, 0));
+                desc.push_str(format!(" until {}", tm.strftime("%Y-%m-%d %H:%M")))
+            }
+        }
+        lines.push(format!("  {}", desc));
+    }
+    lines
+}
@@ -0,0 +1,153 @@
+/// Timer source for plugin timers
+///
+/// Deadlines are tracked by a dedicated task that outlives individual
+/// connections. When a timer comes due, the task sends a command down the
+/// current connection's channel so the timer runs with the connection active.
+/// If there is no connection at the time, or the connection goes away before
+/// the command runs, the timer is retried until one comes back.
+
+use {Cmd, State};
+use std::{io, task};
+use sync::MutexArc;
+use irc::conn::Conn;
+use time;
+
+// how long to wait before retrying a due timer when there's no connection
+static RETRY_DELAY: u64 = 1000;
+
+enum Request {
+    Schedule(uint, u64), // timer id, delay in ms
+    Cancel(uint),
+    CancelAll,
+    Refire(uint) // from a FireGuard
+}
+
+/// The plugin side of the timer source
+pub struct TimerClient {
+    priv tx: Sender<Request>,
+    priv next_id: uint
+}
+
+impl TimerClient {
+    /// Schedules a new timer to come due after `delay` milliseconds,
+    /// returning its id
+    pub fn schedule(&mut self, delay: u64) -> uint {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.reschedule(id, delay);
+        id
+    }
+
+    /// Schedules the existing timer `id` to come due again after `delay`
+    /// milliseconds
+    pub fn reschedule(&self, id: uint, delay: u64) {
+        self.tx.try_send(Schedule(id, delay));
+    }
+
+    /// Cancels the timer `id`
+    pub fn cancel(&self, id: uint) {
+        self.tx.try_send(Cancel(id));
+    }
+
+    /// Cancels every timer
+    pub fn cancel_all(&self) {
+        self.tx.try_send(CancelAll);
+    }
+}
+
+/// Spawns a new (unwatched) task to drive plugin timers
+pub fn spawn_timer_source(arc: MutexArc<Option<Sender<Cmd>>>) -> TimerClient {
+    let (tx, rx) = channel();
+    task::task().named("timer source").spawn(proc() {
+        run_timers(rx, arc);
+    });
+    TimerClient { tx: tx, next_id: 0 }
+}
+
+// sends the id of a timer back to the timer task if its command is dropped
+// without running, as happens when the connection closes first
+struct FireGuard {
+    id: uint,
+    fired: bool,
+    unfired: Sender<uint>
+}
+
+impl Drop for FireGuard {
+    fn drop(&mut self) {
+        if !self.fired {
+            self.unfired.try_send(self.id);
+        }
+    }
+}
+
+fn run_timers(rx: Receiver<Request>, arc: MutexArc<Option<Sender<Cmd>>>) {
+    let mut timer = io::timer::Timer::new().ok().expect("could not create plugin timer");
+    // pending timers as (deadline in ns, id)
+    let mut pending: ~[(u64, uint)] = ~[];
+    let (unfired_tx, unfired_rx) = channel();
+
+    loop {
+        // fire everything that's come due
+        let now = time::precise_time_ns();
+        for entry in pending.mut_iter() {
+            let (deadline, id) = *entry;
+            if deadline > now {
+                continue;
+            }
+            let guard = FireGuard { id: id, fired: false, unfired: unfired_tx.clone() };
+            let mut cmd = Some(fire_cmd(guard));
+            let sent = arc.access(|chan| {
+                match *chan {
+                    None => false,
+                    Some(ref c) => c.try_send(cmd.take_unwrap())
+                }
+            });
+            // the entry is dropped below once it's sent, otherwise wait for a
+            // connection. If the command is dropped unrun, its guard refires it.
+            *entry = if sent { (0, 0) } else { (now + RETRY_DELAY * 1000000, id) };
+        }
+        pending.retain(|&(_, id)| id != 0);
+
+        // wait for the next deadline or a new request
+        let req = match pending.iter().map(|&(deadline, _)| deadline).min() {
+            None => select! {
+                req = rx.recv_opt() => req,
+                id = unfired_rx.recv() => Some(Refire(id))
+            },
+            Some(deadline) => {
+                let now = time::precise_time_ns();
+                let delay = if deadline > now { (deadline - now) / 1000000 + 1 } else { 0 };
+                let oneshot = timer.oneshot(delay);
+                select! {
+                    req = rx.recv_opt() => req,
+                    id = unfired_rx.recv() => Some(Refire(id)),
+                    () = oneshot.recv() => continue
+                }
+            }
+        };
+        match req {
+            None => break, // the plugin manager is gone
+            Some(Schedule(id, delay)) => {
+                pending.retain(|&(_, i)| i != id);
+                pending.push((time::precise_time_ns() + delay * 1000000, id));
+            }
+            Some(Refire(id)) => {
+                // its command was never run, so it's due again right away,
+                // unless it's still pending because it was never sent
+                if !pending.iter().any(|&(_, i)| i == id) {
+                    pending.push((0, id));
+                }
+            }
+            Some(Cancel(id)) => pending.retain(|&(_, i)| i != id),
+            Some(CancelAll) => pending.clear()
+        }
+    }
+}
+
+fn fire_cmd(guard: FireGuard) -> Cmd {
+    proc(conn: &mut Conn, state: &mut State) {
+        let mut guard = guard;
+        guard.fired = true;
+        state.plugins().fire_timer(conn, guard.id);
+    }
+}